@@ -0,0 +1,100 @@
+//! # Audit log
+//!
+//! This module contains an append-only log of who changed which document and when. The
+//! events are written as JSON lines by a separate task, so a slow disk never blocks a
+//! channel. If that task falls behind, events are dropped with a warning.
+use crate::lobby::{ChannelID, UserID};
+use crate::util::unix_millis;
+use color_eyre::Report;
+use eyre::WrapErr;
+use serde::Serialize;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, warn};
+
+/// The number of events that may wait to be written
+const AUDIT_BUFFER: usize = 256;
+
+/// What happened in a channel
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditKind {
+    /// A user joined the channel
+    Join,
+    /// A user left the channel
+    Leave,
+    /// A user was removed from the channel by another user
+    Kicked {
+        /// The user that removed them
+        by: UserID,
+    },
+    /// Steps by a user were applied to the document
+    Steps {
+        /// The version of the document after the steps
+        version: usize,
+    },
+}
+
+/// An entry in the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    /// The time of the event (in milliseconds since the UNIX epoch)
+    pub time: u64,
+    /// The channel of the event
+    pub channel: ChannelID,
+    /// The user that caused the event
+    pub user: UserID,
+    /// What happened
+    #[serde(flatten)]
+    pub kind: AuditKind,
+}
+
+/// A handle to the audit log
+#[derive(Debug, Clone)]
+pub struct AuditLog(mpsc::Sender<AuditEvent>);
+
+impl AuditLog {
+    /// Open the log file and start the task that writes to it
+    pub async fn open(path: &Path) -> Result<Self, Report> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .wrap_err_with(|| format!("Could not open audit log {:?}", path))?;
+        let (tx, rx) = mpsc::channel(AUDIT_BUFFER);
+        tokio::spawn(write_events(file, rx));
+        Ok(Self(tx))
+    }
+
+    /// Record an event, without waiting for it to be written
+    pub fn record(&mut self, channel: ChannelID, user: UserID, kind: AuditKind) {
+        let event = AuditEvent {
+            time: unix_millis(),
+            channel,
+            user,
+            kind,
+        };
+        match self.0.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => warn!("Audit log is full, dropped {:?}", event),
+            Err(TrySendError::Closed(event)) => error!("Audit log is closed, lost {:?}", event),
+        }
+    }
+}
+
+async fn write_events(mut file: File, mut rx: mpsc::Receiver<AuditEvent>) {
+    while let Some(event) = rx.recv().await {
+        let mut line = serde_json::to_string(&event).unwrap();
+        line.push('\n');
+        let res = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(e) = res.await {
+            error!("Could not write to the audit log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use super::cursor::map_pos;
+use prosemirror::markdown::MD;
+use prosemirror::transform::Step;
+use serde::{Deserialize, Serialize};
+
+/// A comment on a range of the document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comment {
+    /// The ID of the comment, unique within the document
+    pub id: u64,
+    /// The start of the commented range
+    pub from: usize,
+    /// The end of the commented range
+    pub to: usize,
+    /// The text of the comment
+    pub text: String,
+    /// The name of the user that wrote the comment
+    pub author: String,
+    /// When the comment was written (in milliseconds since the UNIX epoch)
+    pub time: u64,
+}
+
+impl Comment {
+    /// Move the range to where it ends up after `step` is applied
+    pub(super) fn map(&mut self, step: &Step<MD>) {
+        self.from = map_pos(step, self.from);
+        self.to = map_pos(step, self.to).max(self.from);
+    }
+}
@@ -0,0 +1,71 @@
+use super::DocState;
+use crate::config::{ContentKind, Format};
+use color_eyre::Report;
+use prosemirror::markdown::MarkdownNode;
+use serde::{Deserialize, Serialize};
+
+/// A marker on a map
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Marker {
+    /// The horizontal position
+    pub x: f64,
+    /// The vertical position
+    pub y: f64,
+    /// The text next to the marker
+    #[serde(default)]
+    pub label: String,
+}
+
+/// Current state of a shared map
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MapState {
+    /// The markers on the map
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+}
+
+/// What the clients in a channel work on together
+///
+/// Serialized without a tag, so documents are sent to clients just like before.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Content {
+    /// A document that is edited with steps
+    Document(DocState),
+    /// A map, which is always stored as JSON
+    Map(MapState),
+}
+
+impl Content {
+    /// The content of a new channel of the given kind
+    pub fn initial(kind: ContentKind, template: &MarkdownNode) -> Self {
+        match kind {
+            ContentKind::Document => Content::Document(DocState::new(template.clone())),
+            ContentKind::Map => Content::Map(MapState::default()),
+        }
+    }
+
+    /// Read content of the given kind that was saved in `format`
+    pub fn parse(kind: ContentKind, format: Format, text: &str) -> Result<Self, Report> {
+        match kind {
+            ContentKind::Document => Ok(Content::Document(DocState::new(format.parse(text)?))),
+            ContentKind::Map => Ok(Content::Map(serde_json::from_str(text)?)),
+        }
+    }
+
+    /// Write the content in `format`
+    pub fn write(&self, format: Format) -> Result<String, Report> {
+        match self {
+            Content::Document(doc_state) => format.write(&doc_state.doc),
+            Content::Map(map) => Ok(serde_json::to_string(map)?),
+        }
+    }
+
+    /// The document, if this is one
+    pub fn doc(&self) -> Option<&DocState> {
+        match self {
+            Content::Document(doc_state) => Some(doc_state),
+            Content::Map(_) => None,
+        }
+    }
+}
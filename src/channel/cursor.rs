@@ -0,0 +1,55 @@
+use prosemirror::markdown::MD;
+use prosemirror::transform::Step;
+use serde::Serialize;
+
+/// The selection of a user in the shared document
+#[derive(Debug, Copy, Clone, Serialize, new)]
+pub struct Cursor {
+    /// The start of the selection
+    pub from: usize,
+    /// The end of the selection
+    pub to: usize,
+}
+
+impl Cursor {
+    /// Move the cursor to where it ends up after `step` is applied
+    pub(super) fn map(&mut self, step: &Step<MD>) {
+        self.from = map_pos(step, self.from);
+        self.to = map_pos(step, self.to);
+    }
+}
+
+/// A replaced range `(start, old_size, new_size)`, like in a prosemirror `StepMap`
+type Range = (usize, usize, usize);
+
+fn ranges(step: &Step<MD>) -> Vec<Range> {
+    match step {
+        Step::Replace(s) => vec![(s.from, s.to - s.from, s.slice.size())],
+        Step::ReplaceAround(s) => vec![
+            (s.from, s.gap_from - s.from, s.insert),
+            (s.gap_to, s.to - s.gap_to, s.slice.size() - s.insert),
+        ],
+        _ => vec![],
+    }
+}
+
+/// Map a position through a step, keeping it to the right of inserted content
+pub(super) fn map_pos(step: &Step<MD>, pos: usize) -> usize {
+    // The size difference accumulated by the ranges before `pos`
+    let mut diff: isize = 0;
+    for (start, old_size, new_size) in ranges(step) {
+        if start > pos {
+            break;
+        }
+        let new_start = (start as isize + diff) as usize;
+        if pos <= start + old_size {
+            return if old_size > 0 && pos == start {
+                new_start
+            } else {
+                new_start + new_size
+            };
+        }
+        diff += new_size as isize - old_size as isize;
+    }
+    (pos as isize + diff) as usize
+}
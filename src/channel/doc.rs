@@ -1,5 +1,6 @@
 use prosemirror::markdown::helper::{blockquote, code_block, doc, h1, h2, node, p, strong};
 use prosemirror::markdown::MarkdownNode;
+use prosemirror::model::Node;
 use serde::Serialize;
 
 /// Current state of the shared document
@@ -10,7 +11,74 @@ pub struct DocState {
     pub(super) version: usize,
 }
 
-pub(super) fn initial_doc() -> MarkdownNode {
+/// Word and character counts of a document
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DocStats {
+    /// The number of words
+    pub words: usize,
+    /// The number of characters
+    pub characters: usize,
+    /// The number of headings
+    pub headings: usize,
+    /// The number of paragraphs
+    pub paragraphs: usize,
+}
+
+/// Append the text of all text nodes below `node`
+fn collect_text(node: &MarkdownNode, out: &mut String) {
+    if let Some(text_node) = node.text_node() {
+        out.push_str(text_node.text.as_str());
+    }
+    if let Some(content) = node.content() {
+        for child in content.children() {
+            collect_text(child, out);
+        }
+    }
+}
+
+/// The number of bytes of text in a document, a cheap estimate of its size
+pub fn text_bytes(node: &MarkdownNode) -> usize {
+    let own = node.text_node().map_or(0, |t| t.text.as_str().len());
+    let children = node
+        .content()
+        .map_or(0, |c| c.children().iter().map(text_bytes).sum());
+    own + children
+}
+
+/// Count the words and characters in a document
+pub fn doc_stats(doc: &MarkdownNode) -> DocStats {
+    fn walk(node: &MarkdownNode, stats: &mut DocStats) {
+        match node {
+            MarkdownNode::Heading { .. }
+            | MarkdownNode::Paragraph { .. }
+            | MarkdownNode::CodeBlock { .. } => {
+                // Count whole text blocks, so that marks within a word don't split it
+                let mut text = String::new();
+                collect_text(node, &mut text);
+                stats.words += text.split_whitespace().count();
+                stats.characters += text.chars().count();
+                match node {
+                    MarkdownNode::Heading { .. } => stats.headings += 1,
+                    MarkdownNode::Paragraph { .. } => stats.paragraphs += 1,
+                    _ => {}
+                }
+            }
+            _ => {
+                if let Some(content) = node.content() {
+                    for child in content.children() {
+                        walk(child, stats);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut stats = DocStats::default();
+    walk(doc, &mut stats);
+    stats
+}
+
+pub(crate) fn initial_doc() -> MarkdownNode {
     doc(vec![
         h1((
             "Padington",
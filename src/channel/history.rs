@@ -0,0 +1,119 @@
+use crate::store::DocStore;
+use crate::util::unix_millis;
+use color_eyre::Report;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, error};
+
+/// The extension of the revision files
+const REVISION_EXTENSION: &str = "md";
+
+/// A saved revision of a document
+#[derive(Debug, Clone, Serialize)]
+pub struct Revision {
+    /// The version of the document
+    pub version: usize,
+    /// The time the revision was saved (in milliseconds since the UNIX epoch)
+    pub time: u64,
+}
+
+impl Revision {
+    /// The name of the file, padded so that revisions sort by version
+    fn file_stem(&self) -> String {
+        format!("{:010}-{}", self.version, self.time)
+    }
+
+    fn from_file_stem(stem: &str) -> Option<Self> {
+        let (version, time) = stem.split_at(stem.find('-')?);
+        Some(Self {
+            version: version.parse().ok()?,
+            time: time[1..].parse().ok()?,
+        })
+    }
+}
+
+/// Snapshots of a document, kept for recovery
+#[derive(Debug, Clone)]
+pub struct History {
+    /// The storage for the snapshots
+    store: Arc<dyn DocStore>,
+    /// The directory of the snapshots
+    dir: PathBuf,
+    /// The number of versions between two snapshots
+    interval: usize,
+    /// The maximum number of snapshots
+    keep: usize,
+}
+
+impl History {
+    /// Keep the snapshots of the document at `path` in `history/<name>` next to it
+    pub fn new(store: Arc<dyn DocStore>, path: &Path, interval: usize, keep: usize) -> Self {
+        let mut dir = path.with_file_name("history");
+        if let Some(stem) = path.file_stem() {
+            dir.push(stem);
+        }
+        Self {
+            store,
+            dir,
+            interval,
+            keep,
+        }
+    }
+
+    /// Whether a snapshot is due after the document moved from version `old` to `new`
+    pub fn is_due(&self, old: usize, new: usize) -> bool {
+        old / self.interval != new / self.interval
+    }
+
+    /// Save a snapshot in the background and remove the oldest ones
+    pub fn snapshot(&self, version: usize, md: String) {
+        let history = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = history.save(version, &md).await {
+                error!("Could not save revision {}: {:?}", version, e);
+            }
+        });
+    }
+
+    async fn save(&self, version: usize, md: &str) -> Result<(), Report> {
+        let revision = Revision {
+            version,
+            time: unix_millis(),
+        };
+        self.store.save(&self.path_of(&revision), md).await?;
+        debug!("Saved revision {}", version);
+
+        let revisions = self.list().await?;
+        if revisions.len() > self.keep {
+            for old in &revisions[..revisions.len() - self.keep] {
+                self.store.remove(&self.path_of(old)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All saved revisions, oldest first
+    pub async fn list(&self) -> Result<Vec<Revision>, Report> {
+        let names = self.store.list(&self.dir, REVISION_EXTENSION).await?;
+        Ok(names
+            .iter()
+            .filter_map(|name| Revision::from_file_stem(name))
+            .collect())
+    }
+
+    /// Load the markdown of the latest revision with the given version
+    pub async fn load(&self, version: usize) -> Result<Option<String>, Report> {
+        let revisions = self.list().await?;
+        match revisions.iter().rev().find(|r| r.version == version) {
+            Some(revision) => self.store.load(&self.path_of(revision)).await,
+            None => Ok(None),
+        }
+    }
+
+    fn path_of(&self, revision: &Revision) -> PathBuf {
+        let mut path = self.dir.join(revision.file_stem());
+        path.set_extension(REVISION_EXTENSION);
+        path
+    }
+}
@@ -0,0 +1,128 @@
+use super::Comment;
+use crate::store::DocStore;
+use crate::util::unix_millis;
+use color_eyre::Report;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The extension of the files with the metadata of a document
+const META_EXTENSION: &str = "meta";
+
+/// The maximum length of the title of a document (in bytes)
+const MAX_TITLE_LEN: usize = 200;
+
+/// The maximum length of the description of a document (in bytes)
+const MAX_DESCRIPTION_LEN: usize = 2000;
+
+/// The maximum number of open comments on a document
+pub(super) const MAX_COMMENTS: usize = 1000;
+
+/// What is known about a document besides its content
+///
+/// Kept as JSON in a file next to the document, with the same name and a `.meta` extension.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocMeta {
+    /// The name the document was created with, before it was turned into a file name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// A short description of the document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// When the document was created (in milliseconds since the UNIX epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// When the document was last changed (in milliseconds since the UNIX epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    /// The name of the authenticated user that created the document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    /// The comments that were not resolved yet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub comments: Vec<Comment>,
+}
+
+/// A change to the metadata by a client, fields that are not set stay the same
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetaUpdate {
+    /// The new title, an empty one removes it
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The new description, an empty one removes it
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+impl DocMeta {
+    /// Whether there is nothing to save
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Apply a change by a client, returns `false` without changing anything if it is invalid
+    pub fn update(&mut self, update: MetaUpdate) -> bool {
+        let title_ok = update.title.as_ref().map_or(true, |title| {
+            title.len() <= MAX_TITLE_LEN && !title.contains(char::is_control)
+        });
+        let description_ok = update
+            .description
+            .as_ref()
+            .map_or(true, |description| description.len() <= MAX_DESCRIPTION_LEN);
+        if !title_ok || !description_ok {
+            return false;
+        }
+        if let Some(title) = update.title {
+            let title = title.trim();
+            self.title = Some(title.to_owned()).filter(|_| !title.is_empty());
+        }
+        if let Some(description) = update.description {
+            self.description = Some(description).filter(|d| !d.trim().is_empty());
+        }
+        true
+    }
+
+    /// Add a comment on the range `from..to`, returns it with a new ID
+    pub fn add_comment(
+        &mut self,
+        from: usize,
+        to: usize,
+        text: String,
+        author: String,
+    ) -> &Comment {
+        let id = self.comments.iter().map(|c| c.id + 1).max().unwrap_or(0);
+        self.comments.push(Comment {
+            id,
+            from,
+            to,
+            text,
+            author,
+            time: unix_millis(),
+        });
+        self.comments.last().unwrap()
+    }
+
+    /// Remove the comment with the given ID, returns `false` if there is none
+    pub fn resolve_comment(&mut self, id: u64) -> bool {
+        let len = self.comments.len();
+        self.comments.retain(|comment| comment.id != id);
+        self.comments.len() < len
+    }
+
+    /// Load the metadata of the document at `path`, empty if there is none
+    pub async fn load(store: &dyn DocStore, path: &Path) -> Result<Self, Report> {
+        match store.load(&Self::path_of(path)).await? {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Save the metadata of the document at `path`
+    pub async fn save(&self, store: &dyn DocStore, path: &Path) -> Result<(), Report> {
+        let json = serde_json::to_string(self)?;
+        store.save(&Self::path_of(path), &json).await
+    }
+
+    fn path_of(path: &Path) -> PathBuf {
+        path.with_extension(META_EXTENSION)
+    }
+}
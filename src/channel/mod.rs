@@ -1,23 +1,67 @@
 //! # A channel/room where clients are connected
+mod comment;
+mod content;
+mod cursor;
 mod doc;
+mod history;
+mod meta;
 
-pub use doc::DocState;
+pub use comment::Comment;
+pub use content::{Content, MapState, Marker};
+pub use cursor::Cursor;
+pub(crate) use doc::initial_doc;
+pub use doc::{doc_stats, text_bytes, DocState, DocStats};
+pub use history::{History, Revision};
+use meta::MAX_COMMENTS;
+pub use meta::{DocMeta, MetaUpdate};
 
-use crate::lobby::{ChannelID, UserID};
+use crate::audit::{AuditKind, AuditLog};
+use crate::command::ErrorCode;
+use crate::config::{ContentKind, Format};
+use crate::lobby::{ChannelID, Identity, Role, UserID};
+use crate::metrics::METRICS;
+use crate::store::DocStore;
+use crate::util::{random_token, unix_millis, Fnv1a};
+use crate::webhook::WebhookNotifier;
 use color_eyre::Report;
-use futures_util::future::{select, Either};
-use log::*;
-use prosemirror::markdown::{from_markdown, to_markdown, MarkdownNode, MD};
-use prosemirror::transform::{Step, StepResult, Steps};
+use prosemirror::markdown::{to_markdown, MarkdownNode, MD};
+use prosemirror::transform::{Step, Steps};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
-use tokio::io::{AsyncReadExt, ErrorKind};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::stream::StreamExt;
-use tokio::{
-    fs::File,
-    sync::{broadcast, mpsc, oneshot},
-};
-use tracing::info;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::delay_until;
+use tracing::{debug, error, info, instrument, trace, warn};
+
+/// How often the final save of a channel is attempted
+const SAVE_ATTEMPTS: u64 = 3;
+
+/// How many batches of steps are collected at most before they are broadcast
+const MAX_COALESCED_BATCHES: usize = 32;
+
+/// Save the document when the channel closes, retrying on failure
+async fn save_final(store: &dyn DocStore, path: &Path, md: &str) -> Result<(), Report> {
+    let mut attempt = 1;
+    loop {
+        match store.save(path, md).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < SAVE_ATTEMPTS => {
+                warn!("Failed to save {:?} (attempt {}): {}", path, attempt, e);
+                tokio::time::delay_for(Duration::from_millis(100 * attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                // This is the last copy of the document, so put it into the log
+                error!(?path, error = %e, document = md, "Could not save document");
+                return Err(e);
+            }
+        }
+    }
+}
 
 /// A batch of related steps by the same user. Roughly corresponds to a transaction
 #[derive(Debug, Serialize)]
@@ -26,6 +70,15 @@ pub struct StepBatch {
     pub src: UserID,
     /// The steps to update the editor
     pub steps: Steps<MD>,
+    /// The version of the document after these steps
+    pub version: usize,
+    /// When the steps were applied (in milliseconds since the UNIX epoch)
+    pub time: u64,
+    /// The checksum of the document after these steps
+    ///
+    /// This is the 32-bit FNV-1a hash of the UTF-8 JSON of the document, exactly as the
+    /// `doc` of a `snapshot` frame. A client that disagrees can ask for a new snapshot.
+    pub hash: u32,
 }
 
 /// The reply to an initialization message
@@ -33,8 +86,45 @@ pub struct StepBatch {
 pub struct InitReply {
     /// The last complete state of the doc
     pub doc: String,
-    /// The peers that are currently in the channel
+    /// The peers that are currently in the channel, as an array ordered by ID
     pub j_peers: String,
+    /// The peers as an object keyed by ID, for clients before `padington.v3`
+    pub j_peer_map: String,
+    /// The recent chat messages
+    pub j_chat: String,
+    /// The reactions to the recent chat messages
+    pub j_reactions: String,
+    /// The metadata of the document
+    pub j_meta: String,
+}
+
+/// The reply to an initialization message, with the session of the user
+#[derive(Debug)]
+pub struct SessionReply {
+    /// The ID of the user, differs from the source of the request if a session was resumed
+    pub id: UserID,
+    /// The token to resume this session after a reconnect, if enabled
+    pub token: Option<String>,
+    /// The current state of the channel
+    pub state: InitReply,
+}
+
+/// The reply to a resync request
+#[derive(Debug)]
+pub struct ResyncReply {
+    /// The current state of the channel
+    pub state: InitReply,
+    /// A receiver for all broadcasts after that state
+    pub bct_rx: broadcast::Receiver<Broadcast>,
+}
+
+/// The reply to a snapshot request
+#[derive(Debug)]
+pub struct SnapshotReply {
+    /// The current version and document
+    pub doc: String,
+    /// A receiver for all broadcasts after that state
+    pub bct_rx: broadcast::Receiver<Broadcast>,
 }
 
 /// A request from a client task to the channel task
@@ -53,6 +143,12 @@ pub struct UserConfig {
     name: Option<String>,
     /// When the audio changed, this is `Some(..)` with the new audio setting
     audio: Option<bool>,
+    /// When the color changed, this is `Some(..)` with the new hex color
+    color: Option<String>,
+    /// When the avatar changed, this is `Some(..)` with the new URL (empty if removed)
+    avatar: Option<String>,
+    /// When the status changed, this is `Some(..)` with the new status (empty if removed)
+    status: Option<String>,
 }
 
 /// A kind of request from a client task to the channel
@@ -65,18 +161,87 @@ pub enum RequestKind {
     /// Initialize the connection
     Init {
         /// The reponse channel
-        response: oneshot::Sender<InitReply>,
+        response: oneshot::Sender<SessionReply>,
         /// The name of the client if the user selected one
         name: Option<String>,
+        /// The token of a previous session to resume
+        token: Option<String>,
+        /// The authenticated user, if authentication is enabled
+        identity: Option<Identity>,
         /// The sender signal
         sig_tx: mpsc::Sender<Signal>,
     },
+    /// Request the current state after missing some broadcasts
+    Resync {
+        /// The reponse channel
+        response: oneshot::Sender<ResyncReply>,
+    },
     /// Send a signal to another user
     Signal(Signal),
     /// Update the user data
     Update(UserConfig),
-    /// Close the connection
-    Close,
+    /// Change the title or description of the document
+    Meta(MetaUpdate),
+    /// Start or stop typing
+    Typing(bool),
+    /// Remove another user from the channel
+    Kick(UserID),
+    /// Request the current document, without announcing the user again
+    Snapshot {
+        /// The reponse channel
+        response: oneshot::Sender<SnapshotReply>,
+    },
+    /// Count the words and characters in the document
+    Stats {
+        /// The reponse channel
+        response: oneshot::Sender<DocStats>,
+    },
+    /// List the saved revisions of the document
+    Revisions {
+        /// The reponse channel
+        response: oneshot::Sender<Vec<Revision>>,
+    },
+    /// Load the markdown of a saved revision
+    Revision {
+        /// The version of the revision
+        version: usize,
+        /// The reponse channel
+        response: oneshot::Sender<Option<String>>,
+    },
+    /// Read the document as markdown, without joining the channel
+    Markdown {
+        /// The reponse channel
+        response: oneshot::Sender<String>,
+    },
+    /// Add or remove a reaction to a chat message
+    React {
+        /// The ID of the chat message
+        message: u64,
+        /// The emoji to react with
+        emoji: String,
+    },
+    /// Comment on a range of the document
+    Comment {
+        /// The start of the range
+        from: usize,
+        /// The end of the range
+        to: usize,
+        /// The text of the comment
+        text: String,
+    },
+    /// Resolve (and remove) the comment with the given ID
+    Resolve(u64),
+    /// Move the cursor
+    Cursor {
+        /// The start of the selection
+        from: usize,
+        /// The end of the selection
+        to: usize,
+    },
+    /// Leave the channel but keep the connection open
+    Leave,
+    /// Close the connection, with the reason that the client gave
+    Close(Option<String>),
 }
 
 /// A message from the channel to all clients
@@ -86,7 +251,7 @@ pub enum Broadcast {
     NewUser {
         /// The ID of the new user
         remote_id: UserID,
-        /// The JSON payload for the new user
+        /// The JSON payload for the new user, including its ID
         data: String,
     },
     /// A user left the channel
@@ -95,8 +260,31 @@ pub enum Broadcast {
     Update(UserID, UserConfig),
     /// The shared document has been updated with new steps
     Steps(String),
-    /// A user sent a chat message
-    ChatMessage(UserID, String),
+    /// A user sent a chat message with the given ID
+    ChatMessage(UserID, u64, String),
+    /// A user added or removed a reaction to a chat message
+    Reaction {
+        /// The ID of the chat message
+        message: u64,
+        /// The user that reacted
+        user: UserID,
+        /// The emoji of the reaction
+        emoji: String,
+        /// Whether the reaction was added or removed
+        active: bool,
+    },
+    /// A user started or stopped typing
+    Typing(UserID, bool),
+    /// A user moved their cursor
+    Cursor(UserID, usize, usize),
+    /// An announcement from the server or an admin
+    System(String),
+    /// The metadata of the document changed, as JSON
+    Meta(String),
+    /// A user commented on the document, the comment as JSON
+    Comment(String),
+    /// The comment with the given ID was resolved
+    Resolved(u64),
 }
 
 /// A signal from one client to another
@@ -113,9 +301,80 @@ pub struct Signal {
 /// A kind of signal from one client to another
 #[derive(Debug)]
 pub enum SignalKind {
-    // IDEA: private chat
+    /// A private chat message
+    Whisper(String),
     /// A WebRTC signal
     WebRTC(serde_json::Value),
+    /// An error that is reported back to the sender
+    Error(ErrorCode, String),
+    /// The reciever was removed from the channel and should disconnect
+    Kick,
+}
+
+/// The maximum length of the URL of an avatar (in bytes)
+const MAX_AVATAR_LEN: usize = 512;
+
+/// The maximum length of a status (in bytes)
+const MAX_STATUS_LEN: usize = 100;
+
+/// The colors that are assigned to users in turn
+const PALETTE: [&str; 10] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+    "#469990", "#800000",
+];
+
+/// Hand a signal to a client without waiting for it
+///
+/// A client that does not take its signals must not stall the channel for everyone else,
+/// so the signal is dropped if its buffer is full.
+fn send_signal(sig_tx: &mut mpsc::Sender<Signal>, signal: Signal) {
+    match sig_tx.try_send(signal) {
+        Ok(()) => {}
+        Err(TrySendError::Full(s)) => {
+            METRICS.signals_dropped.inc();
+            warn!("Dropped signal for a slow client {:?}", s);
+        }
+        Err(TrySendError::Closed(s)) => warn!("Failed to send signal {:?}", s),
+    }
+}
+
+/// The name of a user that did not pick one
+fn default_name(id: UserID) -> String {
+    format!("Bear #{}", id.int_val())
+}
+
+/// The color of a user that did not pick one
+fn default_color(id: UserID) -> String {
+    PALETTE[id.int_val() as usize % PALETTE.len()].to_owned()
+}
+
+/// Check that a color is a hex color like `#1a2b3c`, returns it in lowercase
+fn sanitize_color(color: &str) -> Option<String> {
+    let digits = color.strip_prefix('#')?;
+    if digits.len() == 6 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(color.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Clean up a user name, returns `None` if nothing usable is left
+fn sanitize_name(name: &str, max_len: usize) -> Option<String> {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name.len() > max_len {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// Check that an avatar is an `http(s)` URL that is not too long
+fn check_avatar(avatar: &str) -> bool {
+    let is_http = avatar.starts_with("https://") || avatar.starts_with("http://");
+    is_http
+        && avatar.len() <= MAX_AVATAR_LEN
+        && !avatar.contains(|c: char| c.is_whitespace() || c.is_control())
 }
 
 /// The data that represents a user
@@ -124,16 +383,72 @@ struct UserData {
     name: String,
     /// Whether the user has audio enabled
     audio: bool,
+    /// The color of the cursor and name of the user
+    color: String,
+    /// The URL of the avatar of the user, if any
+    avatar: Option<String>,
+    /// What the user is currently doing, if they said so
+    status: Option<String>,
+    /// The selection of the user, if known
+    cursor: Option<Cursor>,
+    /// The authenticated user, if authentication is enabled
+    identity: Option<Identity>,
+    /// What the user is allowed to do
+    role: Role,
     /// The signal channel
     sig_tx: mpsc::Sender<Signal>,
+    /// The token to resume this session, if enabled
+    token: Option<String>,
+}
+
+/// A recent chat message, as replayed to new users
+#[derive(Serialize)]
+struct ChatEntry {
+    /// The ID of the message
+    id: u64,
+    /// The user that sent the message
+    src: UserID,
+    /// The text of the message
+    text: String,
+    /// When the message was sent (in milliseconds since the UNIX epoch)
+    time: u64,
+    /// The users that reacted to the message, by emoji
+    #[serde(skip)]
+    reactions: BTreeMap<String, Vec<UserID>>,
+}
+
+/// A session of a user that disconnected and may be resumed
+struct Session {
+    /// The ID of the user
+    id: UserID,
+    /// The name of the user
+    name: String,
+    /// Whether the user had audio enabled
+    audio: bool,
+    /// The color of the user
+    color: String,
+    /// The URL of the avatar of the user, if any
+    avatar: Option<String>,
+    /// The status of the user, if any
+    status: Option<String>,
+    /// The authenticated user, if authentication is enabled
+    identity: Option<Identity>,
+    /// When this session can no longer be resumed
+    expires: Instant,
 }
 
 impl UserData {
     /// Get the subset of that data that is public
-    fn public(&self) -> PublicMemberData {
+    fn public(&self, id: UserID) -> PublicMemberData {
         PublicMemberData {
+            id,
             name: &self.name,
             audio: self.audio,
+            color: &self.color,
+            avatar: self.avatar.as_deref(),
+            status: self.status.as_deref(),
+            cursor: self.cursor,
+            role: self.role,
         }
     }
 }
@@ -141,8 +456,23 @@ impl UserData {
 /// Data for a client that is public
 #[derive(Debug, Clone, Serialize)]
 pub struct PublicMemberData<'a> {
+    id: UserID,
     name: &'a str,
     audio: bool,
+    color: &'a str,
+    avatar: Option<&'a str>,
+    status: Option<&'a str>,
+    cursor: Option<Cursor>,
+    role: Role,
+}
+
+/// Where the document of a channel is kept
+#[derive(Debug, Clone)]
+pub enum Persistence {
+    /// The document is loaded from and saved to the store at this path, in this format
+    Disk(PathBuf, Format),
+    /// The document only lives as long as the channel
+    Memory,
 }
 
 /// The channel
@@ -153,75 +483,411 @@ pub struct Channel {
     pub msg_rx: mpsc::Receiver<Request>,
     /// The reciever for the termination from the lobby
     pub ter_rx: oneshot::Receiver<()>,
+    /// The sender to tell the lobby whether the document could be loaded
+    pub ready_tx: oneshot::Sender<Result<(), String>>,
+    /// The storage for the document
+    pub store: Arc<dyn DocStore>,
+    /// Whether the document is stored at all
+    pub persistence: Persistence,
+    /// What the channel holds
+    pub kind: ContentKind,
+    /// The document to start with if there is none yet
+    pub template: MarkdownNode,
+    /// The maximum length of a user name (in bytes)
+    pub max_name_len: usize,
+    /// Whether users have to choose a name to join
+    pub require_name: bool,
+    /// The maximum size of the text of the document (in bytes)
+    pub max_doc_bytes: Option<usize>,
+    /// How long a disconnected user can resume their session, if enabled
+    pub resume_grace: Option<Duration>,
+    /// The number of recent chat messages that are kept
+    pub chat_history: usize,
+    /// How long applied steps are collected to be broadcast together, if at all
+    pub step_coalesce: Option<Duration>,
 }
 
 /// The outgoing edges from the channel
 pub struct ChannelComms {
     /// The channel ID
     pub id: ChannelID,
-    /// The path of this channel
+    /// The path of the file of this channel
     pub path: PathBuf,
+    /// The path of this channel, as clients join it
+    pub doc_path: String,
     /// The sender for broadcasts
     pub bct_tx: broadcast::Sender<Broadcast>,
     /// The sender to notify the lobby when the channel is empty
     pub end_tx: mpsc::Sender<ChannelID>,
+    /// The audit log, if enabled
+    pub audit: Option<AuditLog>,
+    /// The webhook task of this channel, if enabled
+    pub webhook: Option<WebhookNotifier>,
+    /// The snapshots of the document, if enabled
+    pub history: Option<History>,
 }
 
 impl ChannelComms {
+    /// Record an event in the audit log, if enabled
+    fn audit(&mut self, user: UserID, kind: AuditKind) {
+        if let Some(audit) = &mut self.audit {
+            audit.record(self.id, user, kind);
+        }
+    }
+
+    /// Broadcast the steps that were applied since the last flush, in a single message
+    fn flush_steps(&mut self, c_state: &mut ChannelState) {
+        c_state.pending_since = None;
+        if c_state.pending_steps.is_empty() {
+            return;
+        }
+        let text = serde_json::to_string(&c_state.pending_steps).unwrap();
+        c_state.pending_steps.clear();
+        if let Err(e) = self.bct_tx.send(Broadcast::Steps(text)) {
+            error!("Error sending broadcast {:?}", e);
+        }
+    }
+
     /// The function to handle an incoming request from a client
     async fn handle_request(&mut self, c_state: &mut ChannelState, request: Request) {
         let id = request.source;
+        // Everything else is ordered after the steps that were applied before it. This
+        // includes the states that are sent to new clients.
+        if !matches!(request.kind, RequestKind::Steps(..)) {
+            self.flush_steps(c_state);
+        }
+        match &request.kind {
+            RequestKind::Init { .. }
+            | RequestKind::Markdown { .. }
+            | RequestKind::Leave
+            | RequestKind::Close(_) => {}
+            _ if !c_state.member_data.contains_key(&id) => {
+                warn!("Ignoring request from uninitialized {}", id);
+                return;
+            }
+            _ => {}
+        }
         match request.kind {
             RequestKind::Init {
                 response,
                 name,
-                sig_tx,
+                token,
+                identity,
+                mut sig_tx,
             } => {
-                let doc = serde_json::to_string(&c_state.doc_state).unwrap();
-                // let steps = serde_json::to_string(&c_state.step_buffer).unwrap();
-
-                let new_name = name.unwrap_or_else(|| format!("Bear #{}", id.int_val()));
-                let new_data = UserData {
-                    name: new_name,
+                // A client that was kicked has to disconnect before it may come back
+                if c_state.kicked.contains(&id) {
+                    warn!("Ignoring init from kicked {}", id);
+                    return;
+                }
+                // A client that is already a member can't take over another session
+                let session = token
+                    .filter(|_| !c_state.member_data.contains_key(&id))
+                    .and_then(|token| c_state.resume(&token, identity.as_ref()));
+                let name = name.and_then(|name| sanitize_name(&name, c_state.max_name_len));
+                if session.is_none() && name.is_none() && c_state.require_name {
+                    // The client can try again with a name
+                    info!("Rejected {} without a name", id);
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::NameRequired,
+                            String::from("Choose a name to join"),
+                        ),
+                    };
+                    send_signal(&mut sig_tx, reply);
+                    return;
+                }
+                // Everyone else starts with a fresh session
+                let session = session.unwrap_or_else(|| Session {
+                    id,
+                    name: name.unwrap_or_else(|| default_name(id)),
                     audio: false,
+                    color: default_color(id),
+                    avatar: None,
+                    status: None,
+                    identity: None,
+                    expires: Instant::now(),
+                });
+                if session.id != id {
+                    info!("{} resumed the session of {}", id, session.id);
+                }
+                let id = session.id;
+                let role = identity.as_ref().map_or(Role::Editor, |i| i.role);
+                let new_data = UserData {
+                    name: session.name,
+                    audio: session.audio,
+                    color: session.color,
+                    avatar: session.avatar,
+                    status: session.status,
+                    cursor: None,
+                    identity,
+                    role,
                     sig_tx,
+                    token: c_state.resume_grace.map(|_| random_token()),
                 };
-                let j_data = serde_json::to_string(&new_data.public()).unwrap();
-
-                c_state.member_data.insert(id, new_data);
-
-                let peers = c_state
-                    .member_data
-                    .iter()
-                    .map(|(id, data)| (id, data.public()))
-                    .collect::<HashMap<_, _>>();
+                let j_data = serde_json::to_string(&new_data.public(id)).unwrap();
+                let token = new_data.token.clone();
 
-                let j_peers = serde_json::to_string(&peers).unwrap();
-
-                let reply = InitReply {
-                    doc,
-                    //steps,
-                    j_peers,
+                if c_state.member_data.insert(id, new_data).is_none() {
+                    METRICS.live_users.inc();
+                    self.audit(id, AuditKind::Join);
+                }
+                let reply = SessionReply {
+                    id,
+                    token,
+                    state: c_state.init_reply(),
                 };
 
                 if let Err(_e) = response.send(reply) {
                     error!("Client dropped while initializing");
                 } else {
-                    info!("New user: {}", id);
-                    self.bct_tx
-                        .send(Broadcast::NewUser {
-                            remote_id: id,
-                            data: j_data,
-                        })
-                        .unwrap();
+                    let identity = c_state
+                        .member_data
+                        .get(&id)
+                        .and_then(|m| m.identity.as_ref());
+                    info!("New user: {} ({:?})", id, identity);
+                    let new_user = Broadcast::NewUser {
+                        remote_id: id,
+                        data: j_data,
+                    };
+                    if let Err(e) = self.bct_tx.send(new_user) {
+                        error!("Error sending broadcast {:?}", e);
+                    }
+                }
+            }
+            RequestKind::Resync { response } => {
+                let reply = ResyncReply {
+                    state: c_state.init_reply(),
+                    bct_rx: self.bct_tx.subscribe(),
+                };
+                if let Err(_e) = response.send(reply) {
+                    error!("Client dropped while resyncing");
+                } else {
+                    info!("Resynced {}", id);
+                }
+            }
+            RequestKind::Snapshot { response } => {
+                let reply = SnapshotReply {
+                    doc: c_state.doc_json(),
+                    bct_rx: self.bct_tx.subscribe(),
+                };
+                if let Err(_e) = response.send(reply) {
+                    error!("Client dropped while taking a snapshot");
+                }
+            }
+            RequestKind::Stats { response } => {
+                let stats = c_state
+                    .content
+                    .doc()
+                    .map(|doc_state| doc_stats(&doc_state.doc))
+                    .unwrap_or_default();
+                if let Err(_e) = response.send(stats) {
+                    error!("Client dropped while computing stats");
+                }
+            }
+            RequestKind::Revisions { response } => {
+                // Reading the store may take a while, so don't block the channel
+                let history = self.history.clone();
+                tokio::spawn(async move {
+                    let revisions = match history {
+                        Some(history) => history.list().await.unwrap_or_else(|e| {
+                            error!("Could not list revisions: {:?}", e);
+                            Vec::new()
+                        }),
+                        None => Vec::new(),
+                    };
+                    if let Err(_e) = response.send(revisions) {
+                        error!("Client dropped while listing revisions");
+                    }
+                });
+            }
+            RequestKind::Revision { version, response } => {
+                let history = self.history.clone();
+                tokio::spawn(async move {
+                    let md = match history {
+                        Some(history) => history.load(version).await.unwrap_or_else(|e| {
+                            error!("Could not load revision {}: {:?}", version, e);
+                            None
+                        }),
+                        None => None,
+                    };
+                    if let Err(_e) = response.send(md) {
+                        error!("Client dropped while loading a revision");
+                    }
+                });
+            }
+            RequestKind::Markdown { response } => {
+                match c_state
+                    .content
+                    .doc()
+                    .map(|doc_state| to_markdown(&doc_state.doc))
+                {
+                    Some(Ok(md)) => {
+                        if let Err(_e) = response.send(md) {
+                            error!("Reader dropped while serializing the document");
+                        }
+                    }
+                    Some(Err(e)) => error!("Could not serialize the document: {}", e),
+                    None => info!("Only documents can be read as markdown"),
                 }
             }
             RequestKind::Chat(text) => {
+                METRICS.chat_messages.inc();
                 info!("New message: {}", text);
-                self.bct_tx.send(Broadcast::ChatMessage(id, text)).unwrap();
+                let message = c_state.push_chat(id, text.clone());
+                if let Err(e) = self.bct_tx.send(Broadcast::ChatMessage(id, message, text)) {
+                    error!("Error sending broadcast {:?}", e);
+                }
+            }
+            RequestKind::React { message, emoji } => {
+                match c_state.chat.iter_mut().find(|entry| entry.id == message) {
+                    Some(entry) => {
+                        // Reacting twice with the same emoji takes the reaction back
+                        let users = entry.reactions.entry(emoji.clone()).or_default();
+                        let active = match users.iter().position(|user| *user == id) {
+                            Some(index) => {
+                                users.remove(index);
+                                false
+                            }
+                            None => {
+                                users.push(id);
+                                true
+                            }
+                        };
+                        if users.is_empty() {
+                            entry.reactions.remove(&emoji);
+                        }
+                        let reaction = Broadcast::Reaction {
+                            message,
+                            user: id,
+                            emoji,
+                            active,
+                        };
+                        if let Err(e) = self.bct_tx.send(reaction) {
+                            error!("Error sending broadcast {:?}", e);
+                        }
+                    }
+                    None => {
+                        if let Some(member) = c_state.member_data.get_mut(&id) {
+                            let reply = Signal {
+                                sender: id,
+                                reciever: id,
+                                kind: SignalKind::Error(
+                                    ErrorCode::NotFound,
+                                    format!("Unknown message {}", message),
+                                ),
+                            };
+                            send_signal(&mut member.sig_tx, reply);
+                        }
+                    }
+                }
             }
-            RequestKind::Update(cfg) => {
-                let member = c_state.member_data.get_mut(&id).unwrap();
+            RequestKind::Meta(_) if c_state.role_of(id) == Some(Role::Viewer) => {
+                info!("Rejected metadata from viewer {}", id);
+                if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::Forbidden,
+                            String::from("Viewers can not edit"),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
+                }
+            }
+            RequestKind::Meta(update) => {
+                if c_state.meta.update(update) {
+                    info!("{} changed the metadata", id);
+                    let json = serde_json::to_string(&c_state.meta).unwrap();
+                    if let Err(e) = self.bct_tx.send(Broadcast::Meta(json)) {
+                        error!("Error sending broadcast {:?}", e);
+                    }
+                } else if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::InvalidArgument,
+                            String::from("Invalid title or description"),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
+                }
+            }
+            RequestKind::Comment { .. } if c_state.content.doc().is_none() => {
+                if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::Unavailable,
+                            String::from("Only documents can be commented on"),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
+                }
+            }
+            RequestKind::Comment { .. } if c_state.meta.comments.len() >= MAX_COMMENTS => {
+                if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::TooLarge,
+                            format!("The document has {} open comments", MAX_COMMENTS),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
+                }
+            }
+            RequestKind::Comment { from, to, text } => {
+                let author = match c_state.member_data.get(&id) {
+                    Some(member) => member.name.clone(),
+                    None => {
+                        warn!("Ignoring comment from unknown {}", id);
+                        return;
+                    }
+                };
+                let comment = c_state.meta.add_comment(from, to, text, author);
+                info!("{} added comment {}", id, comment.id);
+                let json = serde_json::to_string(comment).unwrap();
+                if let Err(e) = self.bct_tx.send(Broadcast::Comment(json)) {
+                    error!("Error sending broadcast {:?}", e);
+                }
+            }
+            RequestKind::Resolve(comment) => {
+                if c_state.meta.resolve_comment(comment) {
+                    info!("{} resolved comment {}", id, comment);
+                    if let Err(e) = self.bct_tx.send(Broadcast::Resolved(comment)) {
+                        error!("Error sending broadcast {:?}", e);
+                    }
+                } else if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::NotFound,
+                            format!("Unknown comment {}", comment),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
+                }
+            }
+            RequestKind::Update(mut cfg) => {
+                if let Some(new_name) = &mut cfg.name {
+                    *new_name = sanitize_name(new_name, c_state.max_name_len)
+                        .unwrap_or_else(|| default_name(id));
+                }
+                let member = match c_state.member_data.get_mut(&id) {
+                    Some(member) => member,
+                    None => {
+                        warn!("Ignoring update from unknown {}", id);
+                        return;
+                    }
+                };
                 if let Some(new_name) = &cfg.name {
                     let old_name = &mut member.name;
                     info!({from = old_name.as_str(), to= new_name.as_str()}, "{} changed their name", id);
@@ -230,48 +896,223 @@ impl ChannelComms {
                 if let Some(audio) = &cfg.audio {
                     member.audio = *audio;
                 }
+                // An empty avatar or status removes it
+                if let Some(avatar) = cfg.avatar.take() {
+                    if avatar.is_empty() || check_avatar(&avatar) {
+                        member.avatar = Some(avatar.clone()).filter(|a| !a.is_empty());
+                        cfg.avatar = Some(avatar);
+                    } else {
+                        let reply = Signal {
+                            sender: id,
+                            reciever: id,
+                            kind: SignalKind::Error(
+                                ErrorCode::InvalidArgument,
+                                String::from("Invalid avatar URL"),
+                            ),
+                        };
+                        send_signal(&mut member.sig_tx, reply);
+                    }
+                }
+                if let Some(status) = &mut cfg.status {
+                    *status = sanitize_name(status, MAX_STATUS_LEN).unwrap_or_default();
+                    member.status = Some(status.clone()).filter(|s| !s.is_empty());
+                }
+                if let Some(color) = cfg.color.take() {
+                    match sanitize_color(&color) {
+                        Some(color) => {
+                            member.color = color.clone();
+                            cfg.color = Some(color);
+                        }
+                        None => {
+                            let reply = Signal {
+                                sender: id,
+                                reciever: id,
+                                kind: SignalKind::Error(
+                                    ErrorCode::InvalidArgument,
+                                    format!("Invalid color {:?}", color),
+                                ),
+                            };
+                            send_signal(&mut member.sig_tx, reply);
+                        }
+                    }
+                }
                 if let Err(e) = self.bct_tx.send(Broadcast::Update(id, cfg)) {
                     error!("Error sending broadcast {:?}", e);
                 }
             }
+            RequestKind::Typing(typing) => {
+                trace!("{} typing: {}", id, typing);
+                if let Err(e) = self.bct_tx.send(Broadcast::Typing(id, typing)) {
+                    error!("Error sending broadcast {:?}", e);
+                }
+            }
+            RequestKind::Cursor { from, to } => {
+                if let Some(member) = c_state.member_data.get_mut(&id) {
+                    member.cursor = Some(Cursor::new(from, to));
+                }
+                if let Err(e) = self.bct_tx.send(Broadcast::Cursor(id, from, to)) {
+                    error!("Error sending broadcast {:?}", e);
+                }
+            }
             RequestKind::Signal(signal) => {
-                let member = c_state.member_data.get_mut(&signal.reciever).unwrap();
                 trace!("{:?}", signal);
-                if let Err(s) = member.sig_tx.send(signal).await {
-                    warn!("Failed to send signal {:?}", s);
+                if let Some(member) = c_state.member_data.get_mut(&signal.reciever) {
+                    send_signal(&mut member.sig_tx, signal);
+                } else {
+                    warn!("Dropped signal from {} to unknown {}", id, signal.reciever);
+                    if let Some(member) = c_state.member_data.get_mut(&id) {
+                        let reply = Signal {
+                            sender: id,
+                            reciever: id,
+                            kind: SignalKind::Error(
+                                ErrorCode::NotFound,
+                                format!("Unknown {}", signal.reciever),
+                            ),
+                        };
+                        send_signal(&mut member.sig_tx, reply);
+                    }
+                }
+            }
+            RequestKind::Steps(_, _) if c_state.content.doc().is_none() => {
+                info!("Rejected steps from {}, the channel holds no document", id);
+                METRICS.steps_rejected.inc();
+                if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::Unavailable,
+                            String::from("Only documents can be edited with steps"),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
+                }
+            }
+            RequestKind::Steps(_, _) if c_state.role_of(id) == Some(Role::Viewer) => {
+                info!("Rejected steps from viewer {}", id);
+                METRICS.steps_rejected.inc();
+                if let Some(member) = c_state.member_data.get_mut(&id) {
+                    let reply = Signal {
+                        sender: id,
+                        reciever: id,
+                        kind: SignalKind::Error(
+                            ErrorCode::Forbidden,
+                            String::from("Viewers can not edit"),
+                        ),
+                    };
+                    send_signal(&mut member.sig_tx, reply);
                 }
             }
             RequestKind::Steps(version, steps) => {
-                if version == c_state.doc_state.version {
-                    info!("Received steps for version {}", version);
+                let current = c_state.content.doc().filter(|doc| doc.version == version);
+                if let Some(doc_state) = current {
+                    let identity = c_state
+                        .member_data
+                        .get(&id)
+                        .and_then(|m| m.identity.as_ref());
+                    info!(
+                        "Received steps for version {} from {} ({:?})",
+                        version, id, identity
+                    );
 
+                    /// Apply all steps to a copy of the document, so that nothing changes if
+                    /// any of them fails. The error has the index of the step that failed.
                     fn apply_steps(
                         doc: &MarkdownNode,
                         (first, rest): (&Step<MD>, &[Step<MD>]),
-                    ) -> StepResult<MD> {
+                    ) -> Result<MarkdownNode, (usize, String)> {
                         debug!("Step {:?}", first);
-                        let mut new_doc = first.apply(doc)?;
-                        for step in rest {
+                        let mut new_doc = first.apply(doc).map_err(|e| (0, format!("{:?}", e)))?;
+                        for (index, step) in rest.iter().enumerate() {
                             debug!("Step {:?}", step);
-                            new_doc = step.apply(&new_doc)?;
+                            new_doc = step
+                                .apply(&new_doc)
+                                .map_err(|e| (index + 1, format!("{:?}", e)))?;
                         }
                         Ok(new_doc)
                     }
 
                     if let Some(fr) = steps.split_first() {
-                        match apply_steps(&c_state.doc_state.doc, fr) {
+                        match apply_steps(&doc_state.doc, fr) {
+                            Ok(new_doc) if c_state.exceeds_size_limit(&new_doc) => {
+                                info!("Rejected steps from {}, the document is too large", id);
+                                METRICS.steps_rejected.inc();
+                                if let Some(member) = c_state.member_data.get_mut(&id) {
+                                    let max = c_state.max_doc_bytes.unwrap_or_default();
+                                    let msg = format!("Document exceeds {} bytes", max);
+                                    let reply = Signal {
+                                        sender: id,
+                                        reciever: id,
+                                        kind: SignalKind::Error(ErrorCode::TooLarge, msg),
+                                    };
+                                    send_signal(&mut member.sig_tx, reply);
+                                }
+                            }
                             Ok(new_doc) => {
-                                c_state.doc_state.doc = new_doc;
-                                c_state.doc_state.version += steps.len();
+                                c_state.update_doc(new_doc, steps.len());
+                                METRICS.steps_applied.inc();
+                                let version = version + steps.len();
+                                let doc = c_state.content.doc();
+                                if let (Some(history), Some(doc_state)) = (&self.history, doc) {
+                                    if history.is_due(version - steps.len(), version) {
+                                        match to_markdown(&doc_state.doc) {
+                                            Ok(md) => history.snapshot(version, md),
+                                            Err(e) => error!("Could not serialize revision: {}", e),
+                                        }
+                                    }
+                                }
+                                self.audit(id, AuditKind::Steps { version });
+                                if let Some(webhook) = &self.webhook {
+                                    webhook.notify(&self.doc_path, version, id);
+                                }
+
+                                for member in c_state.member_data.values_mut() {
+                                    if let Some(cursor) = &mut member.cursor {
+                                        for step in steps.iter() {
+                                            cursor.map(step);
+                                        }
+                                    }
+                                }
+                                for comment in &mut c_state.meta.comments {
+                                    for step in steps.iter() {
+                                        comment.map(step);
+                                    }
+                                }
 
-                                let batch = StepBatch { src: id, steps };
-                                let msg = [&batch];
-                                let text = serde_json::to_string(&msg).unwrap();
+                                let batch = StepBatch {
+                                    src: id,
+                                    steps,
+                                    version,
+                                    time: unix_millis(),
+                                    hash: c_state.doc_hash(),
+                                };
                                 //c_state.step_buffer.push(batch);
-                                self.bct_tx.send(Broadcast::Steps(text)).unwrap();
+                                c_state.pending_since.get_or_insert_with(Instant::now);
+                                c_state.pending_steps.push(batch);
+                                if c_state.step_coalesce.is_none()
+                                    || c_state.pending_steps.len() >= MAX_COALESCED_BATCHES
+                                {
+                                    self.flush_steps(c_state);
+                                }
                             }
-                            Err(err) => {
-                                warn!("Failed to apply some step: {:?}", err);
+                            Err((index, reason)) => {
+                                warn!("Failed to apply step {} from {}: {}", index, id, reason);
+                                METRICS.steps_rejected.inc();
+                                if let Some(member) = c_state.member_data.get_mut(&id) {
+                                    // The document is unchanged, none of the steps were applied
+                                    let msg = format!(
+                                        "Step {} of {} does not apply to the document: {}",
+                                        index + 1,
+                                        steps.len(),
+                                        reason
+                                    );
+                                    let reply = Signal {
+                                        sender: id,
+                                        reciever: id,
+                                        kind: SignalKind::Error(ErrorCode::StepsRejected, msg),
+                                    };
+                                    send_signal(&mut member.sig_tx, reply);
+                                }
                             }
                         }
                     } else {
@@ -279,11 +1120,63 @@ impl ChannelComms {
                     }
                 } else {
                     info!("Rejected steps for outdated version {}", version);
+                    METRICS.steps_rejected.inc();
                 }
             }
-            RequestKind::Close => {
-                info!("User left: {}", id);
-                c_state.member_data.remove(&id);
+            RequestKind::Kick(target) => match c_state.member_data.remove(&target) {
+                Some(member) => {
+                    info!("{} kicked {}", id, target);
+                    METRICS.live_users.dec();
+                    self.audit(target, AuditKind::Kicked { by: id });
+                    c_state.kicked.insert(target);
+                    let signal = Signal {
+                        sender: id,
+                        reciever: target,
+                        kind: SignalKind::Kick,
+                    };
+                    // Unlike other signals, a kick must never be dropped, but a slow client
+                    // must not stall the channel either, so it is delivered by its own task
+                    let mut sig_tx = member.sig_tx;
+                    tokio::spawn(async move {
+                        if let Err(err) = sig_tx.send(signal).await {
+                            warn!("Failed to send kick {:?}", err.0);
+                        }
+                    });
+                    if let Err(err) = self.bct_tx.send(Broadcast::UserLeft(target)) {
+                        info!("No client left: {:?}", err);
+                    }
+                    if let Err(err) = self.end_tx.send(self.id).await {
+                        error!("Could not send quit message: {}", err);
+                    }
+                }
+                None => {
+                    warn!("{} tried to kick unknown {}", id, target);
+                    if let Some(member) = c_state.member_data.get_mut(&id) {
+                        let reply = Signal {
+                            sender: id,
+                            reciever: id,
+                            kind: SignalKind::Error(
+                                ErrorCode::NotFound,
+                                format!("Unknown {}", target),
+                            ),
+                        };
+                        send_signal(&mut member.sig_tx, reply);
+                    }
+                }
+            },
+            RequestKind::Leave | RequestKind::Close(_) if c_state.kicked.remove(&id) => {
+                debug!("Kicked user {} disconnected", id);
+            }
+            kind @ RequestKind::Leave | kind @ RequestKind::Close(_) => {
+                match kind {
+                    RequestKind::Close(Some(reason)) => info!("User left: {} ({})", id, reason),
+                    _ => info!("User left: {}", id),
+                }
+                if let Some(member) = c_state.member_data.remove(&id) {
+                    METRICS.live_users.dec();
+                    self.audit(id, AuditKind::Leave);
+                    c_state.park(id, member);
+                }
 
                 if let Err(err) = self.bct_tx.send(Broadcast::UserLeft(id)) {
                     info!("No client left, shutting down: {:?}", err);
@@ -303,59 +1196,693 @@ pub struct ChannelState {
     /// The data for each channel member
     #[new(default)]
     member_data: HashMap<UserID, UserData>,
-    /// The state of the common document
-    doc_state: DocState,
+    /// The users that were kicked but did not disconnect yet
+    #[new(default)]
+    kicked: HashSet<UserID>,
+    /// The sessions of disconnected users, by their resume token
+    #[new(default)]
+    sessions: HashMap<String, Session>,
+    /// The recent chat messages
+    #[new(default)]
+    chat: VecDeque<ChatEntry>,
+    /// The ID of the next chat message
+    #[new(default)]
+    next_message: u64,
+    /// What the clients work on together
+    content: Content,
+    /// The title, description and timestamps of the document
+    meta: DocMeta,
+    /// The serialized `content`, until it changes
+    #[new(default)]
+    doc_json: Option<String>,
+    /// The maximum length of a user name (in bytes)
+    max_name_len: usize,
+    /// Whether users have to choose a name to join
+    require_name: bool,
+    /// The maximum size of the text of the document (in bytes)
+    max_doc_bytes: Option<usize>,
+    /// How long a disconnected user can resume their session, if enabled
+    resume_grace: Option<Duration>,
+    /// The number of recent chat messages that are kept
+    chat_history: usize,
+    /// How long applied steps are collected to be broadcast together, if at all
+    step_coalesce: Option<Duration>,
+    /// The applied steps that were not broadcast yet
+    #[new(default)]
+    pending_steps: Vec<StepBatch>,
+    /// When the oldest of the `pending_steps` was applied
+    #[new(default)]
+    pending_since: Option<Instant>,
+}
+
+impl ChannelState {
+    /// Remember a new chat message, returns its ID
+    fn push_chat(&mut self, src: UserID, text: String) -> u64 {
+        let id = self.next_message;
+        self.next_message += 1;
+        if self.chat_history == 0 {
+            return id;
+        }
+        if self.chat.len() >= self.chat_history {
+            self.chat.pop_front();
+        }
+        self.chat.push_back(ChatEntry {
+            id,
+            src,
+            text,
+            time: unix_millis(),
+            reactions: BTreeMap::new(),
+        });
+        id
+    }
+
+    /// Keep the session of a member that disconnected, so that it can be resumed
+    fn park(&mut self, id: UserID, member: UserData) {
+        self.prune_sessions();
+        if let (Some(grace), Some(token)) = (self.resume_grace, member.token) {
+            let session = Session {
+                id,
+                name: member.name,
+                audio: member.audio,
+                color: member.color,
+                avatar: member.avatar,
+                status: member.status,
+                identity: member.identity,
+                expires: Instant::now() + grace,
+            };
+            self.sessions.insert(token, session);
+        }
+    }
+
+    /// Forget the sessions that can no longer be resumed
+    fn prune_sessions(&mut self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, session| session.expires > now);
+    }
+
+    /// Take the session for `token`, if it has not expired and belongs to the same user
+    fn resume(&mut self, token: &str, identity: Option<&Identity>) -> Option<Session> {
+        self.prune_sessions();
+        let session = self.sessions.remove(token)?;
+        let same_user = match (&session.identity, identity) {
+            (Some(old), Some(new)) => old.name == new.name,
+            (None, None) => true,
+            _ => false,
+        };
+        Some(session).filter(|_| same_user)
+    }
+
+    /// Whether `new_doc` grows the document beyond its size limit. Changes that shrink an
+    /// oversized document are still allowed.
+    fn exceeds_size_limit(&self, new_doc: &MarkdownNode) -> bool {
+        match self.max_doc_bytes {
+            Some(max) => {
+                let size = text_bytes(new_doc);
+                let old_size = self.content.doc().map_or(0, |d| text_bytes(&d.doc));
+                size > max && size > old_size
+            }
+            None => false,
+        }
+    }
+
+    /// The role of a member of this channel
+    fn role_of(&self, id: UserID) -> Option<Role> {
+        self.member_data.get(&id).map(|member| member.role)
+    }
+
+    /// When the pending steps need to be broadcast, if there are any
+    fn flush_deadline(&self) -> Option<Instant> {
+        Some(self.pending_since? + self.step_coalesce?)
+    }
+
+    /// Replace the document after `count` steps were applied to it
+    fn update_doc(&mut self, doc: MarkdownNode, count: usize) {
+        if let Content::Document(doc_state) = &mut self.content {
+            doc_state.doc = doc;
+            doc_state.version += count;
+        }
+        self.meta.modified = Some(unix_millis());
+        self.doc_json = None;
+    }
+
+    /// The checksum of the current document, see [`StepBatch::hash`]
+    fn doc_hash(&self) -> u32 {
+        let mut hasher = Fnv1a::default();
+        if let Some(doc_state) = self.content.doc() {
+            // Serialize straight into the hasher, without building the string
+            serde_json::to_writer(&mut hasher, &doc_state.doc).unwrap();
+        }
+        hasher.finish()
+    }
+
+    /// The current content as JSON, serialized once per version
+    fn doc_json(&mut self) -> String {
+        let content = &self.content;
+        self.doc_json
+            .get_or_insert_with(|| serde_json::to_string(content).unwrap())
+            .clone()
+    }
+
+    /// The current document and peers, as sent to a client
+    fn init_reply(&mut self) -> InitReply {
+        let doc = self.doc_json();
+        // let steps = serde_json::to_string(&self.step_buffer).unwrap();
+
+        let mut peers = self
+            .member_data
+            .iter()
+            .map(|(id, data)| data.public(*id))
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.id.int_val());
+        let j_peers = serde_json::to_string(&peers).unwrap();
+        let peer_map = peers
+            .iter()
+            .map(|peer| (peer.id.int_val(), peer))
+            .collect::<BTreeMap<_, _>>();
+        let j_peer_map = serde_json::to_string(&peer_map).unwrap();
+
+        let j_chat = serde_json::to_string(&self.chat).unwrap();
+        let reactions = self
+            .chat
+            .iter()
+            .filter(|entry| !entry.reactions.is_empty())
+            .map(|entry| (entry.id, &entry.reactions))
+            .collect::<BTreeMap<_, _>>();
+        let j_reactions = serde_json::to_string(&reactions).unwrap();
+
+        InitReply {
+            doc,
+            //steps,
+            j_peers,
+            j_peer_map,
+            j_chat,
+            j_reactions,
+            j_meta: serde_json::to_string(&self.meta).unwrap(),
+        }
+    }
 }
 
 impl Channel {
+    /// Load the content and metadata, creating the document if there is none yet
+    async fn load(&self) -> Result<(Content, DocMeta), Report> {
+        let mut meta = match &self.persistence {
+            Persistence::Disk(path, _) => DocMeta::load(self.store.as_ref(), path)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Could not load the metadata: {:?}", e);
+                    DocMeta::default()
+                }),
+            Persistence::Memory => DocMeta::default(),
+        };
+        let content = match &self.persistence {
+            Persistence::Disk(path, format) => match self.store.load(path).await? {
+                Some(buf) => Content::parse(self.kind, *format, &buf)?,
+                None => {
+                    meta.created = Some(unix_millis());
+                    let content = Content::initial(self.kind, &self.template);
+                    let text = content.write(*format)?;
+                    self.store.save(path, &text).await?;
+                    content
+                }
+            },
+            Persistence::Memory => Content::initial(self.kind, &self.template),
+        };
+        Ok((content, meta))
+    }
+
     /// The main task for a channel
+    ///
+    /// Reports to the lobby whether the document could be loaded before any request is handled.
+    #[instrument(skip(self), fields(channel = %self.comms.id, path = ?self.comms.path))]
     pub async fn handle_messages(mut self) -> Result<(), Report> {
-        let path = &self.comms.path;
-
-        let doc_state = match File::open(path).await {
-            Ok(mut file) => {
-                let mut buf = String::new();
-                file.read_to_string(&mut buf).await?;
-                let md = from_markdown(&buf)?;
-                DocState::new(md)
-            }
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                let doc = doc::initial_doc();
-                let md = to_markdown(&doc)?;
-                tokio::fs::write(path, md).await?;
-                DocState::new(doc)
-            }
-            Err(e) => return Err(Report::from(e)),
+        let (content, meta) = match self.load().await {
+            Ok(loaded) => {
+                if self.ready_tx.send(Ok(())).is_err() {
+                    warn!("The lobby stopped waiting for the channel");
+                }
+                loaded
+            }
+            Err(report) => {
+                let _ = self.ready_tx.send(Err(report.root_cause().to_string()));
+                return Err(report);
+            }
         };
 
-        let mut c_state = ChannelState::new(doc_state);
+        let mut c_state = ChannelState::new(
+            content,
+            meta,
+            self.max_name_len,
+            self.require_name,
+            self.max_doc_bytes,
+            self.resume_grace,
+            self.chat_history,
+            self.step_coalesce,
+        );
 
-        let mut ter_fut = self.ter_rx;
-        let mut msg_fut = self.msg_rx.next();
+        let mut ter_rx = self.ter_rx;
         loop {
-            match select(ter_fut, msg_fut).await {
-                Either::Left((ter, _msg_fut_continue)) => {
+            let flush_at = c_state.flush_deadline();
+            let flush = delay_until(flush_at.unwrap_or_else(Instant::now).into());
+            tokio::select! {
+                ter = &mut ter_rx => {
                     match ter {
                         Ok(()) => info!("No clients left, terminating"),
                         Err(_) => info!("Server shutdown, terminating"),
                     }
 
-                    let path = &self.comms.path;
-                    let md = to_markdown(&c_state.doc_state.doc)?;
-                    std::fs::write(path, md)?;
+                    if let Persistence::Disk(path, format) = &self.persistence {
+                        let text = c_state.content.write(*format)?;
+                        save_final(self.store.as_ref(), path, &text).await?;
+                        if !c_state.meta.is_empty() {
+                            if let Err(e) = c_state.meta.save(self.store.as_ref(), path).await {
+                                warn!("Could not save the metadata: {:?}", e);
+                            }
+                        }
+                    }
 
                     break Ok(());
                 }
-                Either::Right((req, ter_fut_continue)) => {
+                req = self.msg_rx.next() => {
                     if let Some(request) = req {
                         self.comms.handle_request(&mut c_state, request).await
                     } else {
                         info!("Terminated stream, what is this?");
                     }
-                    ter_fut = ter_fut_continue;
-                    msg_fut = self.msg_rx.next();
+                }
+                _ = flush, if flush_at.is_some() => {
+                    self.comms.flush_steps(&mut c_state);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Folder, Storage};
+    use crate::testing::{insert_text, TestServer};
+
+    fn whisper(sender: UserID, reciever: UserID, text: &str) -> RequestKind {
+        RequestKind::Signal(Signal {
+            sender,
+            reciever,
+            kind: SignalKind::Whisper(text.to_owned()),
+        })
+    }
+
+    /// The receivers of a channel that is driven by the test itself
+    type BareReceivers = (broadcast::Receiver<Broadcast>, mpsc::Receiver<ChannelID>);
+
+    /// A channel without a task, so that the test can look at its state
+    fn bare_channel() -> (ChannelComms, ChannelState, BareReceivers) {
+        let (bct_tx, bct_rx) = broadcast::channel(16);
+        let (end_tx, end_rx) = mpsc::channel(16);
+        let comms = ChannelComms {
+            id: ChannelID::from(1),
+            path: PathBuf::from("bare.md"),
+            doc_path: String::from("/bare"),
+            bct_tx,
+            end_tx,
+            audit: None,
+            webhook: None,
+            history: None,
+        };
+        let content = Content::initial(ContentKind::Document, &initial_doc());
+        let c_state =
+            ChannelState::new(content, DocMeta::default(), 64, false, None, None, 50, None);
+        (comms, c_state, (bct_rx, end_rx))
+    }
+
+    /// Let `id` join a bare channel, returns its signals
+    async fn bare_init(
+        comms: &mut ChannelComms,
+        c_state: &mut ChannelState,
+        id: UserID,
+    ) -> mpsc::Receiver<Signal> {
+        let (sig_tx, sig_rx) = mpsc::channel(10);
+        let (response, _rx) = oneshot::channel();
+        let kind = RequestKind::Init {
+            response,
+            name: None,
+            token: None,
+            identity: None,
+            sig_tx,
+        };
+        comms
+            .handle_request(c_state, Request { source: id, kind })
+            .await;
+        sig_rx
+    }
+
+    #[tokio::test]
+    async fn a_kick_is_forgotten_when_the_client_disconnects() {
+        let (mut comms, mut c_state, _receivers) = bare_channel();
+        let alice = UserID::from(1);
+        let bob = UserID::from(2);
+        let _alice_rx = bare_init(&mut comms, &mut c_state, alice).await;
+        let mut bob_rx = bare_init(&mut comms, &mut c_state, bob).await;
+
+        let kick = Request {
+            source: alice,
+            kind: RequestKind::Kick(bob),
+        };
+        comms.handle_request(&mut c_state, kick).await;
+        assert!(c_state.kicked.contains(&bob));
+        loop {
+            let signal = bob_rx.recv().await.unwrap();
+            if let SignalKind::Kick = signal.kind {
+                break;
+            }
+        }
+
+        // What the connection of Bob sends after it was kicked
+        let close = Request {
+            source: bob,
+            kind: RequestKind::Close(None),
+        };
+        comms.handle_request(&mut c_state, close).await;
+        assert!(c_state.kicked.is_empty());
+        assert!(!c_state.member_data.contains_key(&bob));
+        assert!(c_state.member_data.contains_key(&alice));
+    }
+
+    #[test]
+    fn names_are_trimmed_and_limited() {
+        assert_eq!(sanitize_name("  Alice \n", 10), Some(String::from("Alice")));
+        assert_eq!(sanitize_name("Al\u{7}ice", 10), Some(String::from("Alice")));
+        assert_eq!(sanitize_name("", 10), None);
+        assert_eq!(sanitize_name(" \t ", 10), None);
+        assert_eq!(sanitize_name(&"a".repeat(10), 10), Some("a".repeat(10)));
+        assert_eq!(sanitize_name(&"a".repeat(11), 10), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_names_fall_back_to_the_default() -> Result<(), Report> {
+        let server = TestServer::start();
+        let long_name = "a".repeat(10_000);
+        let mut blank = server.connect("/names", Some("   ")).await?;
+        let long = server.connect("/names", Some(&long_name)).await?;
+        let mut check = server.connect("/names", Some("Check")).await?;
+        assert!(check.init.j_peers.contains(&default_name(blank.id)));
+        assert!(check.init.j_peers.contains(&default_name(long.id)));
+        assert!(!check.init.j_peers.contains(&long_name));
+
+        // The same goes for a rename
+        let update = UserConfig {
+            name: Some(String::from(" ")),
+            audio: None,
+            color: None,
+            avatar: None,
+            status: None,
+        };
+        check.send(RequestKind::Update(update)).await?;
+        let bct = blank
+            .expect_broadcast(|b| matches!(b, Broadcast::Update(..)))
+            .await?;
+        match bct {
+            Broadcast::Update(id, cfg) => {
+                assert_eq!(id, check.id);
+                assert_eq!(cfg.name, Some(default_name(check.id)));
+            }
+            _ => unreachable!(),
+        }
+
+        blank.leave().await?;
+        long.leave().await?;
+        check.leave().await?;
+        server.shutdown().await
+    }
+
+    /// Send `Init` for a client that joined `/named`, returns the reply if there is one
+    async fn init(
+        msg_tx: &mut mpsc::Sender<Request>,
+        sig_tx: mpsc::Sender<Signal>,
+        id: UserID,
+        name: Option<&str>,
+    ) -> Option<SessionReply> {
+        let (tx, rx) = oneshot::channel();
+        let kind = RequestKind::Init {
+            response: tx,
+            name: name.map(str::to_owned),
+            token: None,
+            identity: None,
+            sig_tx,
+        };
+        let req = Request { source: id, kind };
+        assert!(msg_tx.send(req).await.is_ok());
+        rx.await.ok()
+    }
+
+    #[tokio::test]
+    async fn folders_can_require_a_name() -> Result<(), Report> {
+        let folder = Folder::default()
+            .with_storage(Storage::Memory)
+            .with_require_name(true);
+        let server = TestServer::with_folder(folder);
+        let alice = server.connect("/named", Some("Alice")).await?;
+
+        let join = server.lobby().join_channel("/named", None, None).await?;
+        let mut msg_tx = join.msg_tx;
+        let (sig_tx, mut sig_rx) = mpsc::channel(10);
+        for name in [None, Some("  ")].iter() {
+            assert!(init(&mut msg_tx, sig_tx.clone(), join.id, *name)
+                .await
+                .is_none());
+            let reply = sig_rx.recv().await.unwrap();
+            assert!(matches!(
+                reply.kind,
+                SignalKind::Error(ErrorCode::NameRequired, _)
+            ));
+        }
+
+        // The client is not a member until it picks a name
+        let check = server.connect("/named", Some("Check")).await?;
+        let peers: Vec<serde_json::Value> = serde_json::from_str(&check.init.j_peers)?;
+        assert_eq!(peers.len(), 2);
+        let reply = init(&mut msg_tx, sig_tx, join.id, Some("Bob")).await;
+        assert_eq!(reply.map(|reply| reply.id), Some(join.id));
+
+        alice.leave().await?;
+        check.leave().await?;
+        let leave = Request {
+            source: join.id,
+            kind: RequestKind::Leave,
+        };
+        assert!(msg_tx.send(leave).await.is_ok());
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn names_are_optional_by_default() -> Result<(), Report> {
+        let server = TestServer::start();
+        let anonymous = server.connect("/anonymous", None).await?;
+        let check = server.connect("/anonymous", Some("Check")).await?;
+        assert!(check.init.j_peers.contains(&default_name(anonymous.id)));
+
+        anonymous.leave().await?;
+        check.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn documents_can_grow_up_to_the_limit() -> Result<(), Report> {
+        let max = text_bytes(&initial_doc()) + 5;
+        let folder = Folder::default()
+            .with_storage(Storage::Memory)
+            .with_max_doc_bytes(Some(max));
+        let server = TestServer::with_folder(folder);
+        let mut alice = server.connect("/big", Some("Alice")).await?;
+
+        alice.steps(0, insert_text(1, "12345")).await?;
+        alice
+            .expect_broadcast(|b| matches!(b, Broadcast::Steps(_)))
+            .await?;
+
+        alice.steps(1, insert_text(1, "6")).await?;
+        let reply = alice.next_signal().await?;
+        assert!(matches!(
+            reply.kind,
+            SignalKind::Error(ErrorCode::TooLarge, _)
+        ));
+
+        // The rejected steps did not change the document
+        let bob = server.connect("/big", Some("Bob")).await?;
+        assert!(bob.init.doc.contains("12345"));
+        assert!(!bob.init.doc.contains('6'));
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn chat_survives_a_client_that_is_gone() -> Result<(), Report> {
+        let server = TestServer::start();
+        let mut alice = server.connect("/gone", Some("Alice")).await?;
+
+        // The message is only handled after the last receiver of broadcasts is dropped
+        alice.chat("bye").await?;
+        drop(alice);
+
+        // The channel did not panic and still serves new clients
+        let mut bob = server.connect("/gone", Some("Bob")).await?;
+        let mut carol = server.connect("/gone", Some("Carol")).await?;
+        bob.chat("hi").await?;
+        let bct = carol
+            .expect_broadcast(|b| matches!(b, Broadcast::ChatMessage(..)))
+            .await?;
+        assert!(
+            matches!(bct, Broadcast::ChatMessage(src, _, text) if src == bob.id && text == "hi")
+        );
+
+        bob.leave().await?;
+        carol.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn batches_are_applied_completely_or_not_at_all() -> Result<(), Report> {
+        let server = TestServer::start();
+        let mut alice = server.connect("/batch", Some("Alice")).await?;
+
+        // The second step is far past the end of the document
+        let steps = serde_json::json!([
+            {
+                "stepType": "replace",
+                "from": 1,
+                "to": 1,
+                "slice": { "content": [{ "type": "text", "text": "first" }] },
+            },
+            {
+                "stepType": "replace",
+                "from": 10_000,
+                "to": 10_000,
+                "slice": { "content": [{ "type": "text", "text": "second" }] },
+            },
+        ]);
+        alice.steps(0, serde_json::from_value(steps)?).await?;
+        let reply = alice.next_signal().await?;
+        match reply.kind {
+            SignalKind::Error(ErrorCode::StepsRejected, text) => {
+                assert!(text.starts_with("Step 2 of 2"), "{}", text)
+            }
+            kind => panic!("Unexpected signal {:?}", kind),
+        }
+
+        let bob = server.connect("/batch", Some("Bob")).await?;
+        assert!(!bob.init.doc.contains("first"));
+        assert!(!bob.init.doc.contains("second"));
+
+        // The version did not change either
+        alice.steps(0, insert_text(1, "third")).await?;
+        alice
+            .expect_broadcast(|b| matches!(b, Broadcast::Steps(_)))
+            .await?;
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn signals_to_unknown_users_are_reported() -> Result<(), Report> {
+        let server = TestServer::start();
+        let mut alice = server.connect("/signal", Some("Alice")).await?;
+        let mut bob = server.connect("/signal", Some("Bob")).await?;
+
+        let unknown = UserID::from(1000);
+        alice.send(whisper(alice.id, unknown, "hello?")).await?;
+        let reply = alice.next_signal().await?;
+        assert_eq!(reply.reciever, alice.id);
+        assert!(matches!(
+            reply.kind,
+            SignalKind::Error(ErrorCode::NotFound, _)
+        ));
+
+        // The channel is still there for everyone
+        alice.send(whisper(alice.id, bob.id, "hello!")).await?;
+        let signal = bob.next_signal().await?;
+        assert!(matches!(signal.kind, SignalKind::Whisper(text) if text == "hello!"));
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn an_update_before_init_is_ignored() -> Result<(), Report> {
+        let server = TestServer::start();
+        let mut alice = server.connect("/early", Some("Alice")).await?;
+
+        // A client that joined but never sent `Init`
+        let join = server.lobby().join_channel("/early", None, None).await?;
+        let mut msg_tx = join.msg_tx;
+        let update = UserConfig {
+            name: Some(String::from("Mallory")),
+            audio: None,
+            color: None,
+            avatar: None,
+            status: None,
+        };
+        let req = Request {
+            source: join.id,
+            kind: RequestKind::Update(update),
+        };
+        assert!(msg_tx.send(req).await.is_ok());
+
+        // The channel survives and handles the next request
+        let mut bob = server.connect("/early", Some("Bob")).await?;
+        assert!(!bob.init.j_peers.contains("Mallory"));
+        bob.chat("hi").await?;
+        let bct = alice
+            .expect_broadcast(|b| matches!(b, Broadcast::ChatMessage(..) | Broadcast::Update(..)))
+            .await?;
+        assert!(matches!(bct, Broadcast::ChatMessage(src, _, _) if src == bob.id));
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn a_slow_client_does_not_stall_the_channel() -> Result<(), Report> {
+        let server = TestServer::start();
+        // Alice does not take any signals until after the kick
+        let mut alice = server.connect("/slow", Some("Alice")).await?;
+        let mut bob = server.connect("/slow", Some("Bob")).await?;
+        let mut carol = server.connect("/slow", Some("Carol")).await?;
+
+        for i in 0..100 {
+            bob.send(whisper(bob.id, alice.id, &format!("#{}", i)))
+                .await?;
+        }
+        bob.chat("still here").await?;
+        let bct = carol
+            .expect_broadcast(|b| matches!(b, Broadcast::ChatMessage(..)))
+            .await?;
+        assert!(matches!(bct, Broadcast::ChatMessage(src, _, _) if src == bob.id));
+
+        // The kick is delivered even though the buffer of Alice is still full
+        bob.send(RequestKind::Kick(alice.id)).await?;
+        let alice_id = alice.id;
+        carol
+            .expect_broadcast(|b| matches!(b, Broadcast::UserLeft(id) if *id == alice_id))
+            .await?;
+        let mut whispers = 0;
+        loop {
+            let signal = alice.next_signal().await?;
+            match signal.kind {
+                SignalKind::Whisper(_) => whispers += 1,
+                SignalKind::Kick => break,
+                kind => panic!("Unexpected signal {:?}", kind),
+            }
+        }
+        assert!(whispers < 100);
+
+        alice.leave().await?;
+        bob.leave().await?;
+        carol.leave().await?;
+        server.shutdown().await
+    }
+}
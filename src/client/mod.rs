@@ -1,48 +1,223 @@
 //! # Connections to clients
+//!
+//! ## Compression
+//!
+//! Frames are never compressed. `tungstenite` 0.10 can not negotiate `permessage-deflate`
+//! and rejects frames with the RSV1 bit set, so the extension is left out of the handshake
+//! response even if a client offers it. Clients then fall back to uncompressed frames. A
+//! reverse proxy that terminates the WebSocket can still compress large `init` frames.
+mod protocol;
+mod tab;
 
-use crate::channel::{Broadcast, InitReply, Request, RequestKind, Signal, SignalKind, UserConfig};
-use crate::command::{Command, ParseCommandError};
-use crate::lobby::{JoinError, LobbyClient, UserID};
+pub use protocol::ProtocolVersion;
+use tab::Tabs;
+
+use crate::channel::{
+    Broadcast, DocStats, InitReply, MetaUpdate, Request, RequestKind, ResyncReply, Revision,
+    SessionReply, Signal, SignalKind, SnapshotReply, UserConfig,
+};
+use crate::command::{Command, ErrorCode, ParseCommandError};
+use crate::config::{AuthConfig, ClientConfig};
+use crate::lobby::{Identity, JoinError, JoinResponse, LobbyClient, Role, UserID};
+use crate::metrics::METRICS;
+use crate::util::http::query_param;
+use crate::util::{Secret, TokenBucket};
 use crate::ClientStream;
 use color_eyre::Report;
-use eyre::WrapErr;
-use futures_util::future::{select, Either};
-use futures_util::stream::SplitSink;
-use futures_util::{SinkExt, StreamExt};
-use log::*;
+use eyre::{eyre, WrapErr};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{Sink, SinkExt, StreamExt};
 use prosemirror::markdown::MD;
 use prosemirror::transform::Steps;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_tungstenite::accept_hdr_async;
 use tokio_tungstenite::WebSocketStream;
-use tracing::error;
+use tracing::field::{display, Empty};
+use tracing::{debug, error, info, instrument, trace, warn, Span};
 use tungstenite::http::{
-    header::SEC_WEBSOCKET_PROTOCOL,
+    header::{ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, ORIGIN, SEC_WEBSOCKET_PROTOCOL, VARY},
     response::Response as HttpResponse,
     status::StatusCode,
     uri::Uri,
     HeaderValue,
 };
-use tungstenite::{handshake::server, Message, Result as TResult};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tungstenite::{handshake::server, Error as WsError, Message, Result as TResult};
 
 type WsSender = SplitSink<WebSocketStream<ClientStream>, Message>;
+type WsReceiver = SplitStream<WebSocketStream<ClientStream>>;
+
+/// Where frames to the client are written, the WebSocket itself or one of its tabs
+trait FrameSink: Sink<Message, Error = WsError> + Unpin {}
+
+impl<S: Sink<Message, Error = WsError> + Unpin> FrameSink for S {}
+
+/// The maximum length of the emoji of a reaction (in bytes)
+const MAX_REACTION_LEN: usize = 32;
+
+/// The maximum length of the reason a client gives for closing the connection (in bytes)
+const MAX_CLOSE_REASON_LEN: usize = 100;
+
+/// Keeps track of the pings sent to a client and the pongs it answered with
+struct Heartbeat {
+    /// The time the connection was established
+    start_time: Instant,
+    /// The time the last pong was received
+    last_pong: Instant,
+    /// The smoothed round-trip time, if a pong was measured yet
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start_time: now,
+            last_pong: now,
+            rtt: None,
+        }
+    }
+
+    /// The payload of a ping sent at `time`
+    fn ping_payload(&self, time: Instant) -> Vec<u8> {
+        let dur = time.duration_since(self.start_time);
+        let bytes: [u8; 16] = dur.as_micros().to_le_bytes();
+        Vec::from(&bytes[..])
+    }
+
+    /// Record a pong and return the round-trip time if the payload is one of our pings
+    fn pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        let now = Instant::now();
+        self.last_pong = now;
+        let mut bytes = [0u8; 16];
+        if payload.len() != bytes.len() {
+            return None;
+        }
+        bytes.copy_from_slice(payload);
+        let sent = Duration::from_micros(u128::from_le_bytes(bytes) as u64);
+        let rtt = now.duration_since(self.start_time).checked_sub(sent)?;
+        // Like TCP, weigh each new sample with 1/8 to smooth out single slow pongs
+        self.rtt = Some(match self.rtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        Some(rtt)
+    }
+
+    /// Whether the client failed to answer for longer than `timeout`
+    fn is_dead(&self, timeout: Duration) -> bool {
+        self.last_pong.elapsed() > timeout
+    }
+}
+
+/// The result of a successful handshake
+struct Handshake {
+    /// The requested URI
+    uri: Uri,
+    /// The negotiated protocol version
+    version: ProtocolVersion,
+    /// The authenticated user, if authentication is enabled
+    identity: Option<Identity>,
+    /// The password for the folders on the way to the channel, if one was given
+    password: Option<Secret>,
+}
 
-fn make_callback(tx: oneshot::Sender<Uri>) -> impl server::Callback {
+/// The header for the password of a folder
+const PASSWORD_HEADER: &str = "x-padington-password";
+
+/// Get the password of a folder from the `X-Padington-Password` header
+///
+/// Browsers can't set headers for a WebSocket, so they may use the `password` query
+/// parameter instead, which ends up in the logs of proxies and should be avoided.
+pub(crate) fn find_password(http_req: &server::Request) -> Option<Secret> {
+    let header = http_req
+        .headers()
+        .get(PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    header
+        .or_else(|| query_param(http_req.uri(), "password"))
+        .map(Secret::new)
+}
+
+/// Get the token from the `token` query parameter or the `Authorization` header
+pub(crate) fn find_token(http_req: &server::Request) -> Option<String> {
+    query_param(http_req.uri(), "token").or_else(|| {
+        let value = http_req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        value.strip_prefix("Bearer ").map(str::to_owned)
+    })
+}
+
+fn make_callback(
+    tx: oneshot::Sender<Handshake>,
+    auth: Option<Arc<AuthConfig>>,
+    allowed_origins: Vec<String>,
+) -> impl server::Callback {
     move |http_req: &server::Request, mut http_rep: server::Response| {
         let headers = http_req.headers();
+        let allow_origin = if allowed_origins.is_empty() {
+            HeaderValue::from_static("*")
+        } else {
+            match headers.get(ORIGIN) {
+                Some(origin)
+                    if allowed_origins
+                        .iter()
+                        .any(|o| o.as_bytes() == origin.as_bytes()) =>
+                {
+                    origin.clone()
+                }
+                origin => {
+                    warn!("Rejected connection from origin {:?}", origin);
+                    let msg = "Origin not allowed".to_string();
+                    let mut rep = HttpResponse::new(Some(msg));
+                    *rep.status_mut() = StatusCode::FORBIDDEN;
+                    return Err(rep);
+                }
+            }
+        };
         if let Some(value) = headers.get(SEC_WEBSOCKET_PROTOCOL) {
-            if value == "padington" {
+            if let Some(version) = ProtocolVersion::negotiate(headers) {
+                let identity = match &auth {
+                    Some(auth) => match find_token(http_req).and_then(|t| auth.authenticate(&t)) {
+                        Some(identity) => Some(identity),
+                        None => {
+                            warn!("Rejected connection without a valid token");
+                            let msg = "Invalid or missing token".to_string();
+                            let mut rep = HttpResponse::new(Some(msg));
+                            *rep.status_mut() = StatusCode::UNAUTHORIZED;
+                            return Err(rep);
+                        }
+                    },
+                    None => None,
+                };
                 let headers = http_rep.headers_mut();
-                headers.append(SEC_WEBSOCKET_PROTOCOL, value.clone());
                 headers.append(
-                    tungstenite::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-                    HeaderValue::from_static("*"),
+                    SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(version.name()),
                 );
-                match tx.send(http_req.uri().clone()) {
+                if !allowed_origins.is_empty() {
+                    headers.append(VARY, HeaderValue::from_static("Origin"));
+                }
+                headers.append(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+                let handshake = Handshake {
+                    uri: http_req.uri().clone(),
+                    version,
+                    identity,
+                    password: find_password(http_req),
+                };
+                match tx.send(handshake) {
                     Ok(_) => Ok(http_rep),
-                    Err(e) => todo!("{}", e),
+                    Err(_) => {
+                        error!("Connection dropped during handshake");
+                        let msg = "Connection dropped during handshake".to_string();
+                        let mut rep = HttpResponse::new(Some(msg));
+                        *rep.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        Err(rep)
+                    }
                 }
             } else {
                 let msg = format!("Invalid protocol {:?}", value);
@@ -61,57 +236,341 @@ fn make_callback(tx: oneshot::Sender<Uri>) -> impl server::Callback {
     }
 }
 
+/// Limits how fast a client may send commands to its channel
+struct RateLimiter {
+    /// The tokens for incoming messages
+    bucket: TokenBucket,
+    /// The number of rate limited messages in a row
+    violations: u32,
+    /// The number of violations until the client is disconnected
+    max_violations: Option<u32>,
+}
+
+impl RateLimiter {
+    fn new(cfg: &ClientConfig) -> Self {
+        Self {
+            bucket: TokenBucket::new(cfg.rate_limit, cfg.rate_burst),
+            violations: 0,
+            max_violations: cfg.max_rate_violations,
+        }
+    }
+
+    /// Check whether the next message is allowed
+    fn allow(&mut self) -> bool {
+        if self.bucket.try_take() {
+            self.violations = 0;
+            true
+        } else {
+            self.violations += 1;
+            false
+        }
+    }
+
+    /// Whether the client exceeded the limit too often
+    fn is_abusive(&self) -> bool {
+        self.max_violations
+            .map_or(false, |max| self.violations > max)
+    }
+}
+
+/// The state of a single connection
+struct ConnState {
+    /// The negotiated protocol version
+    version: ProtocolVersion,
+    /// The authenticated user, if authentication is enabled
+    identity: Option<Identity>,
+    /// The options for this connection
+    cfg: ClientConfig,
+    /// The pings and pongs of this connection
+    heartbeat: Heartbeat,
+    /// The rate limit for this connection
+    limiter: RateLimiter,
+    /// The time the client last sent a message that was not a pong
+    last_activity: Instant,
+}
+
+impl ConnState {
+    /// Whether the client did not send anything for longer than the idle timeout
+    ///
+    /// Pongs are answers to our own pings, so they are tracked by the heartbeat instead.
+    fn is_idle(&self) -> bool {
+        self.cfg
+            .idle_timeout()
+            .map_or(false, |timeout| self.last_activity.elapsed() > timeout)
+    }
+
+    /// Whether the client may moderate channels
+    fn is_admin(&self) -> bool {
+        self.identity
+            .as_ref()
+            .map_or(false, |identity| identity.role == Role::Admin)
+    }
+
+    fn new(handshake: Handshake, cfg: ClientConfig) -> Self {
+        let limiter = RateLimiter::new(&cfg);
+        Self {
+            version: handshake.version,
+            identity: handshake.identity,
+            cfg,
+            heartbeat: Heartbeat::new(),
+            limiter,
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// The state for a tab of this connection, with a rate limit of its own
+    fn for_tab(&self) -> Self {
+        Self {
+            version: self.version,
+            identity: self.identity.clone(),
+            cfg: self.cfg.clone(),
+            heartbeat: Heartbeat::new(),
+            limiter: RateLimiter::new(&self.cfg),
+            last_activity: Instant::now(),
+        }
+    }
+}
+
+/// Why the server closes a connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CloseReason {
+    /// The client asked to close the connection
+    Normal,
+    /// The client sent a message that could not be understood
+    Protocol,
+    /// The client sent a binary message
+    Binary,
+    /// The client exceeded the rate limit too often
+    RateLimited,
+    /// The channel that the client wanted to join is full
+    ChannelFull,
+    /// The path that the client wanted to join does not exist
+    InvalidPath,
+    /// The client may not access the path that it wanted to join
+    Forbidden,
+    /// The client gave no or the wrong password for the path that it wanted to join
+    BadPassword,
+    /// The client was kicked by an admin
+    Kicked,
+    /// The client stopped answering pings
+    Timeout,
+    /// The client did not send anything for too long
+    Idle,
+    /// The channel or the whole server shut down
+    Shutdown,
+    /// Something went wrong on the server
+    Error,
+}
+
+impl CloseReason {
+    /// The close frame that is sent to the client
+    fn frame(self) -> CloseFrame<'static> {
+        let (code, reason) = match self {
+            Self::Normal => (CloseCode::Normal, "closed"),
+            Self::Protocol => (CloseCode::Protocol, "invalid message"),
+            Self::Binary => (CloseCode::Unsupported, "binary messages are not supported"),
+            Self::RateLimited => (CloseCode::Policy, "rate limited"),
+            Self::ChannelFull => (CloseCode::Again, "channel full"),
+            Self::InvalidPath => (CloseCode::Policy, "invalid path"),
+            Self::Forbidden => (CloseCode::Policy, "forbidden"),
+            Self::BadPassword => (CloseCode::Policy, "wrong or missing password"),
+            Self::Kicked => (CloseCode::Policy, "kicked"),
+            Self::Timeout => (CloseCode::Away, "ping timeout"),
+            Self::Idle => (CloseCode::Away, "idle"),
+            Self::Shutdown => (CloseCode::Restart, "server restarting"),
+            Self::Error => (CloseCode::Error, "internal error"),
+        };
+        CloseFrame {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Send a close frame to the client
+async fn send_close(ws_sender: &mut WsSender, reason: CloseReason) {
+    debug!("Closing connection ({:?})", reason);
+    if let Err(err) = ws_sender.send(Message::Close(Some(reason.frame()))).await {
+        warn!("Could not send close frame: {}", err);
+    }
+}
+
 enum CommandRes {
+    /// The connection is gone, there is nothing left to send
     Break,
     Continue,
+    /// Close the connection with a close frame
+    Close(CloseReason),
+    /// Leave the channel and join the one at the given path
+    Leave(String),
+    /// Send the current document and follow the broadcasts from there
+    Snapshot,
+    /// The client resumed a previous session and continues with its ID
+    Resumed(UserID),
+    /// Join the channel at the given path in another tab
+    Open(u32, String),
+    /// Pass a command to the channel in another tab
+    Tab(u32, String),
 }
 
 async fn handle_command(
     id: UserID,
+    conn: &mut ConnState,
     sig_tx: &mut mpsc::Sender<Signal>,
     msg_tx: &mut mpsc::Sender<Request>,
-    ws_sender: &mut WsSender,
-    cmd_res: Result<Command, ParseCommandError>,
+    ws_sender: &mut impl FrameSink,
+    cmd_res: Result<Command<'_>, ParseCommandError>,
 ) -> TResult<CommandRes> {
+    // Every command that ends up in the queue of the channel counts against the limit, except
+    // for leaving, which has to work for a client that is over it. Commands for another tab
+    // are counted by that tab, and `open` and `create` only go to the lobby.
+    let is_limited = match &cmd_res {
+        Ok(Command::Close(_)) | Ok(Command::Leave(_)) => false,
+        Ok(Command::Open(..)) | Ok(Command::Tab(..)) | Ok(Command::Create(_)) => false,
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    if is_limited && !conn.limiter.allow() {
+        if conn.limiter.is_abusive() {
+            warn!(
+                "Closing connection to {} after exceeding the rate limit",
+                id
+            );
+            submit_close(id, msg_tx).await;
+            return Ok(CommandRes::Close(CloseReason::RateLimited));
+        }
+        let msg = conn
+            .version
+            .error(ErrorCode::RateLimited, "Too many commands, slow down");
+        ws_sender.send(msg).await?;
+        return Ok(CommandRes::Continue);
+    }
     match cmd_res {
-        Ok(Command::Init(name)) => {
-            let (tx, rx) = oneshot::channel::<InitReply>();
+        Ok(Command::Init(name, token)) => {
+            let (tx, rx) = oneshot::channel::<SessionReply>();
             let req = Request {
                 source: id,
                 kind: RequestKind::Init {
                     response: tx,
-                    name,
+                    name: name.map(str::to_owned),
+                    token: token.map(str::to_owned),
+                    identity: conn.identity.clone(),
                     sig_tx: sig_tx.clone(),
                 },
             };
             if let Err(e) = msg_tx.send(req).await {
                 error!("{:?}", e);
-                return Ok(CommandRes::Break);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
             }
             match rx.await {
-                Ok(state) => {
-                    let msg = format!("init|{}|{}", id.int_val(), state.doc);
-                    ws_sender.send(Message::text(msg)).await?;
-                    let msg = format!("peers|{}", state.j_peers);
-                    ws_sender.send(Message::text(msg)).await?;
+                Ok(reply) => {
+                    send_init(reply.id, conn.version, reply.state, ws_sender).await?;
+                    if let Some(token) = reply.token {
+                        let msg = format!("resume|{}", token);
+                        ws_sender.send(Message::text(msg)).await?;
+                    }
+                    if reply.id != id {
+                        return Ok(CommandRes::Resumed(reply.id));
+                    }
                 }
                 Err(err) => {
                     error!("{}", err);
                 }
             }
         }
+        Ok(Command::Chat(msg)) if msg.len() > conn.cfg.max_chat_len => {
+            let max_len = conn.cfg.max_chat_len;
+            let text = format!("Chat message exceeds {} bytes", max_len);
+            ws_sender
+                .send(conn.version.error(ErrorCode::TooLarge, &text))
+                .await?;
+        }
+        Ok(Command::Whisper(_, msg)) if msg.len() > conn.cfg.max_chat_len => {
+            let max_len = conn.cfg.max_chat_len;
+            let text = format!("Whisper exceeds {} bytes", max_len);
+            ws_sender
+                .send(conn.version.error(ErrorCode::TooLarge, &text))
+                .await?;
+        }
+        Ok(Command::Steps(_, payload))
+        | Ok(Command::Update(payload))
+        | Ok(Command::Meta(payload))
+        | Ok(Command::WebRTC(_, payload))
+            if payload.len() > conn.cfg.max_payload_len =>
+        {
+            // Checked before parsing, so that large payloads are never deserialized
+            let text = format!("Payload exceeds {} bytes", conn.cfg.max_payload_len);
+            ws_sender
+                .send(conn.version.error(ErrorCode::TooLarge, &text))
+                .await?;
+        }
         Ok(Command::Chat(msg)) => {
             let req = Request {
                 source: id,
-                kind: RequestKind::Chat(msg),
+                kind: RequestKind::Chat(msg.to_owned()),
             };
             if let Err(e) = msg_tx.send(req).await {
                 error!("{:?}", e);
-                return Ok(CommandRes::Break);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
+        Ok(Command::React(_, emoji))
+            if emoji.is_empty()
+                || emoji.len() > MAX_REACTION_LEN
+                || emoji.contains(char::is_control) =>
+        {
+            let msg = conn
+                .version
+                .error(ErrorCode::InvalidArgument, "Invalid reaction");
+            ws_sender.send(msg).await?;
+        }
+        Ok(Command::React(message, emoji)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::React {
+                    message,
+                    emoji: emoji.to_owned(),
+                },
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
+        Ok(Command::Comment(from, to, text))
+            if from > to || text.trim().is_empty() || text.len() > conn.cfg.max_chat_len =>
+        {
+            let msg = conn
+                .version
+                .error(ErrorCode::InvalidArgument, "Invalid comment");
+            ws_sender.send(msg).await?;
+        }
+        Ok(Command::Comment(from, to, text)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::Comment {
+                    from,
+                    to,
+                    text: text.to_owned(),
+                },
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
+        Ok(Command::Resolve(comment)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::Resolve(comment),
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
             }
         }
         Ok(Command::Update(payload)) => {
-            let update: Result<UserConfig, _> = serde_json::from_str(&payload);
+            let update: Result<UserConfig, _> = serde_json::from_str(payload);
             match update {
                 Ok(cfg) => {
                     debug!("Recieved Update {:?} from {:?}", cfg, id);
@@ -121,17 +580,41 @@ async fn handle_command(
                     };
                     if let Err(e) = msg_tx.send(req).await {
                         error!("{:?}", e);
-                        return Ok(CommandRes::Break);
+                        return Ok(CommandRes::Close(CloseReason::Shutdown));
                     }
                 }
                 Err(e) => {
                     error!("{:?}", e);
-                    return Ok(CommandRes::Break);
+                    let text = format!("Malformed JSON: {}", e);
+                    let msg = conn.version.error(ErrorCode::InvalidArgument, &text);
+                    ws_sender.send(msg).await?;
+                    submit_close(id, msg_tx).await;
+                    return Ok(CommandRes::Close(CloseReason::Protocol));
                 }
             }
         }
+        Ok(Command::Meta(payload)) => match serde_json::from_str::<MetaUpdate>(payload) {
+            Ok(update) => {
+                let req = Request {
+                    source: id,
+                    kind: RequestKind::Meta(update),
+                };
+                if let Err(e) = msg_tx.send(req).await {
+                    error!("{:?}", e);
+                    return Ok(CommandRes::Close(CloseReason::Shutdown));
+                }
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                let text = format!("Malformed JSON: {}", e);
+                let msg = conn.version.error(ErrorCode::InvalidArgument, &text);
+                ws_sender.send(msg).await?;
+                submit_close(id, msg_tx).await;
+                return Ok(CommandRes::Close(CloseReason::Protocol));
+            }
+        },
         Ok(Command::WebRTC(reciever, payload)) => {
-            let value: Result<serde_json::Value, _> = serde_json::from_str(&payload);
+            let value: Result<serde_json::Value, _> = serde_json::from_str(payload);
             match value {
                 Ok(value) => {
                     let req = Request {
@@ -144,20 +627,47 @@ async fn handle_command(
                     };
                     if let Err(e) = msg_tx.send(req).await {
                         error!("{:?}", e);
-                        return Ok(CommandRes::Break);
+                        return Ok(CommandRes::Close(CloseReason::Shutdown));
                     }
                 }
                 Err(e) => {
                     error!("{:?}", e);
-                    return Ok(CommandRes::Break);
+                    let text = format!("Malformed JSON: {}", e);
+                    let msg = conn.version.error(ErrorCode::InvalidArgument, &text);
+                    ws_sender.send(msg).await?;
+                    submit_close(id, msg_tx).await;
+                    return Ok(CommandRes::Close(CloseReason::Protocol));
                 }
             }
         }
+        Ok(Command::Whisper(reciever, text)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::Signal(Signal {
+                    sender: id,
+                    reciever: UserID::from(reciever),
+                    kind: SignalKind::Whisper(text.to_owned()),
+                }),
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
         Ok(Command::Steps(version, string)) => {
             debug!("Step Text: {:?}", string);
-            let steps_res: Result<Steps<MD>, _> = serde_json::from_str(&string);
+            let steps_res: Result<Steps<MD>, _> = serde_json::from_str(string);
 
             match steps_res {
+                Ok(steps) if steps.len() > conn.cfg.max_steps => {
+                    // Applying the steps blocks the channel, so the batch is never sent there
+                    info!("Rejected {} steps from {}", steps.len(), id);
+                    METRICS.steps_rejected.inc();
+                    let text = format!("More than {} steps at once", conn.cfg.max_steps);
+                    ws_sender
+                        .send(conn.version.error(ErrorCode::TooLarge, &text))
+                        .await?;
+                }
                 Ok(steps) => {
                     let req = Request {
                         source: id,
@@ -165,59 +675,283 @@ async fn handle_command(
                     };
                     if let Err(e) = msg_tx.send(req).await {
                         error!("{:?}", e);
-                        return Ok(CommandRes::Break);
+                        return Ok(CommandRes::Close(CloseReason::Shutdown));
                     }
                 }
                 Err(e) => {
                     error!("{:?}", e);
-                    return Ok(CommandRes::Break);
+                    let text = format!("Malformed JSON: {}", e);
+                    let msg = conn.version.error(ErrorCode::InvalidArgument, &text);
+                    ws_sender.send(msg).await?;
+                    submit_close(id, msg_tx).await;
+                    return Ok(CommandRes::Close(CloseReason::Protocol));
                 }
             }
         }
-        Ok(Command::Close) => {
+        Ok(Command::Typing(typing)) => {
             let req = Request {
                 source: id,
-                kind: RequestKind::Close,
+                kind: RequestKind::Typing(typing),
             };
             if let Err(e) = msg_tx.send(req).await {
                 error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
+        Ok(Command::Cursor(from, to)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::Cursor { from, to },
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
+        Ok(Command::Create(_)) => {
+            let msg = conn.version.error(
+                ErrorCode::Unavailable,
+                "Documents can only be created in a folder",
+            );
+            ws_sender.send(msg).await?;
+        }
+        Ok(Command::Stats) => {
+            let (tx, rx) = oneshot::channel::<DocStats>();
+            let req = Request {
+                source: id,
+                kind: RequestKind::Stats { response: tx },
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+            match rx.await {
+                Ok(stats) => {
+                    let msg = format!("stats|{}", serde_json::to_string(&stats).unwrap());
+                    ws_sender.send(Message::text(msg)).await?;
+                }
+                Err(err) => {
+                    error!("{}", err);
+                }
+            }
+        }
+        Ok(Command::Snapshot) => return Ok(CommandRes::Snapshot),
+        Ok(Command::Revisions) => {
+            let (tx, rx) = oneshot::channel::<Vec<Revision>>();
+            let req = Request {
+                source: id,
+                kind: RequestKind::Revisions { response: tx },
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+            match rx.await {
+                Ok(revisions) => {
+                    let msg = format!("revisions|{}", serde_json::to_string(&revisions).unwrap());
+                    ws_sender.send(Message::text(msg)).await?;
+                }
+                Err(err) => {
+                    error!("{}", err);
+                }
             }
-            return Ok(CommandRes::Break);
+        }
+        Ok(Command::Revision(version)) => {
+            let (tx, rx) = oneshot::channel::<Option<String>>();
+            let req = Request {
+                source: id,
+                kind: RequestKind::Revision {
+                    version,
+                    response: tx,
+                },
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+            match rx.await {
+                Ok(Some(md)) => {
+                    let msg = format!("revision|{}|{}", version, md);
+                    ws_sender.send(Message::text(msg)).await?;
+                }
+                Ok(None) => {
+                    let text = format!("No revision for version {}", version);
+                    ws_sender
+                        .send(conn.version.error(ErrorCode::NotFound, &text))
+                        .await?;
+                }
+                Err(err) => {
+                    error!("{}", err);
+                }
+            }
+        }
+        Ok(Command::Kick(_)) if !conn.is_admin() => {
+            let msg = conn
+                .version
+                .error(ErrorCode::Forbidden, "Only admins can kick users");
+            ws_sender.send(msg).await?;
+        }
+        Ok(Command::Kick(target)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::Kick(UserID::from(target)),
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+        }
+        Ok(Command::Leave(path)) => {
+            let req = Request {
+                source: id,
+                kind: RequestKind::Leave,
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+                return Ok(CommandRes::Close(CloseReason::Shutdown));
+            }
+            return Ok(CommandRes::Leave(path.to_owned()));
+        }
+        Ok(Command::Open(tab, path)) => return Ok(CommandRes::Open(tab, path.to_owned())),
+        Ok(Command::Tab(tab, cmd)) => return Ok(CommandRes::Tab(tab, cmd.to_owned())),
+        Ok(Command::Close(reason)) => {
+            let reason = reason.filter(|reason| {
+                reason.len() <= MAX_CLOSE_REASON_LEN && !reason.contains(char::is_control)
+            });
+            let req = Request {
+                source: id,
+                kind: RequestKind::Close(reason.map(str::to_owned)),
+            };
+            if let Err(e) = msg_tx.send(req).await {
+                error!("{:?}", e);
+            }
+            return Ok(CommandRes::Close(CloseReason::Normal));
         }
         Err(err) => {
-            ws_sender
-                .send(Message::text(format!("error|{}", err)))
-                .await?;
+            let msg = conn.version.error(err.code(), &err.to_string());
+            ws_sender.send(msg).await?;
         }
     }
     Ok(CommandRes::Continue)
 }
 
+async fn send_init(
+    id: UserID,
+    version: ProtocolVersion,
+    state: InitReply,
+    ws_sender: &mut impl FrameSink,
+) -> TResult<()> {
+    let msg = format!("init|{}|{}", id.int_val(), state.doc);
+    ws_sender.send(Message::text(msg)).await?;
+    let msg = if version < ProtocolVersion::V3 {
+        format!("peers|{}", state.j_peer_map)
+    } else {
+        format!("peers|{}", state.j_peers)
+    };
+    ws_sender.send(Message::text(msg)).await?;
+    let msg = format!("chat-history|{}", state.j_chat);
+    ws_sender.send(Message::text(msg)).await?;
+    let msg = format!("reactions|{}", state.j_reactions);
+    ws_sender.send(Message::text(msg)).await?;
+    let msg = format!("meta|{}", state.j_meta);
+    ws_sender.send(Message::text(msg)).await?;
+    Ok(())
+}
+
+/// Request a fresh state after missing broadcasts, returns the new broadcast receiver
+async fn resync(
+    id: UserID,
+    version: ProtocolVersion,
+    msg_tx: &mut mpsc::Sender<Request>,
+    ws_sender: &mut impl FrameSink,
+) -> Result<broadcast::Receiver<Broadcast>, Report> {
+    let (tx, rx) = oneshot::channel::<ResyncReply>();
+    let req = Request {
+        source: id,
+        kind: RequestKind::Resync { response: tx },
+    };
+    msg_tx
+        .send(req)
+        .await
+        .map_err(|e| eyre!("Failed to send resync request: {}", e))?;
+    let reply = rx.await.wrap_err("Channel dropped resync request")?;
+    send_init(id, version, reply.state, ws_sender).await?;
+    Ok(reply.bct_rx)
+}
+
+/// Send the current document to the client, returns the receiver for the broadcasts after it
+async fn snapshot(
+    id: UserID,
+    msg_tx: &mut mpsc::Sender<Request>,
+    ws_sender: &mut impl FrameSink,
+) -> Result<broadcast::Receiver<Broadcast>, Report> {
+    let (tx, rx) = oneshot::channel::<SnapshotReply>();
+    let req = Request {
+        source: id,
+        kind: RequestKind::Snapshot { response: tx },
+    };
+    msg_tx
+        .send(req)
+        .await
+        .map_err(|e| eyre!("Failed to send snapshot request: {}", e))?;
+    let reply = rx.await.wrap_err("Channel dropped snapshot request")?;
+    let msg = format!("snapshot|{}", reply.doc);
+    ws_sender.send(Message::text(msg)).await?;
+    Ok(reply.bct_rx)
+}
+
 async fn submit_close(id: UserID, msg_tx: &mut mpsc::Sender<Request>) {
     let close_req = Request {
         source: id,
-        kind: RequestKind::Close,
+        kind: RequestKind::Close(None),
     };
     match msg_tx.send(close_req).await {
         Ok(()) => {
-            debug!("Sent {:?}", Command::Close);
+            debug!("Sent close request for {}", id);
         }
         Err(e) => {
-            error!("Failed to send {:?} ({:?})", Command::Close, e);
+            error!("Failed to send close request for {} ({:?})", id, e);
         }
     }
 }
 
-async fn handle_broadcast(msg: Broadcast, ws_sender: &mut WsSender) -> TResult<()> {
+async fn handle_broadcast(
+    own_id: UserID,
+    version: ProtocolVersion,
+    msg: Broadcast,
+    ws_sender: &mut impl FrameSink,
+) -> TResult<()> {
     match msg {
-        Broadcast::ChatMessage(id, text) => {
+        Broadcast::ChatMessage(id, _message, text) if version < ProtocolVersion::V2 => {
             let msg = format!("chat|{}|{}", id.int_val(), text);
             ws_sender.send(Message::text(msg)).await?;
         }
-        Broadcast::NewUser { remote_id, data } => {
+        Broadcast::ChatMessage(id, message, text) => {
+            let msg = format!("chat|{}|{}|{}", id.int_val(), message, text);
+            ws_sender.send(Message::text(msg)).await?;
+        }
+        Broadcast::Reaction {
+            message,
+            user,
+            emoji,
+            active,
+        } => {
+            let kind = if active {
+                "reaction"
+            } else {
+                "reaction-removed"
+            };
+            let msg = format!("{}|{}|{}|{}", kind, message, user.int_val(), emoji);
+            ws_sender.send(Message::text(msg)).await?;
+        }
+        Broadcast::NewUser { remote_id, data } if version < ProtocolVersion::V3 => {
             let msg = format!("new-user|{}|{}", remote_id.int_val(), data);
             ws_sender.send(Message::text(msg)).await?;
         }
+        Broadcast::NewUser { data, .. } => {
+            let msg = format!("new-user|{}", data);
+            ws_sender.send(Message::text(msg)).await?;
+        }
         Broadcast::Update(id, cfg) => {
             debug!("Sending update {:?} for {:?}", cfg, id);
             let msg = format!(
@@ -235,12 +969,48 @@ async fn handle_broadcast(msg: Broadcast, ws_sender: &mut WsSender) -> TResult<(
             let msg = format!("steps|{}", steps);
             ws_sender.send(Message::text(msg)).await?;
         }
+        Broadcast::Cursor(id, from, to) => {
+            if id != own_id {
+                let msg = format!("cursor|{}|{}|{}", id.int_val(), from, to);
+                ws_sender.send(Message::text(msg)).await?;
+            }
+        }
+        Broadcast::Typing(id, typing) => {
+            if id != own_id {
+                let msg = format!("typing|{}|{}", id.int_val(), typing);
+                ws_sender.send(Message::text(msg)).await?;
+            }
+        }
+        Broadcast::System(text) => {
+            let msg = format!("system|{}", text);
+            ws_sender.send(Message::text(msg)).await?;
+        }
+        Broadcast::Meta(json) => {
+            let msg = format!("meta|{}", json);
+            ws_sender.send(Message::text(msg)).await?;
+        }
+        Broadcast::Comment(json) => {
+            let msg = format!("comment|{}", json);
+            ws_sender.send(Message::text(msg)).await?;
+        }
+        Broadcast::Resolved(comment) => {
+            let msg = format!("resolved|{}", comment);
+            ws_sender.send(Message::text(msg)).await?;
+        }
     }
     Ok(())
 }
 
-async fn handle_signal(signal: Signal, ws_sender: &mut WsSender) -> TResult<()> {
+async fn handle_signal(
+    signal: Signal,
+    version: ProtocolVersion,
+    ws_sender: &mut impl FrameSink,
+) -> TResult<()> {
     match signal.kind {
+        SignalKind::Whisper(text) => {
+            let msg = format!("whisper|{}|{}", signal.sender.int_val(), text);
+            ws_sender.send(Message::text(msg)).await?;
+        }
         SignalKind::WebRTC(payload) => {
             let msg = format!(
                 "webrtc|{}|{}",
@@ -249,24 +1019,134 @@ async fn handle_signal(signal: Signal, ws_sender: &mut WsSender) -> TResult<()>
             );
             ws_sender.send(Message::text(msg)).await?;
         }
+        SignalKind::Error(code, text) => {
+            ws_sender.send(version.error(code, &text)).await?;
+        }
+        SignalKind::Kick => {}
     }
     Ok(())
 }
 
+/// Serve a client that opened a folder until it closes the connection
+///
+/// Returns the path to join next if the client left the folder. Every command counts
+/// towards the rate limit here, since most of them end up in the lobby.
+async fn handle_folder(
+    lc: &mut LobbyClient,
+    path: &str,
+    conn: &mut ConnState,
+    tabs: &mut Tabs,
+    ws_sender: &mut WsSender,
+    ws_receiver: &mut WsReceiver,
+) -> Result<Option<String>, Report> {
+    let mut interval = tokio::time::interval(conn.cfg.ping_interval());
+    loop {
+        tokio::select! {
+            msg = ws_receiver.next() => {
+                let msg = match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                };
+                if !matches!(msg, Message::Pong(_)) {
+                    conn.last_activity = Instant::now();
+                }
+                match msg {
+                    Message::Text(_) if !conn.limiter.allow() => {
+                        if conn.limiter.is_abusive() {
+                            warn!("Closing connection in {:?} after exceeding the rate limit", path);
+                            send_close(ws_sender, CloseReason::RateLimited).await;
+                            break;
+                        }
+                        let msg = conn
+                            .version
+                            .error(ErrorCode::RateLimited, "Too many commands, slow down");
+                        ws_sender.send(msg).await?;
+                    }
+                    Message::Text(t) => {
+                        let msg = match Command::parse(&t) {
+                            Ok(Command::Leave(next_path)) => return Ok(Some(next_path.to_owned())),
+                            Ok(Command::Open(tab, tab_path)) => {
+                                let tab_path = tab_path.to_owned();
+                                tabs.open(tab, tab_path, conn, ws_sender).await?;
+                                continue;
+                            }
+                            Ok(Command::Tab(tab, cmd)) => {
+                                tabs.forward(tab, cmd.to_owned(), conn, ws_sender).await?;
+                                continue;
+                            }
+                            Ok(Command::Create(name)) => {
+                                let identity = conn.identity.clone();
+                                match lc.create_document(path, name, identity).await {
+                                    Ok(new_path) => Message::text(format!("created|{}", new_path)),
+                                    Err(e) => conn.version.error(e.code(), &e.to_string()),
+                                }
+                            }
+                            Ok(_) => conn
+                                .version
+                                .error(ErrorCode::Unavailable, "Not in a document"),
+                            Err(err) => conn.version.error(err.code(), &err.to_string()),
+                        };
+                        ws_sender.send(msg).await?;
+                    }
+                    Message::Ping(p) => {
+                        ws_sender.send(Message::Pong(p)).await?;
+                    }
+                    Message::Close(c) => {
+                        debug!("WebSocket closed ({:?})", c);
+                        break;
+                    }
+                    Message::Binary(_) => {
+                        send_close(ws_sender, CloseReason::Binary).await;
+                        break;
+                    }
+                    Message::Pong(p) => {
+                        if let Some(rtt) = conn.heartbeat.pong(&p) {
+                            METRICS.round_trip.observe(rtt);
+                        }
+                    }
+                }
+            }
+            time = interval.tick() => {
+                if conn.heartbeat.is_dead(conn.cfg.pong_timeout()) {
+                    info!("Connection in {:?} timed out", path);
+                    send_close(ws_sender, CloseReason::Timeout).await;
+                    break;
+                }
+                if conn.is_idle() {
+                    info!("Connection in {:?} was idle for too long", path);
+                    send_close(ws_sender, CloseReason::Idle).await;
+                    break;
+                }
+                let vec = conn.heartbeat.ping_payload(time.into_std());
+                ws_sender.send(Message::Ping(vec)).await?;
+            }
+            event = tabs.next_event() => {
+                if let Some(event) = event {
+                    tabs.handle_event(event, ws_sender).await?;
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
 async fn handle_message(
     id: UserID,
     msg: Message,
+    conn: &mut ConnState,
     sig_tx: &mut mpsc::Sender<Signal>,
     msg_tx: &mut mpsc::Sender<Request>,
-    ws_sender: &mut WsSender,
+    ws_sender: &mut impl FrameSink,
 ) -> Result<CommandRes, Report> {
     match msg {
         Message::Text(t) => {
-            let cmd_res = t.parse();
-            handle_command(id, sig_tx, msg_tx, ws_sender, cmd_res).await?;
+            let cmd_res = Command::parse(&t);
+            return Ok(handle_command(id, conn, sig_tx, msg_tx, ws_sender, cmd_res).await?);
         }
-        Message::Binary(b) => {
-            ws_sender.send(Message::binary(b)).await?;
+        Message::Binary(_) => {
+            info!("Closing connection to {} after a binary message", id);
+            submit_close(id, msg_tx).await;
+            return Ok(CommandRes::Close(CloseReason::Binary));
         }
         Message::Close(c) => {
             debug!("WebSocket closed ({:?})", c);
@@ -280,155 +1160,345 @@ async fn handle_message(
                 return Ok(CommandRes::Break);
             }
         }
-        Message::Pong(_) => {}
+        Message::Pong(p) => {
+            if let Some(rtt) = conn.heartbeat.pong(&p) {
+                trace!(
+                    "Round-trip time to {} is {:?} (smoothed {:?})",
+                    id,
+                    rtt,
+                    conn.heartbeat.rtt
+                );
+                METRICS.round_trip.observe(rtt);
+                if conn.cfg.report_latency {
+                    let msg = format!("latency|{}", rtt.as_micros());
+                    ws_sender.send(Message::text(msg)).await?;
+                }
+            }
+        }
     }
     Ok(CommandRes::Continue)
 }
 
-/// Handle an incoming connection
-pub async fn handle_connection(
-    mut lc: LobbyClient,
-    peer: SocketAddr,
-    stream: ClientStream,
-) -> Result<(), Report> {
-    let (tx, rx) = oneshot::channel::<Uri>();
-    let ws_stream: WebSocketStream<ClientStream> =
-        accept_hdr_async(stream, make_callback(tx)).await?;
-    let uri: Uri = rx.await.wrap_err("Callback dropped")?;
-    let start_time = Instant::now();
-
-    info!("New WebSocket connection: {} to {}", peer, uri);
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-
-    let channel_path = urlencoding::decode(uri.path())?;
-    let join_response = match lc.join_channel(channel_path).await {
-        Ok(jr) => jr,
-        Err(JoinError::IsFolder(c)) => {
-            let msg = format!("folder|{}", c);
-            ws_sender.send(Message::text(msg)).await?;
-            ws_sender.send(Message::Close(None)).await?;
-            return Ok(());
-        }
-        Err(e) => return Err(e.into()),
-    };
+/// Serve a client in a channel until it leaves or closes the connection
+///
+/// Returns the path to join next if the client left the channel.
+async fn serve_channel(
+    join_response: JoinResponse,
+    conn: &mut ConnState,
+    tabs: &mut Tabs,
+    ws_sender: &mut WsSender,
+    ws_receiver: &mut WsReceiver,
+) -> Option<String> {
     let mut msg_tx = join_response.msg_tx;
     let mut bct_rx = join_response.bct_rx;
-    let id: UserID = join_response.id;
+    let mut id: UserID = join_response.id;
+    Span::current().record("user", &display(id));
 
-    let mut interval = tokio::time::interval(Duration::from_millis(1000));
-    // Echo incoming WebSocket messages and send a message periodically every second.
+    let mut interval = tokio::time::interval(conn.cfg.ping_interval());
+    let (mut sig_tx, mut sig_rx) = mpsc::channel::<Signal>(conn.cfg.signal_buffer);
+    let mut next_path = None;
+    let mut close = None;
 
-    let (mut sig_tx, mut sig_rx) = mpsc::channel::<Signal>(20);
-
-    let int_fut = interval.next();
-    let msg_fut = ws_receiver.next();
-
-    let bct_fut = bct_rx.next();
-    let sig_fut = sig_rx.next();
-
-    let mut int_or_msg_fut = select(msg_fut, int_fut);
-    let mut bct_or_sig_fut = select(bct_fut, sig_fut);
     loop {
         trace!("Loop iteration");
-        match select(int_or_msg_fut, bct_or_sig_fut).await {
-            Either::Left((msg_or_int, bct_or_sig_fut_continue)) => {
-                match msg_or_int {
-                    Either::Left((msg, int_fut_continue)) => {
-                        trace!("Message");
-                        match msg {
-                            Some(msg) => {
-                                let msg = match msg {
-                                    Err(e) => {
-                                        error!("Error on input stream: {}", e);
-                                        submit_close(id, &mut msg_tx).await;
-                                        break;
-                                    }
-                                    Ok(msg) => msg,
-                                };
-
-                                match handle_message(
-                                    id,
-                                    msg,
-                                    &mut sig_tx,
-                                    &mut msg_tx,
-                                    &mut ws_sender,
-                                )
-                                .await
-                                {
-                                    Ok(CommandRes::Break) => break,
-                                    Ok(CommandRes::Continue) => {}
-                                    Err(err) => {
-                                        error!("Could not handle message: {}", err);
-                                        break;
-                                    }
-                                }
-                            }
-                            None => {
-                                debug!("WebSocket stream was terminated unexpectedly");
-                                submit_close(id, &mut msg_tx).await;
-                                break;
-                            }
-                        };
+        tokio::select! {
+            msg = ws_receiver.next() => {
+                trace!("Message");
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        error!("Error on input stream: {}", e);
+                        submit_close(id, &mut msg_tx).await;
+                        break;
+                    }
+                    None => {
+                        debug!("WebSocket stream was terminated unexpectedly");
+                        submit_close(id, &mut msg_tx).await;
+                        break;
+                    }
+                };
 
-                        int_or_msg_fut = select(ws_receiver.next(), int_fut_continue);
+                if !matches!(msg, Message::Pong(_)) {
+                    conn.last_activity = Instant::now();
+                }
+                let res = handle_message(
+                    id,
+                    msg,
+                    conn,
+                    &mut sig_tx,
+                    &mut msg_tx,
+                    ws_sender,
+                )
+                .await;
+                match res {
+                    Ok(CommandRes::Break) => break,
+                    Ok(CommandRes::Close(reason)) => {
+                        close = Some(reason);
+                        break;
+                    }
+                    Ok(CommandRes::Leave(path)) => {
+                        next_path = Some(path);
+                        break;
                     }
-                    Either::Right((opt_instant, msg_fut_continue)) => {
-                        trace!("Send ping to {}", id);
-                        let time = opt_instant.unwrap();
-                        let dur = time.into_std().duration_since(start_time);
-                        let bytes: [u8; 16] = dur.as_micros().to_le_bytes();
-                        let vec: Vec<u8> = Vec::from(&bytes[..]);
-                        if let Err(err) = ws_sender.send(Message::Ping(vec)).await {
-                            error!("Could not send ping: {}", err);
+                    Ok(CommandRes::Continue) => {}
+                    Ok(CommandRes::Resumed(old_id)) => {
+                        debug!("{} continues as {}", id, old_id);
+                        id = old_id;
+                        Span::current().record("user", &display(id));
+                    }
+                    Ok(CommandRes::Snapshot) => match snapshot(id, &mut msg_tx, ws_sender).await {
+                        // Broadcasts up to the snapshot are already part of it
+                        Ok(new_bct_rx) => bct_rx = new_bct_rx,
+                        Err(err) => {
+                            error!("Could not send snapshot: {}", err);
+                            submit_close(id, &mut msg_tx).await;
+                            close = Some(CloseReason::Error);
+                            break;
+                        }
+                    },
+                    Ok(CommandRes::Open(tab, path)) => {
+                        if let Err(err) = tabs.open(tab, path, conn, ws_sender).await {
+                            error!("Could not open tab {}: {}", tab, err);
                             submit_close(id, &mut msg_tx).await;
                             break;
                         }
-
-                        int_or_msg_fut = select(msg_fut_continue, interval.next());
+                    }
+                    Ok(CommandRes::Tab(tab, cmd)) => {
+                        if let Err(err) = tabs.forward(tab, cmd, conn, ws_sender).await {
+                            error!("Could not pass command to tab {}: {}", tab, err);
+                            submit_close(id, &mut msg_tx).await;
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Could not handle message: {}", err);
+                        submit_close(id, &mut msg_tx).await;
+                        close = Some(CloseReason::Error);
+                        break;
                     }
                 }
-                bct_or_sig_fut = bct_or_sig_fut_continue; // Continue waiting for broadcasts
-            }
-            Either::Right((bct_or_sig, int_or_msg_fut_continue)) => {
-                match bct_or_sig {
-                    Either::Left((bct, sig_fut_continue)) => {
-                        if let Some(msg) = bct {
-                            match msg {
-                                Ok(msg) => {
-                                    if let Err(err) = handle_broadcast(msg, &mut ws_sender).await {
-                                        error!("Could not send broadcast: {}", err);
-                                        //submit_close(id, &mut msg_tx).await;
-                                        //break;
-                                    }
-                                }
-                                Err(err) => {
-                                    error!("Could not recieve broadcast: {}", err);
-                                    //submit_close(id, &mut msg_tx).await;
-                                    //break;
-                                }
-                            }
-                        } else {
-                            // End of stream
-                            info!("End of stream");
+            }
+            time = interval.tick() => {
+                if conn.heartbeat.is_dead(conn.cfg.pong_timeout()) {
+                    info!("Connection to {} timed out", id);
+                    submit_close(id, &mut msg_tx).await;
+                    close = Some(CloseReason::Timeout);
+                    break;
+                }
+                if conn.is_idle() {
+                    info!("Connection to {} was idle for too long", id);
+                    submit_close(id, &mut msg_tx).await;
+                    close = Some(CloseReason::Idle);
+                    break;
+                }
+                trace!("Send ping to {}", id);
+                let vec = conn.heartbeat.ping_payload(time.into_std());
+                if let Err(err) = ws_sender.send(Message::Ping(vec)).await {
+                    error!("Could not send ping: {}", err);
+                    submit_close(id, &mut msg_tx).await;
+                    break;
+                }
+            }
+            bct = bct_rx.recv() => {
+                match bct {
+                    Ok(msg) => {
+                        if let Err(err) = handle_broadcast(id, conn.version, msg, ws_sender).await {
+                            error!("Could not send broadcast: {}", err);
                         }
-
-                        // Wait for next broadcast.
-                        bct_or_sig_fut = select(bct_rx.next(), sig_fut_continue);
                     }
-                    Either::Right((sig, bct_fut_continue)) => {
-                        if let Some(signal) = sig {
-                            if let Err(err) = handle_signal(signal, &mut ws_sender).await {
-                                warn!("Could not handle signal {:?}", err);
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("{} missed {} broadcasts, resyncing", id, n);
+                        match resync(id, conn.version, &mut msg_tx, ws_sender).await {
+                            Ok(new_bct_rx) => bct_rx = new_bct_rx,
+                            Err(err) => {
+                                error!("Could not resync: {}", err);
+                                submit_close(id, &mut msg_tx).await;
+                                close = Some(CloseReason::Error);
+                                break;
                             }
-                        } else {
-                            // None signal, end of stream
                         }
-                        bct_or_sig_fut = select(bct_fut_continue, sig_rx.next());
+                    }
+                    Err(RecvError::Closed) => {
+                        info!("End of stream");
+                        close = Some(CloseReason::Shutdown);
+                        break;
+                    }
+                }
+            }
+            sig = sig_rx.recv() => {
+                if let Some(signal) = sig {
+                    if let SignalKind::Kick = signal.kind {
+                        // The channel already removed us, but waits for this to forget the kick
+                        info!("{} was kicked by {}", id, signal.sender);
+                        submit_close(id, &mut msg_tx).await;
+                        close = Some(CloseReason::Kicked);
+                        break;
+                    }
+                    if let Err(err) = handle_signal(signal, conn.version, ws_sender).await {
+                        warn!("Could not handle signal {:?}", err);
+                    }
+                }
+            }
+            event = tabs.next_event() => {
+                if let Some(event) = event {
+                    if let Err(err) = tabs.handle_event(event, ws_sender).await {
+                        error!("Could not send frame of a tab: {}", err);
+                        submit_close(id, &mut msg_tx).await;
+                        break;
                     }
                 }
+            }
+        }
+    }
+
+    if let Some(reason) = close {
+        send_close(ws_sender, reason).await;
+    }
+    next_path
+}
+
+/// Refuse a connection in the handshake, e.g. because the server is at capacity
+pub async fn reject_connection(stream: ClientStream, status: StatusCode, reason: &'static str) {
+    let callback = move |_req: &server::Request,
+                         _rep: server::Response|
+          -> Result<server::Response, server::ErrorResponse> {
+        let mut rep = HttpResponse::new(Some(reason.to_string()));
+        *rep.status_mut() = status;
+        Err(rep)
+    };
+    if let Err(e) = accept_hdr_async(stream, callback).await {
+        debug!("Rejected connection: {}", e);
+    }
+}
+
+/// Handle an incoming connection
+#[instrument(skip(lc, stream, cfg, auth), fields(user = Empty))]
+pub async fn handle_connection(
+    mut lc: LobbyClient,
+    peer: SocketAddr,
+    stream: ClientStream,
+    cfg: ClientConfig,
+    auth: Option<Arc<AuthConfig>>,
+) -> Result<(), Report> {
+    let (tx, rx) = oneshot::channel::<Handshake>();
+    let ws_stream: WebSocketStream<ClientStream> =
+        accept_hdr_async(stream, make_callback(tx, auth, cfg.allowed_origins.clone())).await?;
+    let handshake = rx.await.wrap_err("Callback dropped")?;
+    // The query may contain the token or a password, so only the path is used from here on
+    let path = handshake.uri.path().to_owned();
+    let password = handshake.password.clone();
+    let mut conn = ConnState::new(handshake, cfg);
 
-                int_or_msg_fut = int_or_msg_fut_continue; // Continue receiving the WebSocket message.
+    info!(
+        "New WebSocket connection: {} to {} ({:?}, {:?})",
+        peer, path, conn.version, conn.identity
+    );
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let mut tabs = Tabs::new(lc.clone(), password.clone());
+
+    let mut channel_path = urlencoding::decode(&path)?;
+    loop {
+        let identity = conn.identity.clone();
+        let join_response = match lc
+            .join_channel(channel_path.clone(), identity, password.clone())
+            .await
+        {
+            Ok(jr) => {
+                // The client may have asked for a path that is spelled differently
+                let msg = format!("joined|{}", jr.path);
+                ws_sender.send(Message::text(msg)).await?;
+                if let Some(title) = &jr.title {
+                    let msg = format!("title|{}", title);
+                    ws_sender.send(Message::text(msg)).await?;
+                }
+                jr
+            }
+            Err(JoinError::IsFolder(listing)) => {
+                let msg = format!("folder|{}", serde_json::to_string(&listing).unwrap());
+                ws_sender.send(Message::text(msg)).await?;
+                match handle_folder(
+                    &mut lc,
+                    &channel_path,
+                    &mut conn,
+                    &mut tabs,
+                    &mut ws_sender,
+                    &mut ws_receiver,
+                )
+                .await?
+                {
+                    Some(next_path) => {
+                        channel_path = next_path;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            Err(JoinError::ChannelFull(limit)) => {
+                let msg = format!("full|{}", limit);
+                ws_sender.send(Message::text(msg)).await?;
+                send_close(&mut ws_sender, CloseReason::ChannelFull).await;
+                break;
+            }
+            Err(JoinError::Forbidden(path)) => {
+                info!("{} may not join {:?}", peer, path);
+                send_close(&mut ws_sender, CloseReason::Forbidden).await;
+                break;
+            }
+            Err(JoinError::BadPassword(path)) => {
+                info!("{} gave no or the wrong password for {:?}", peer, path);
+                send_close(&mut ws_sender, CloseReason::BadPassword).await;
+                break;
+            }
+            Err(JoinError::InvalidPath(path)) => {
+                info!("{} tried to join invalid path {:?}", peer, path);
+                send_close(&mut ws_sender, CloseReason::InvalidPath).await;
+                break;
+            }
+            Err(JoinError::NotCanonical(path, canonical)) => {
+                info!(
+                    "{} tried to join {:?} instead of {:?}",
+                    peer, path, canonical
+                );
+                let msg = format!("moved|{}", canonical);
+                ws_sender.send(Message::text(msg)).await?;
+                send_close(&mut ws_sender, CloseReason::InvalidPath).await;
+                break;
+            }
+            Err(e @ JoinError::Exhausted) => {
+                let text = e.to_string();
+                ws_sender
+                    .send(conn.version.error(ErrorCode::Unavailable, &text))
+                    .await?;
+                send_close(&mut ws_sender, CloseReason::Error).await;
+                break;
             }
+            Err(JoinError::StorageFailed(e)) | Err(JoinError::LoadFailed(e)) => {
+                let text = format!("Could not open the document: {}", e);
+                ws_sender
+                    .send(conn.version.error(ErrorCode::Storage, &text))
+                    .await?;
+                send_close(&mut ws_sender, CloseReason::Error).await;
+                break;
+            }
+            Err(e) => {
+                send_close(&mut ws_sender, CloseReason::Shutdown).await;
+                return Err(e.into());
+            }
+        };
+        let res = serve_channel(
+            join_response,
+            &mut conn,
+            &mut tabs,
+            &mut ws_sender,
+            &mut ws_receiver,
+        )
+        .await;
+        match res {
+            Some(next_path) => {
+                info!("{} switches to {}", peer, next_path);
+                channel_path = next_path;
+            }
+            None => break,
         }
     }
 
@@ -436,3 +1506,354 @@ pub async fn handle_connection(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tungstenite::handshake::server::Callback;
+    use tungstenite::http::Request;
+
+    /// Collects the frames that would be sent to the client
+    #[derive(Default)]
+    struct Frames(Vec<Message>);
+
+    impl Sink<Message> for Frames {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<TResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> TResult<()> {
+            self.0.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<TResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<TResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The state of a `padington.v2` connection with the given options
+    fn conn_state(cfg: ClientConfig) -> ConnState {
+        let handshake = Handshake {
+            uri: Uri::from_static("/test"),
+            version: ProtocolVersion::V2,
+            identity: None,
+            password: None,
+        };
+        ConnState::new(handshake, cfg)
+    }
+
+    /// Handle a single command, returns the requests for the channel and the frames
+    async fn run_command(conn: &mut ConnState, text: &str) -> (Vec<RequestKind>, Vec<Message>) {
+        let (mut sig_tx, _sig_rx) = mpsc::channel(10);
+        let (mut msg_tx, mut msg_rx) = mpsc::channel(10);
+        let mut frames = Frames::default();
+        let id = UserID::from(1);
+        let cmd_res = Command::parse(text);
+        handle_command(id, conn, &mut sig_tx, &mut msg_tx, &mut frames, cmd_res)
+            .await
+            .unwrap();
+        drop(msg_tx);
+        let mut requests = Vec::new();
+        while let Some(req) = msg_rx.recv().await {
+            requests.push(req.kind);
+        }
+        (requests, frames.0)
+    }
+
+    #[tokio::test]
+    async fn chat_messages_up_to_the_limit_are_sent() {
+        let mut conn = conn_state(ClientConfig::default());
+        let max_len = conn.cfg.max_chat_len;
+
+        let borderline = format!("chat|{}", "a".repeat(max_len));
+        let (requests, frames) = run_command(&mut conn, &borderline).await;
+        assert!(frames.is_empty());
+        assert!(matches!(&requests[..], [RequestKind::Chat(msg)] if msg.len() == max_len));
+
+        let too_long = format!("chat|{}", "a".repeat(max_len + 1));
+        let (requests, frames) = run_command(&mut conn, &too_long).await;
+        assert!(requests.is_empty());
+        assert_eq!(
+            frames,
+            vec![Message::text(format!(
+                "error|too_large|Chat message exceeds {} bytes",
+                max_len
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn whispers_up_to_the_limit_are_sent() {
+        let mut conn = conn_state(ClientConfig::default());
+        let max_len = conn.cfg.max_chat_len;
+
+        let borderline = format!("whisper|2|{}", "a".repeat(max_len));
+        let (requests, frames) = run_command(&mut conn, &borderline).await;
+        assert!(frames.is_empty());
+        assert!(matches!(
+            &requests[..],
+            [RequestKind::Signal(Signal { kind: SignalKind::Whisper(msg), .. })] if msg.len() == max_len
+        ));
+
+        let too_long = format!("whisper|2|{}", "a".repeat(max_len + 1));
+        let (requests, frames) = run_command(&mut conn, &too_long).await;
+        assert!(requests.is_empty());
+        assert_eq!(
+            frames,
+            vec![Message::text(format!(
+                "error|too_large|Whisper exceeds {} bytes",
+                max_len
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn payloads_up_to_the_limit_are_parsed() {
+        let steps = r#"[{"stepType":"replace","from":1,"to":1,"slice":{"content":[{"type":"text","text":"hi"}]}}]"#;
+        let cfg = ClientConfig {
+            max_payload_len: steps.len(),
+            ..ClientConfig::default()
+        };
+        let mut conn = conn_state(cfg);
+        let too_large = Message::text(format!(
+            "error|too_large|Payload exceeds {} bytes",
+            steps.len()
+        ));
+
+        let (requests, frames) = run_command(&mut conn, &format!("steps|0|{}", steps)).await;
+        assert!(frames.is_empty());
+        assert!(matches!(&requests[..], [RequestKind::Steps(0, _)]));
+
+        // Still valid JSON, but one byte too long
+        let (requests, frames) = run_command(&mut conn, &format!("steps|0|{} ", steps)).await;
+        assert!(requests.is_empty());
+        assert_eq!(frames, vec![too_large.clone()]);
+
+        let update = format!("{{\"name\":\"{}\"}}", "a".repeat(steps.len()));
+        let (requests, frames) = run_command(&mut conn, &format!("update|{}", update)).await;
+        assert!(requests.is_empty());
+        assert_eq!(frames, vec![too_large.clone()]);
+
+        let signal = format!("\"{}\"", "a".repeat(steps.len()));
+        let (requests, frames) = run_command(&mut conn, &format!("webrtc|2|{}", signal)).await;
+        assert!(requests.is_empty());
+        assert_eq!(frames, vec![too_large]);
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_reported_with_their_verb() {
+        let mut conn = conn_state(ClientConfig::default());
+        let (mut sig_tx, _sig_rx) = mpsc::channel(10);
+        let (mut msg_tx, _msg_rx) = mpsc::channel(10);
+        let mut frames = Frames::default();
+        let cmd_res = Command::parse("bogus|foo");
+        let res = handle_command(
+            UserID::from(1),
+            &mut conn,
+            &mut sig_tx,
+            &mut msg_tx,
+            &mut frames,
+            cmd_res,
+        )
+        .await;
+        assert!(matches!(res, Ok(CommandRes::Continue)));
+        assert_eq!(
+            frames.0,
+            vec![Message::text(
+                "error|unknown_command|The command `bogus` is not known"
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_messages_close_the_connection() {
+        let mut conn = conn_state(ClientConfig::default());
+        let (mut sig_tx, _sig_rx) = mpsc::channel(10);
+        let (mut msg_tx, mut msg_rx) = mpsc::channel(10);
+        let mut frames = Frames::default();
+        let id = UserID::from(1);
+        let msg = Message::binary(vec![1, 2, 3]);
+        let res = handle_message(id, msg, &mut conn, &mut sig_tx, &mut msg_tx, &mut frames).await;
+        assert!(matches!(res, Ok(CommandRes::Close(CloseReason::Binary))));
+        // Nothing is echoed, and the channel knows that the client is gone
+        assert!(frames.0.is_empty());
+        drop(msg_tx);
+        let req = msg_rx.recv().await.unwrap();
+        assert!(matches!(req.kind, RequestKind::Close(None)));
+
+        let frame = CloseReason::Binary.frame();
+        assert_eq!(frame.code, CloseCode::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn batches_over_the_step_limit_are_rejected() {
+        let cfg = ClientConfig {
+            max_steps: 2,
+            ..ClientConfig::default()
+        };
+        let mut conn = conn_state(cfg);
+        let step = r#"{"stepType":"replace","from":1,"to":1,"slice":{"content":[{"type":"text","text":"a"}]}}"#;
+
+        let two = format!("steps|0|[{},{}]", step, step);
+        let (requests, frames) = run_command(&mut conn, &two).await;
+        assert!(frames.is_empty());
+        assert!(matches!(&requests[..], [RequestKind::Steps(0, steps)] if steps.len() == 2));
+
+        let three = format!("steps|0|[{},{},{}]", step, step, step);
+        let (requests, frames) = run_command(&mut conn, &three).await;
+        assert!(requests.is_empty());
+        assert_eq!(
+            frames,
+            vec![Message::text("error|too_large|More than 2 steps at once")]
+        );
+
+        // The connection is still usable
+        let (requests, frames) = run_command(&mut conn, "chat|hi").await;
+        assert!(frames.is_empty());
+        assert!(matches!(&requests[..], [RequestKind::Chat(msg)] if msg == "hi"));
+    }
+
+    #[test]
+    fn compression_is_not_negotiated() {
+        let (tx, _rx) = oneshot::channel();
+        let callback = make_callback(tx, None, Vec::new());
+        let req = Request::builder()
+            .uri("/doc")
+            .header(SEC_WEBSOCKET_PROTOCOL, "padington.v2")
+            .header(
+                "Sec-WebSocket-Extensions",
+                "permessage-deflate; client_max_window_bits",
+            )
+            .body(())
+            .unwrap();
+        let rep = callback
+            .on_request(&req, server::Response::default())
+            .unwrap();
+        assert!(rep.headers().get("Sec-WebSocket-Extensions").is_none());
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_with_pongs() {
+        let mut conn = conn_state(ClientConfig::default());
+        let (mut sig_tx, _sig_rx) = mpsc::channel(10);
+        let (mut msg_tx, _msg_rx) = mpsc::channel(10);
+        let mut frames = Frames::default();
+        let msg = Message::Ping(vec![1, 2, 3]);
+        let res = handle_message(
+            UserID::from(1),
+            msg,
+            &mut conn,
+            &mut sig_tx,
+            &mut msg_tx,
+            &mut frames,
+        )
+        .await;
+        assert!(matches!(res, Ok(CommandRes::Continue)));
+        assert_eq!(frames.0, vec![Message::Pong(vec![1, 2, 3])]);
+    }
+
+    #[tokio::test]
+    async fn all_channel_commands_are_rate_limited() {
+        let cfg = ClientConfig {
+            rate_limit: 0.001,
+            rate_burst: 1,
+            ..ClientConfig::default()
+        };
+        let mut conn = conn_state(cfg);
+        let rate_limited = Message::text("error|rate_limited|Too many commands, slow down");
+
+        let (requests, frames) = run_command(&mut conn, "typing|true").await;
+        assert!(frames.is_empty());
+        assert!(matches!(&requests[..], [RequestKind::Typing(true)]));
+
+        let commands = [
+            "typing|false",
+            "cursor|1|2",
+            "whisper|2|hi",
+            "webrtc|2|{}",
+            r#"update|{"name":"Alice"}"#,
+        ];
+        for command in commands.iter() {
+            let (requests, frames) = run_command(&mut conn, command).await;
+            assert!(requests.is_empty(), "{} was not limited", command);
+            assert_eq!(frames, vec![rate_limited.clone()]);
+        }
+
+        // Leaving always works
+        let (requests, frames) = run_command(&mut conn, "close").await;
+        assert!(frames.is_empty());
+        assert!(matches!(&requests[..], [RequestKind::Close(None)]));
+    }
+
+    /// An upgrade request for `padington.v2` from `origin`
+    fn upgrade_from(origin: &str) -> server::Request {
+        Request::builder()
+            .uri("/doc")
+            .header(ORIGIN, origin)
+            .header(SEC_WEBSOCKET_PROTOCOL, "padington.v2")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn only_allowed_origins_may_connect() {
+        let allowed = vec![String::from("https://pad.example")];
+
+        let (tx, _rx) = oneshot::channel();
+        let callback = make_callback(tx, None, allowed.clone());
+        let req = upgrade_from("https://evil.example");
+        let rep = callback
+            .on_request(&req, server::Response::default())
+            .unwrap_err();
+        assert_eq!(rep.status(), StatusCode::FORBIDDEN);
+
+        let (tx, _rx) = oneshot::channel();
+        let callback = make_callback(tx, None, allowed);
+        let req = upgrade_from("https://pad.example");
+        let rep = callback
+            .on_request(&req, server::Response::default())
+            .unwrap();
+        let origin = rep.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap();
+        assert_eq!(origin, "https://pad.example");
+    }
+
+    #[test]
+    fn a_dropped_receiver_rejects_the_upgrade() {
+        let (tx, rx) = oneshot::channel();
+        drop(rx);
+        let callback = make_callback(tx, None, Vec::new());
+        let req = upgrade_from("https://pad.example");
+        let rep = callback
+            .on_request(&req, server::Response::default())
+            .unwrap_err();
+        assert_eq!(rep.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn the_password_header_wins_over_the_query() {
+        let req = Request::builder()
+            .uri("/doc?password=query")
+            .header("X-Padington-Password", "header")
+            .body(())
+            .unwrap();
+        assert_eq!(find_password(&req).unwrap().expose(), "header");
+
+        let req = Request::builder()
+            .uri("/doc?password=query")
+            .body(())
+            .unwrap();
+        assert_eq!(find_password(&req).unwrap().expose(), "query");
+
+        let req = Request::builder().uri("/doc").body(()).unwrap();
+        assert!(find_password(&req).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+//! # Versions of the wire protocol
+
+use crate::command::ErrorCode;
+use tungstenite::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap};
+use tungstenite::Message;
+
+/// A version of the padington protocol, as negotiated with the `Sec-WebSocket-Protocol` header
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    /// The original protocol (`padington`)
+    V1,
+    /// The second version of the protocol (`padington.v2`)
+    V2,
+    /// The third version of the protocol (`padington.v3`)
+    ///
+    /// Peers are sent as an array of objects that contain their ID.
+    V3,
+}
+
+impl ProtocolVersion {
+    /// All supported versions
+    pub const ALL: [Self; 3] = [Self::V1, Self::V2, Self::V3];
+
+    /// The name of the subprotocol for this version
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V1 => "padington",
+            Self::V2 => "padington.v2",
+            Self::V3 => "padington.v3",
+        }
+    }
+
+    /// Get the version for a subprotocol name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|version| version.name() == name)
+    }
+
+    /// Pick the highest version from the subprotocols that a client offered
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| Self::from_name(name.trim()))
+            .max()
+    }
+
+    /// The frame that reports an error to a client of this version
+    ///
+    /// The first version only sends the message, later versions put the code before it.
+    pub fn error(self, code: ErrorCode, text: &str) -> Message {
+        match self {
+            Self::V1 => Message::text(format!("error|{}", text)),
+            Self::V2 | Self::V3 => Message::text(format!("error|{}|{}", code, text)),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! # Tabs
+//!
+//! A connection can be in more than one channel at a time. The channel from the URL (or
+//! the last `leave`) is served by the connection task itself, every other one by a task
+//! for its tab. Tab tasks get their commands from the connection task and hand their frames
+//! back to it, so that there is still a single writer for the WebSocket.
+use super::{
+    handle_broadcast, handle_command, handle_signal, resync, snapshot, submit_close, CommandRes,
+    ConnState, WsSender,
+};
+use crate::channel::{Signal, SignalKind};
+use crate::command::{Command, ErrorCode};
+use crate::lobby::{JoinError, JoinResponse, LobbyClient};
+use crate::util::Secret;
+use futures_util::{Sink, SinkExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_futures::Instrument;
+use tungstenite::{Error as WsError, Message, Result as TResult};
+
+/// The maximum number of tabs per connection, besides its main channel
+const MAX_TABS: usize = 8;
+
+/// The number of frames from all tabs that may wait for the connection
+const FRAME_BUFFER: usize = 100;
+
+/// The number of commands that may wait for a tab
+const COMMAND_BUFFER: usize = 20;
+
+/// Something that a tab hands to the connection task
+pub(super) enum TabEvent {
+    /// A frame for the client, already tagged with the tab
+    Frame(Message),
+    /// The tab left its channel
+    Closed(u32),
+}
+
+/// Writes the frames of a tab to the connection task, tagging text frames with the tab
+struct TabSink {
+    tab: u32,
+    event_tx: mpsc::Sender<TabEvent>,
+}
+
+impl Sink<Message> for TabSink {
+    type Error = WsError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        self.event_tx
+            .poll_ready(cx)
+            .map_err(|_| WsError::ConnectionClosed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), WsError> {
+        let item = match item {
+            Message::Text(text) => Message::text(format!("tab|{}|{}", self.tab, text)),
+            other => other,
+        };
+        self.event_tx
+            .try_send(TabEvent::Frame(item))
+            .map_err(|_| WsError::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The tabs of a connection
+pub(super) struct Tabs {
+    /// The lobby to join the channels of new tabs
+    lc: LobbyClient,
+    /// The password that the connection was opened with
+    password: Option<Secret>,
+    /// The senders for commands to each open tab
+    cmd_txs: HashMap<u32, mpsc::Sender<String>>,
+    /// The sender for the tab tasks
+    event_tx: mpsc::Sender<TabEvent>,
+    /// The receiver for frames and closed tabs
+    event_rx: mpsc::Receiver<TabEvent>,
+}
+
+impl Tabs {
+    pub(super) fn new(lc: LobbyClient, password: Option<Secret>) -> Self {
+        let (event_tx, event_rx) = mpsc::channel(FRAME_BUFFER);
+        Self {
+            lc,
+            password,
+            cmd_txs: HashMap::new(),
+            event_tx,
+            event_rx,
+        }
+    }
+
+    /// Wait for the next event from any of the tabs
+    pub(super) async fn next_event(&mut self) -> Option<TabEvent> {
+        self.event_rx.recv().await
+    }
+
+    /// Send a frame from a tab to the client, or forget a tab that was closed
+    pub(super) async fn handle_event(
+        &mut self,
+        event: TabEvent,
+        ws_sender: &mut WsSender,
+    ) -> TResult<()> {
+        match event {
+            TabEvent::Frame(msg) => ws_sender.send(msg).await,
+            TabEvent::Closed(tab) => {
+                self.cmd_txs.remove(&tab);
+                Ok(())
+            }
+        }
+    }
+
+    /// Join the channel at `path` in a new tab
+    pub(super) async fn open(
+        &mut self,
+        tab: u32,
+        path: String,
+        conn: &ConnState,
+        ws_sender: &mut WsSender,
+    ) -> TResult<()> {
+        let (code, text) = if tab == 0 {
+            (
+                ErrorCode::InvalidArgument,
+                String::from("Tab 0 is the main channel"),
+            )
+        } else if self.cmd_txs.contains_key(&tab) {
+            (ErrorCode::Exists, format!("Tab {} is already open", tab))
+        } else if self.cmd_txs.len() >= MAX_TABS {
+            (
+                ErrorCode::TooLarge,
+                format!("No more than {} tabs", MAX_TABS),
+            )
+        } else {
+            let identity = conn.identity.clone();
+            let password = self.password.clone();
+            match self.lc.join_channel(path, identity, password).await {
+                Ok(join_response) => {
+                    let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_BUFFER);
+                    let sink = TabSink {
+                        tab,
+                        event_tx: self.event_tx.clone(),
+                    };
+                    let task = serve_tab(tab, join_response, conn.for_tab(), cmd_rx, sink);
+                    tokio::spawn(task.instrument(info_span!("tab", tab)));
+                    self.cmd_txs.insert(tab, cmd_tx);
+                    return Ok(());
+                }
+                Err(e) => {
+                    let code = match &e {
+                        JoinError::Forbidden(_) | JoinError::BadPassword(_) => ErrorCode::Forbidden,
+                        JoinError::InvalidPath(_) | JoinError::NotCanonical(..) => {
+                            ErrorCode::NotFound
+                        }
+                        JoinError::StorageFailed(_) | JoinError::LoadFailed(_) => {
+                            ErrorCode::Storage
+                        }
+                        _ => ErrorCode::Unavailable,
+                    };
+                    let text = match e {
+                        JoinError::IsFolder(_) => String::from("Tabs can not hold folders"),
+                        e => e.to_string(),
+                    };
+                    (code, format!("Could not open tab {}: {}", tab, text))
+                }
+            }
+        };
+        ws_sender.send(conn.version.error(code, &text)).await
+    }
+
+    /// Pass a command to the channel in a tab
+    pub(super) async fn forward(
+        &mut self,
+        tab: u32,
+        cmd: String,
+        conn: &ConnState,
+        ws_sender: &mut WsSender,
+    ) -> TResult<()> {
+        // The tab may be waiting for this task to take its frames, so never wait for it here
+        let res = match self.cmd_txs.get_mut(&tab) {
+            Some(cmd_tx) => cmd_tx.try_send(cmd),
+            None => return send_no_tab(tab, conn, ws_sender).await,
+        };
+        match res {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                let text = format!("Tab {} is busy, slow down", tab);
+                ws_sender
+                    .send(conn.version.error(ErrorCode::RateLimited, &text))
+                    .await
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.cmd_txs.remove(&tab);
+                send_no_tab(tab, conn, ws_sender).await
+            }
+        }
+    }
+}
+
+/// Tell the client that there is no open tab with this number
+async fn send_no_tab(tab: u32, conn: &ConnState, ws_sender: &mut WsSender) -> TResult<()> {
+    let text = format!("No tab {}", tab);
+    ws_sender
+        .send(conn.version.error(ErrorCode::NotFound, &text))
+        .await
+}
+
+/// Serve a client in the channel of a tab until it leaves or the connection closes
+async fn serve_tab(
+    tab: u32,
+    join_response: JoinResponse,
+    mut conn: ConnState,
+    mut cmd_rx: mpsc::Receiver<String>,
+    mut sink: TabSink,
+) {
+    let mut msg_tx = join_response.msg_tx;
+    let mut bct_rx = join_response.bct_rx;
+    let mut id = join_response.id;
+    let (mut sig_tx, mut sig_rx) = mpsc::channel::<Signal>(conn.cfg.signal_buffer);
+    info!("Opened tab {} for {} in {:?}", tab, id, join_response.path);
+
+    let msg = format!("joined|{}", join_response.path);
+    if sink.send(Message::text(msg)).await.is_err() {
+        submit_close(id, &mut msg_tx).await;
+        return;
+    }
+    if let Some(title) = &join_response.title {
+        let msg = format!("title|{}", title);
+        if sink.send(Message::text(msg)).await.is_err() {
+            submit_close(id, &mut msg_tx).await;
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            cmd = cmd_rx.recv() => {
+                let text = match cmd {
+                    Some(text) => text,
+                    None => {
+                        debug!("The connection of tab {} closed", tab);
+                        submit_close(id, &mut msg_tx).await;
+                        break;
+                    }
+                };
+                let cmd_res = Command::parse(&text);
+                let res =
+                    handle_command(id, &mut conn, &mut sig_tx, &mut msg_tx, &mut sink, cmd_res).await;
+                match res {
+                    Ok(CommandRes::Continue) => {}
+                    Ok(CommandRes::Resumed(old_id)) => {
+                        debug!("{} continues as {}", id, old_id);
+                        id = old_id;
+                    }
+                    Ok(CommandRes::Snapshot) => match snapshot(id, &mut msg_tx, &mut sink).await {
+                        Ok(new_bct_rx) => bct_rx = new_bct_rx,
+                        Err(err) => {
+                            error!("Could not send snapshot: {}", err);
+                            submit_close(id, &mut msg_tx).await;
+                            break;
+                        }
+                    },
+                    Ok(CommandRes::Open(..)) | Ok(CommandRes::Tab(..)) => {
+                        let msg = conn
+                            .version
+                            .error(ErrorCode::Unavailable, "Tabs can not be nested");
+                        if sink.send(msg).await.is_err() {
+                            submit_close(id, &mut msg_tx).await;
+                            break;
+                        }
+                    }
+                    // The channel already knows that the client left
+                    Ok(CommandRes::Leave(_)) | Ok(CommandRes::Close(_)) | Ok(CommandRes::Break) => {
+                        break
+                    }
+                    Err(err) => {
+                        error!("Could not handle command: {}", err);
+                        submit_close(id, &mut msg_tx).await;
+                        break;
+                    }
+                }
+            }
+            bct = bct_rx.recv() => {
+                match bct {
+                    Ok(msg) => {
+                        if let Err(err) = handle_broadcast(id, conn.version, msg, &mut sink).await {
+                            error!("Could not send broadcast: {}", err);
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("{} missed {} broadcasts, resyncing", id, n);
+                        match resync(id, conn.version, &mut msg_tx, &mut sink).await {
+                            Ok(new_bct_rx) => bct_rx = new_bct_rx,
+                            Err(err) => {
+                                error!("Could not resync: {}", err);
+                                submit_close(id, &mut msg_tx).await;
+                                break;
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => {
+                        info!("End of stream");
+                        break;
+                    }
+                }
+            }
+            sig = sig_rx.recv() => {
+                if let Some(signal) = sig {
+                    if let SignalKind::Kick = signal.kind {
+                        info!("{} was kicked by {}", id, signal.sender);
+                        submit_close(id, &mut msg_tx).await;
+                        break;
+                    }
+                    if let Err(err) = handle_signal(signal, conn.version, &mut sink).await {
+                        warn!("Could not handle signal {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    info!("Closed tab {} for {}", tab, id);
+    if sink.send(Message::text("closed")).await.is_err() {
+        debug!("The connection of tab {} is gone", tab);
+    }
+    if sink.event_tx.send(TabEvent::Closed(tab)).await.is_err() {
+        debug!("The connection of tab {} is gone", tab);
+    }
+}
@@ -1,17 +1,96 @@
 //! # Padington commands
+//!
+//! Commands are sent in text frames as `<command>|<argument>`. The protocol has no binary
+//! encoding (yet), so a binary frame closes the connection with the "unsupported data" code.
+//!
+//! A connection is in one channel, the one from the URL or from the last `leave`. It can
+//! join more channels with `open|<tab>|<path>` and then send `tab|<tab>|<command>` to
+//! them. Everything the server sends for such a tab starts with `tab|<tab>|`, and a tab
+//! ends with `tab|<tab>|closed`.
 
 use displaydoc::Display;
+use std::fmt;
 use std::str::FromStr;
 
+/// The code of an error that is reported to a client
+///
+/// Since `padington.v2`, errors are sent as `error|<code>|<message>`, where the message
+/// is meant for humans and the code is one of the following.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// `unknown_command`: the command is not known
+    UnknownCommand,
+    /// `invalid_argument`: an argument of the command is missing or malformed
+    InvalidArgument,
+    /// `rate_limited`: the client sent too many commands
+    RateLimited,
+    /// `forbidden`: the user may not do this
+    Forbidden,
+    /// `too_large`: a message or the document would exceed its size limit
+    TooLarge,
+    /// `steps_rejected`: the steps could not be applied to the document
+    StepsRejected,
+    /// `not_found`: the message, user or revision does not exist
+    NotFound,
+    /// `unavailable`: the command can not be used here
+    Unavailable,
+    /// `exists`: the document already exists
+    Exists,
+    /// `name_required`: the channel can only be joined with a name
+    NameRequired,
+    /// `storage`: the document could not be loaded or saved
+    Storage,
+}
+
+impl ErrorCode {
+    /// The code as it is sent to the client
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UnknownCommand => "unknown_command",
+            Self::InvalidArgument => "invalid_argument",
+            Self::RateLimited => "rate_limited",
+            Self::Forbidden => "forbidden",
+            Self::TooLarge => "too_large",
+            Self::StepsRejected => "steps_rejected",
+            Self::NotFound => "not_found",
+            Self::Unavailable => "unavailable",
+            Self::Exists => "exists",
+            Self::NameRequired => "name_required",
+            Self::Storage => "storage",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The maximum length of an unknown command that is echoed back to the client (in bytes)
+const MAX_ECHOED_LEN: usize = 32;
+
 /// Error when parsing a command
 #[derive(Display)]
 pub enum ParseCommandError {
     /// The command expected an argument (e.g. `{0}|foo`)
     MissingArg(CommandKind),
     /// The command `{0}` is not known
+    ///
+    /// Only contains the part before the first `|`, shortened to a few bytes.
     UnknownCommand(String),
 }
 
+impl ParseCommandError {
+    /// The code that this error is reported with
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::MissingArg(_) => ErrorCode::InvalidArgument,
+            Self::UnknownCommand(_) => ErrorCode::UnknownCommand,
+        }
+    }
+}
+
 /// A kind of incoming command
 #[derive(Display)]
 pub enum CommandKind {
@@ -25,23 +104,92 @@ pub enum CommandKind {
     Update,
     /// webrtc
     WebRTC,
+    /// typing
+    Typing,
+    /// cursor
+    Cursor,
+    /// whisper
+    Whisper,
+    /// create
+    Create,
+    /// leave
+    Leave,
+    /// kick
+    Kick,
+    /// stats
+    Stats,
+    /// revisions
+    Revisions,
+    /// revision
+    Revision,
+    /// snapshot
+    Snapshot,
+    /// react
+    React,
+    /// close
+    Close,
+    /// meta
+    Meta,
+    /// comment
+    Comment,
+    /// resolve
+    Resolve,
+    /// open
+    Open,
+    /// tab
+    Tab,
 }
 
 /// An incoming command
+///
+/// The arguments borrow from the frame they were parsed from, so parsing a
+/// command does not allocate. Callers own the parts that outlive the frame.
 #[derive(Debug, Clone)]
-pub enum Command {
+pub enum Command<'a> {
     /// A chat message
-    Chat(String),
+    Chat(&'a str),
     /// Steps from the server
-    Steps(usize, String),
+    Steps(usize, &'a str),
     /// A renamed user
-    Update(String),
-    /// Initialize with an intended name
-    Init(Option<String>),
-    /// Close the connection
-    Close,
+    Update(&'a str),
+    /// Initialize with an intended name and a token to resume a previous session
+    Init(Option<&'a str>, Option<&'a str>),
+    /// Close the connection, with an optional reason
+    Close(Option<&'a str>),
     /// A WebRTC signal for a client
-    WebRTC(u64, String),
+    WebRTC(u64, &'a str),
+    /// Whether the user is currently typing
+    Typing(bool),
+    /// The selection of the user
+    Cursor(usize, usize),
+    /// A private chat message for a client
+    Whisper(u64, &'a str),
+    /// Create a document in the current folder
+    Create(&'a str),
+    /// Leave the current channel and join the one at the given path
+    Leave(&'a str),
+    /// Remove a client from the channel
+    Kick(u64),
+    /// Request word and character counts of the document
+    Stats,
+    /// List the saved revisions of the document
+    Revisions,
+    /// Request the markdown of a saved revision
+    Revision(usize),
+    /// Request the current state of the document
+    Snapshot,
+    /// Add or remove a reaction to a chat message
+    React(u64, &'a str),
+    /// Change the title or description of the document
+    Meta(&'a str),
+    /// Comment on a range of the document
+    Comment(usize, usize, &'a str),
+    /// Resolve a comment by its ID
+    Resolve(u64),
+    /// Join the channel at the given path in another tab of this connection
+    Open(u32, &'a str),
+    /// A command for the channel in the given tab
+    Tab(u32, &'a str),
 }
 
 impl FromStr for CommandKind {
@@ -53,7 +201,30 @@ impl FromStr for CommandKind {
             "steps" => Ok(Self::Steps),
             "update" => Ok(Self::Update),
             "webrtc" => Ok(Self::WebRTC),
-            _ => Err(ParseCommandError::UnknownCommand(s.to_owned())),
+            "typing" => Ok(Self::Typing),
+            "cursor" => Ok(Self::Cursor),
+            "whisper" => Ok(Self::Whisper),
+            "create" => Ok(Self::Create),
+            "leave" => Ok(Self::Leave),
+            "kick" => Ok(Self::Kick),
+            "stats" => Ok(Self::Stats),
+            "revisions" => Ok(Self::Revisions),
+            "revision" => Ok(Self::Revision),
+            "snapshot" => Ok(Self::Snapshot),
+            "react" => Ok(Self::React),
+            "close" => Ok(Self::Close),
+            "meta" => Ok(Self::Meta),
+            "comment" => Ok(Self::Comment),
+            "resolve" => Ok(Self::Resolve),
+            "open" => Ok(Self::Open),
+            "tab" => Ok(Self::Tab),
+            _ => {
+                let mut end = s.len().min(MAX_ECHOED_LEN);
+                while !s.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Err(ParseCommandError::UnknownCommand(s[..end].to_owned()))
+            }
         }
     }
 }
@@ -68,20 +239,26 @@ fn split_arg(input: &str) -> (&str, Option<&str>) {
     }
 }
 
-impl FromStr for Command {
-    type Err = ParseCommandError;
-    fn from_str(input: &str) -> Result<Command, ParseCommandError> {
+impl<'a> Command<'a> {
+    /// Parse a command from a text frame, borrowing its arguments from `input`
+    pub fn parse(input: &'a str) -> Result<Self, ParseCommandError> {
         let (cmd, arg) = split_arg(input);
 
         match cmd.parse()? {
-            CommandKind::Init => Ok(Command::Init(arg.map(str::to_owned))),
+            CommandKind::Init => match arg.map(split_arg) {
+                Some((name, token)) => {
+                    let name = Some(name).filter(|name| !name.is_empty());
+                    Ok(Command::Init(name, token))
+                }
+                None => Ok(Command::Init(None, None)),
+            },
             CommandKind::Chat => {
                 let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Chat))?;
-                Ok(Command::Chat(text.to_owned()))
+                Ok(Command::Chat(text))
             }
             CommandKind::Update => {
                 let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Update))?;
-                Ok(Command::Update(text.to_owned()))
+                Ok(Command::Update(text))
             }
             CommandKind::WebRTC => {
                 let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::WebRTC))?;
@@ -91,7 +268,7 @@ impl FromStr for Command {
                 let reciever: u64 = reciever_str
                     .parse()
                     .map_err(|_| ParseCommandError::MissingArg(CommandKind::WebRTC))?;
-                Ok(Command::WebRTC(reciever, payload.to_owned()))
+                Ok(Command::WebRTC(reciever, payload))
             }
             CommandKind::Steps => {
                 let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Steps))?;
@@ -100,8 +277,154 @@ impl FromStr for Command {
                 let version: usize = version_str
                     .parse()
                     .map_err(|_| ParseCommandError::MissingArg(CommandKind::Steps))?;
-                Ok(Command::Steps(version, steps.to_owned()))
+                Ok(Command::Steps(version, steps))
+            }
+            CommandKind::Typing => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Typing))?;
+                let typing: bool = text
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Typing))?;
+                Ok(Command::Typing(typing))
+            }
+            CommandKind::Cursor => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Cursor))?;
+                let (from_str, opt_to) = split_arg(text);
+                let to_str = opt_to.ok_or(ParseCommandError::MissingArg(CommandKind::Cursor))?;
+                let from: usize = from_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Cursor))?;
+                let to: usize = to_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Cursor))?;
+                Ok(Command::Cursor(from, to))
+            }
+            CommandKind::Create => {
+                let name = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Create))?;
+                Ok(Command::Create(name))
+            }
+            CommandKind::Leave => {
+                let path = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Leave))?;
+                Ok(Command::Leave(path))
+            }
+            CommandKind::Meta => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Meta))?;
+                Ok(Command::Meta(text))
+            }
+            CommandKind::Close => {
+                let reason = arg.filter(|reason| !reason.is_empty());
+                Ok(Command::Close(reason))
+            }
+            CommandKind::Stats => Ok(Command::Stats),
+            CommandKind::Revisions => Ok(Command::Revisions),
+            CommandKind::Snapshot => Ok(Command::Snapshot),
+            CommandKind::Revision => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Revision))?;
+                let version: usize = text
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Revision))?;
+                Ok(Command::Revision(version))
+            }
+            CommandKind::React => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::React))?;
+                let (message_str, opt_emoji) = split_arg(text);
+                let emoji = opt_emoji.ok_or(ParseCommandError::MissingArg(CommandKind::React))?;
+                let message: u64 = message_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::React))?;
+                Ok(Command::React(message, emoji))
+            }
+            CommandKind::Comment => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Comment))?;
+                let (from_str, opt_rest) = split_arg(text);
+                let rest = opt_rest.ok_or(ParseCommandError::MissingArg(CommandKind::Comment))?;
+                let (to_str, opt_text) = split_arg(rest);
+                let text = opt_text.ok_or(ParseCommandError::MissingArg(CommandKind::Comment))?;
+                let from: usize = from_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Comment))?;
+                let to: usize = to_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Comment))?;
+                Ok(Command::Comment(from, to, text))
+            }
+            CommandKind::Resolve => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Resolve))?;
+                let comment: u64 = text
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Resolve))?;
+                Ok(Command::Resolve(comment))
+            }
+            CommandKind::Open => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Open))?;
+                let (tab_str, opt_path) = split_arg(text);
+                let path = opt_path.ok_or(ParseCommandError::MissingArg(CommandKind::Open))?;
+                let tab: u32 = tab_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Open))?;
+                Ok(Command::Open(tab, path))
+            }
+            CommandKind::Tab => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Tab))?;
+                let (tab_str, opt_cmd) = split_arg(text);
+                let cmd = opt_cmd.ok_or(ParseCommandError::MissingArg(CommandKind::Tab))?;
+                let tab: u32 = tab_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Tab))?;
+                Ok(Command::Tab(tab, cmd))
+            }
+            CommandKind::Kick => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Kick))?;
+                let target: u64 = text
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Kick))?;
+                Ok(Command::Kick(target))
+            }
+            CommandKind::Whisper => {
+                let text = arg.ok_or(ParseCommandError::MissingArg(CommandKind::Whisper))?;
+                let (reciever_str, opt_text) = split_arg(text);
+                let text = opt_text.ok_or(ParseCommandError::MissingArg(CommandKind::Whisper))?;
+                let reciever: u64 = reciever_str
+                    .parse()
+                    .map_err(|_| ParseCommandError::MissingArg(CommandKind::Whisper))?;
+                Ok(Command::Whisper(reciever, text))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_commands_carry_only_the_verb() {
+        match Command::parse("bogus|foo") {
+            Err(ParseCommandError::UnknownCommand(verb)) => assert_eq!(verb, "bogus"),
+            _ => panic!("Expected an unknown command"),
+        }
+        match Command::parse("bogus") {
+            Err(ParseCommandError::UnknownCommand(verb)) => assert_eq!(verb, "bogus"),
+            _ => panic!("Expected an unknown command"),
+        }
+    }
+
+    #[test]
+    fn long_verbs_are_shortened() {
+        let verb = "a".repeat(1000);
+        match Command::parse(&format!("{}|foo", verb)) {
+            Err(ParseCommandError::UnknownCommand(echoed)) => {
+                assert_eq!(echoed, verb[..MAX_ECHOED_LEN])
+            }
+            _ => panic!("Expected an unknown command"),
+        }
+        // Never in the middle of a character
+        let verb = format!("a{}", "ü".repeat(100));
+        match Command::parse(&verb) {
+            Err(ParseCommandError::UnknownCommand(echoed)) => {
+                assert_eq!(echoed.len(), MAX_ECHOED_LEN - 1);
+                assert!(verb.starts_with(&echoed));
             }
+            _ => panic!("Expected an unknown command"),
         }
     }
 }
@@ -1,14 +1,118 @@
-use crate::lobby::ChannelID;
+use crate::channel::initial_doc;
+use crate::lobby::{ChannelID, Identity};
+use crate::util::constant_time_eq;
+use color_eyre::Report;
+use eyre::{eyre, WrapErr};
+use prosemirror::markdown::{from_markdown, to_markdown, MarkdownNode};
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf, str::Split};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use unicode_normalization::UnicodeNormalization;
+
+/// The directory of the root folder if it has no `save_dir`, relative to the working directory
+pub const DEFAULT_SAVE_DIR: &str = "pads";
 
 /// A folder in the system
 #[derive(Default, Debug, Deserialize)]
 pub struct Folder {
-    /// The directory to save the files to
+    /// The directory to save the files to, see [`DEFAULT_SAVE_DIR`] for the root folder
     #[serde(default)]
     save_dir: Option<PathBuf>,
 
+    /// The maximum number of users in a channel of this folder
+    #[serde(default)]
+    max_users_per_channel: Option<u64>,
+
+    /// The maximum length of a user name in a channel of this folder (in bytes)
+    #[serde(default)]
+    max_name_len: Option<usize>,
+
+    /// Whether users have to choose a name to join a channel of this folder
+    #[serde(default)]
+    require_name: bool,
+
+    /// The maximum size of the text of a document in this folder (in bytes)
+    #[serde(default)]
+    max_doc_bytes: Option<usize>,
+
+    /// The number of versions between two snapshots of a document, no snapshots if not set
+    #[serde(default)]
+    revision_interval: Option<usize>,
+
+    /// The maximum number of snapshots that are kept per document (default: 10)
+    #[serde(default)]
+    max_revisions: Option<usize>,
+
+    /// How long a disconnected user can resume their session (in seconds, default: 60, 0 disables)
+    #[serde(default)]
+    resume_grace: Option<u64>,
+
+    /// The number of recent chat messages that new users receive (default: 100)
+    #[serde(default)]
+    chat_history: Option<usize>,
+
+    /// How long applied steps are collected to be broadcast together (in milliseconds)
+    #[serde(default)]
+    step_coalesce_ms: Option<u64>,
+
+    /// How long an empty channel waits for someone to rejoin before it closes (in seconds)
+    #[serde(default)]
+    channel_linger: Option<u64>,
+
+    /// The maximum number of folders in a path below this one (default: 16)
+    #[serde(default)]
+    max_depth: Option<usize>,
+
+    /// Where the documents in this folder are kept
+    #[serde(default)]
+    storage: Storage,
+
+    /// Whether clients are prevented from creating documents in this folder
+    #[serde(default)]
+    read_only: bool,
+
+    /// How the documents in this folder are written to the store
+    #[serde(default)]
+    format: Format,
+
+    /// What the channels in this folder hold
+    #[serde(default)]
+    content: ContentKind,
+
+    /// The file extension of the documents in this folder (default: depends on the format)
+    #[serde(default)]
+    extension: Option<String>,
+
+    /// The names of the users that may access this folder, everyone if not set
+    #[serde(default)]
+    allow: Option<Vec<String>>,
+
+    /// The names of the users that may not access this folder
+    #[serde(default)]
+    deny: Vec<String>,
+
+    /// The password to access this folder, as `<salt>$<hex>` with the SHA-256 hash of the
+    /// salt followed by the password, e.g. from `printf '%s%s' salt pw | sha256sum`
+    ///
+    /// The hash is checked on every join, so it is not stretched and only keeps the password
+    /// out of the config file. Treat the password as a shared secret for the folder: use a
+    /// long random one and never a password that someone uses elsewhere. Clients send it in
+    /// the `X-Padington-Password` header, or in the `password` query parameter if they can't.
+    #[serde(default)]
+    password_hash: Option<String>,
+
+    /// The markdown file that new documents in this folder start from
+    #[serde(default)]
+    template: Option<PathBuf>,
+
+    /// The parsed template
+    #[serde(skip)]
+    template_doc: Option<MarkdownNode>,
+
     /// The channels that are currently active
     #[serde(skip)]
     channels: HashMap<String, ChannelID>,
@@ -24,61 +128,579 @@ impl From<Option<PathBuf>> for Folder {
     }
 }
 
+/// Where the documents of a folder are kept
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// Documents are loaded from and saved to the `save_dir`
+    #[default]
+    Disk,
+    /// Documents are discarded when the channel closes
+    Memory,
+}
+
+/// What the channels of a folder hold
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    /// A text document
+    #[default]
+    Document,
+    /// A map with markers
+    Map,
+}
+
+/// How the documents of a folder are written to the store
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Documents are saved as markdown
+    #[default]
+    Markdown,
+    /// Documents are saved as the JSON of the node tree, which keeps all attributes
+    Json,
+}
+
+impl Format {
+    /// The default file extension of documents in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Json => "json",
+        }
+    }
+
+    /// Read a document that was saved in this format
+    pub fn parse(self, text: &str) -> Result<MarkdownNode, Report> {
+        match self {
+            Format::Markdown => Ok(from_markdown(text)?),
+            Format::Json => Ok(serde_json::from_str(text)?),
+        }
+    }
+
+    /// Write a document in this format
+    pub fn write(self, doc: &MarkdownNode) -> Result<String, Report> {
+        match self {
+            Format::Markdown => Ok(to_markdown(doc)?),
+            Format::Json => Ok(serde_json::to_string(doc)?),
+        }
+    }
+}
+
 /// The type of file
 pub enum PathValidity<'a, 'b> {
     /// The path is not valid
     Invalid,
     /// The path is a file (shared text document)
+    ///
+    /// The channel for it uses the [`Storage`] and [`ContentKind`] of the folder.
     File(&'b mut Folder, PathBuf, &'a str),
     /// The path is a folder (choose a file)
+    ///
+    /// No channel is opened for it, so nothing is stored.
     Folder(&'b mut Folder, PathBuf),
-    // IDEA: game / map
+}
+
+impl Folder {
+    /// Add a subfolder
+    pub fn with_sub<S: Into<String>>(mut self, name: S, folder: Folder) -> Self {
+        self.sub.insert(name.into(), folder);
+        self
+    }
+
+    /// Set the maximum number of users in a channel of this folder
+    pub fn with_max_users_per_channel(mut self, max_users: Option<u64>) -> Self {
+        self.max_users_per_channel = max_users;
+        self
+    }
+
+    /// Set where the documents in this folder are kept
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Set how the documents in this folder are written to the store
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Limit the text of the documents in this folder to `max` bytes
+    pub fn with_max_doc_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_doc_bytes = max;
+        self
+    }
+
+    /// Set whether users have to choose a name to join a channel of this folder
+    pub fn with_require_name(mut self, require_name: bool) -> Self {
+        self.require_name = require_name;
+        self
+    }
+
+    /// Set the file extension of the documents in this folder
+    pub fn with_extension<S: Into<String>>(mut self, extension: S) -> Self {
+        self.extension = Some(extension.into());
+        self
+    }
+
+    /// Only allow the given users to access this folder
+    pub fn with_allow(mut self, allow: Vec<String>) -> Self {
+        self.allow = Some(allow);
+        self
+    }
+
+    /// Prevent the given users from accessing this folder
+    pub fn with_deny(mut self, deny: Vec<String>) -> Self {
+        self.deny = deny;
+        self
+    }
+}
+
+/// Whether a path segment could refer to something outside of its folder
+fn is_unsafe_segment(segment: &str) -> bool {
+    segment == "." || segment == ".." || segment.contains(|c| c == '\\' || c == '\0')
+}
+
+/// Bring a path into the form that is used to look up folders and documents
+///
+/// Clients may send the same name in composed or decomposed form, so all paths are
+/// normalized to NFC. Folder names are case-sensitive and match the keys in the config,
+/// which should be written in NFC as well. Document names must be their lowercase slug,
+/// so every other spelling of a document is rejected in favour of that slug.
+pub fn normalize_path(path: &str) -> String {
+    path.nfc().collect()
+}
+
+/// Make sure that `dir` is a directory, creating it if there is nothing there yet
+fn ensure_dir(dir: &Path) -> Result<(), Report> {
+    if dir.is_dir() {
+        Ok(())
+    } else if dir.exists() {
+        Err(eyre!("The save_dir {:?} is not a directory", dir))
+    } else {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Could not create the save_dir {:?}", dir))
+    }
+}
+
+/// Split a password hash into the salt and the hex digest, the salt may be empty
+fn split_password_hash(hash: &str) -> (&str, &str) {
+    match hash.rfind('$') {
+        Some(index) => (&hash[..index], &hash[index + 1..]),
+        None => ("", hash),
+    }
+}
+
+/// Whether `password` matches a password hash from the config
+fn password_matches(hash: &str, password: &str) -> bool {
+    let (salt, digest) = split_password_hash(hash);
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    let hex: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    constant_time_eq(hex.as_bytes(), digest.to_ascii_lowercase().as_bytes())
 }
 
 /// Checks the name for validity
 impl Folder {
-    fn check_name_iter<'a, 'b>(
-        &'b mut self,
-        mut iter: Split<'a, char>,
-        curr: &'a str,
-        mut base_dir: PathBuf,
-    ) -> PathValidity<'a, 'b> {
-        if let Some(base) = &self.save_dir {
-            base_dir = base.clone();
-        }
-        match iter.next() {
-            Some(next) => {
-                // if there is a next file name
-                if let Some(sub) = self.sub.get_mut(curr) {
-                    base_dir.push(curr);
-                    sub.check_name_iter(iter, next, base_dir)
-                } else {
-                    PathValidity::Invalid
+    /// Set the directory to save the files to
+    pub fn set_save_dir(&mut self, save_dir: PathBuf) {
+        self.save_dir = Some(save_dir);
+    }
+
+    /// The directory that the documents directly in this folder are saved to, if it were the root
+    pub fn base_dir(&self) -> PathBuf {
+        self.save_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SAVE_DIR))
+    }
+
+    /// The names of the subfolders of this folder
+    pub fn sub_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.sub.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The maximum number of users in a channel of this folder, if any
+    pub fn max_users_per_channel(&self) -> Option<u64> {
+        self.max_users_per_channel
+    }
+
+    /// The maximum length of a user name in a channel of this folder
+    pub fn max_name_len(&self) -> usize {
+        self.max_name_len.unwrap_or(64)
+    }
+
+    /// Whether users have to choose a name to join a channel of this folder
+    pub fn require_name(&self) -> bool {
+        self.require_name
+    }
+
+    /// The maximum size of the text of a document in this folder, if any
+    pub fn max_doc_bytes(&self) -> Option<usize> {
+        self.max_doc_bytes
+    }
+
+    /// The number of versions between two snapshots of a document, if enabled
+    pub fn revision_interval(&self) -> Option<usize> {
+        self.revision_interval.filter(|interval| *interval > 0)
+    }
+
+    /// The maximum number of snapshots that are kept per document
+    pub fn max_revisions(&self) -> usize {
+        self.max_revisions.unwrap_or(10)
+    }
+
+    /// How long a disconnected user can resume their session, if enabled
+    pub fn resume_grace(&self) -> Option<Duration> {
+        match self.resume_grace.unwrap_or(60) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// The number of recent chat messages that are kept in a channel of this folder
+    pub fn chat_history(&self) -> usize {
+        self.chat_history.unwrap_or(100)
+    }
+
+    /// How long applied steps are collected to be broadcast together, if at all
+    pub fn step_coalesce(&self) -> Option<Duration> {
+        self.step_coalesce_ms
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis)
+    }
+
+    /// How long an empty channel in this folder stays open, if at all
+    pub fn channel_linger(&self) -> Option<Duration> {
+        self.channel_linger
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// The maximum number of folders in a path below this one
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(16)
+    }
+
+    /// Where the documents in this folder are kept
+    pub fn storage(&self) -> Storage {
+        self.storage
+    }
+
+    /// Whether clients are prevented from creating documents in this folder
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// How the documents in this folder are written to the store
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// What the channels in this folder hold
+    pub fn content(&self) -> ContentKind {
+        self.content
+    }
+
+    /// The file extension of the documents in this folder
+    pub fn extension(&self) -> &str {
+        self.extension
+            .as_deref()
+            .unwrap_or_else(|| match self.content {
+                ContentKind::Document => self.format.extension(),
+                ContentKind::Map => Format::Json.extension(),
+            })
+    }
+
+    /// The document that new documents in this folder start from
+    pub fn initial_doc(&self) -> MarkdownNode {
+        match &self.template_doc {
+            Some(doc) => doc.clone(),
+            None => initial_doc(),
+        }
+    }
+
+    /// Whether a user may access this folder
+    fn allows(&self, identity: Option<&Identity>) -> bool {
+        let name = identity.map(|identity| identity.name.as_str());
+        if let Some(name) = name {
+            if self.deny.iter().any(|denied| denied == name) {
+                return false;
+            }
+        }
+        match &self.allow {
+            Some(allow) => name.map_or(false, |name| allow.iter().any(|allowed| allowed == name)),
+            None => true,
+        }
+    }
+
+    /// Check whether a user may access a path and every folder on the way to it
+    pub fn check_access(&self, path: &str, identity: Option<&Identity>) -> bool {
+        let mut folder = self;
+        let mut segments = path.split('/').skip(1);
+        loop {
+            if !folder.allows(identity) {
+                return false;
+            }
+            match segments.next().and_then(|segment| folder.sub.get(segment)) {
+                Some(sub) => folder = sub,
+                None => return true,
+            }
+        }
+    }
+
+    /// Check a password against every folder on the way to a path that has one
+    pub fn check_password(&self, path: &str, password: Option<&str>) -> bool {
+        let mut folder = self;
+        let mut segments = path.split('/').skip(1);
+        loop {
+            if let Some(hash) = &folder.password_hash {
+                if !password.map_or(false, |password| password_matches(hash, password)) {
+                    return false;
                 }
             }
-            None if curr.is_empty() => {
-                // curr is the file name
-                PathValidity::Folder(self, base_dir)
+            match segments.next().and_then(|segment| folder.sub.get(segment)) {
+                Some(sub) => folder = sub,
+                None => return true,
+            }
+        }
+    }
+
+    /// Check that the templates of this folder and all subfolders exist, and create
+    /// missing save directories
+    pub fn validate(&self) -> Result<(), Report> {
+        if self.storage == Storage::Disk {
+            ensure_dir(&self.base_dir())?;
+        }
+        self.validate_sub()
+    }
+
+    fn validate_sub(&self) -> Result<(), Report> {
+        if self.max_users_per_channel == Some(0) {
+            return Err(eyre!("max_users_per_channel must be at least 1"));
+        }
+        if let Some(dir) = &self.save_dir {
+            if self.storage == Storage::Disk {
+                ensure_dir(dir)?;
+            }
+        }
+        if let Some(path) = &self.template {
+            if !path.is_file() {
+                return Err(eyre!("The template {:?} is not a file", path));
+            }
+        }
+        if let Some(hash) = &self.password_hash {
+            let (_, digest) = split_password_hash(hash);
+            if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(eyre!("The password hash is not a hex encoded SHA-256 hash"));
             }
-            None => PathValidity::File(self, base_dir, curr),
         }
+        for sub in self.sub.values() {
+            sub.validate_sub()?;
+        }
+        Ok(())
+    }
+
+    /// Load and parse the templates of this folder and all subfolders
+    pub fn load_templates(&mut self) -> Result<(), Report> {
+        if let Some(path) = &self.template {
+            let md = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("Could not read template {:?}", path))?;
+            let doc = from_markdown(&md)
+                .wrap_err_with(|| format!("Could not parse template {:?}", path))?;
+            self.template_doc = Some(doc);
+        }
+        for sub in self.sub.values_mut() {
+            sub.load_templates()?;
+        }
+        Ok(())
     }
 
     /// Check a provided path against this folder
-    pub fn check_name<'a, 'b>(
-        &'b mut self,
-        path: &'a str,
-        base_dir: PathBuf,
-    ) -> PathValidity<'a, 'b> {
+    ///
+    /// Paths with more than [`Folder::max_depth`] folders are invalid.
+    pub fn check_name<'a, 'b>(&'b mut self, path: &'a str) -> PathValidity<'a, 'b> {
+        let max_depth = self.max_depth();
+        let mut base_dir = self.base_dir();
         let mut iter = path.split('/');
-        if let Some("") = iter.next() {
-            if let Some(curr) = iter.next() {
-                self.check_name_iter(iter, curr, base_dir)
-            } else {
-                PathValidity::Invalid
+        if iter.next() != Some("") {
+            return PathValidity::Invalid;
+        }
+        let mut curr = match iter.next() {
+            Some(curr) => curr,
+            None => return PathValidity::Invalid,
+        };
+        let mut folder = self;
+        let mut depth = 0;
+        loop {
+            if let Some(base) = &folder.save_dir {
+                base_dir = base.clone();
             }
-        } else {
-            PathValidity::Invalid
+            if is_unsafe_segment(curr) {
+                return PathValidity::Invalid;
+            }
+            match iter.next() {
+                Some(_) if curr.is_empty() => return PathValidity::Invalid,
+                Some(_) if depth >= max_depth => return PathValidity::Invalid,
+                Some(next) => {
+                    // if there is a next file name
+                    match folder.sub.get_mut(curr) {
+                        Some(sub) => {
+                            base_dir.push(curr);
+                            folder = sub;
+                            curr = next;
+                            depth += 1;
+                        }
+                        None => return PathValidity::Invalid,
+                    }
+                }
+                None if curr.is_empty() => {
+                    // curr is the file name
+                    return PathValidity::Folder(folder, base_dir);
+                }
+                None => return PathValidity::File(folder, base_dir, curr),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composed_and_decomposed_paths_are_the_same() {
+        let composed = "/caf\u{e9}/doc";
+        let decomposed = "/cafe\u{301}/doc";
+        assert_ne!(composed, decomposed);
+        assert_eq!(normalize_path(decomposed), composed);
+        assert_eq!(normalize_path(composed), composed);
+
+        let mut folder = Folder::default().with_sub("caf\u{e9}", Folder::default());
+        let path = normalize_path(decomposed);
+        match folder.check_name(&path) {
+            PathValidity::File(_, dir, file) => {
+                assert_eq!(file, "doc");
+                assert!(dir.ends_with("caf\u{e9}"));
+            }
+            _ => panic!("{:?} is not a document", path),
+        }
+    }
+
+    #[test]
+    fn paths_can_not_leave_the_save_dir() {
+        let mut folder = Folder::default().with_sub("sub", Folder::default());
+        let paths = [
+            "/../../etc/passwd",
+            "/sub/../../etc/passwd",
+            "/sub/./doc",
+            "/..",
+            "/.",
+            "/sub/..",
+            "//doc",
+            "/sub//doc",
+            "/..\\..\\etc\\passwd",
+            "/sub\\doc",
+            "/doc\0",
+            "doc",
+            "",
+        ];
+        for path in &paths {
+            assert!(
+                matches!(folder.check_name(path), PathValidity::Invalid),
+                "{:?} is valid",
+                path
+            );
+        }
+        assert!(matches!(
+            folder.check_name("/sub/doc"),
+            PathValidity::File(..)
+        ));
+        assert!(matches!(
+            folder.check_name("/sub/"),
+            PathValidity::Folder(..)
+        ));
+    }
+
+    fn user(name: &str) -> Identity {
+        Identity {
+            name: name.to_owned(),
+            role: Default::default(),
+        }
+    }
+
+    /// An open root with a team folder, which has a folder that is secret from bob
+    fn team_folders() -> Folder {
+        let secret = Folder::default().with_deny(vec![String::from("bob")]);
+        let team = Folder::default()
+            .with_allow(vec![String::from("alice"), String::from("bob")])
+            .with_sub("secret", secret);
+        Folder::default().with_sub("team", team)
+    }
+
+    #[test]
+    fn access_is_checked_on_the_way_down() {
+        let folder = team_folders();
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+        let may = |path: &str, identity: Option<&Identity>| folder.check_access(path, identity);
+
+        for identity in &[None, Some(&alice), Some(&bob), Some(&carol)] {
+            assert!(may("/doc", *identity));
+        }
+        assert!(may("/team/doc", Some(&alice)));
+        assert!(may("/team/doc", Some(&bob)));
+        assert!(!may("/team/doc", Some(&carol)));
+        assert!(!may("/team/doc", None));
+        assert!(may("/team/secret/doc", Some(&alice)));
+        assert!(!may("/team/secret/doc", Some(&bob)));
+        assert!(!may("/team/secret/doc", Some(&carol)));
+    }
+
+    #[test]
+    fn paths_deeper_than_the_limit_are_invalid() {
+        let mut nested = Folder::default();
+        for _ in 0..3 {
+            nested = Folder::default().with_sub("a", nested);
         }
+        nested.max_depth = Some(2);
+
+        assert!(matches!(
+            nested.check_name("/a/doc"),
+            PathValidity::File(..)
+        ));
+        assert!(matches!(
+            nested.check_name("/a/a/doc"),
+            PathValidity::File(..)
+        ));
+        assert!(matches!(
+            nested.check_name("/a/a/"),
+            PathValidity::Folder(..)
+        ));
+        assert!(matches!(
+            nested.check_name("/a/a/a/doc"),
+            PathValidity::Invalid
+        ));
+        assert!(matches!(
+            nested.check_name("/a/a/a/"),
+            PathValidity::Invalid
+        ));
+        // Paths that are far too deep fail the same way, without walking all of them
+        let deep = format!("{}doc", "/a".repeat(10_000));
+        assert!(matches!(nested.check_name(&deep), PathValidity::Invalid));
+    }
+
+    #[test]
+    fn folder_names_are_case_sensitive() {
+        let mut folder = Folder::default().with_sub("caf\u{e9}", Folder::default());
+        let path = normalize_path("/CAFE\u{301}/doc");
+        assert_eq!(path, "/CAF\u{c9}/doc");
+        assert!(matches!(folder.check_name(&path), PathValidity::Invalid));
     }
 }
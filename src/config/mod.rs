@@ -1,38 +1,119 @@
 //! # Server configuration
 
 mod folder;
+mod sec1;
 
-pub use folder::{Folder, PathValidity};
+pub use folder::{normalize_path, ContentKind, Folder, Format, PathValidity, Storage};
 
+use crate::lobby::{Identity, Role};
+use crate::store::{DocStore, FsDocStore};
+use crate::webhook::Webhook;
 use color_eyre::Report;
 use color_eyre::Result;
 use eyre::{eyre, WrapErr};
 use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::fs::read_to_string;
 use tracing::instrument;
 use tungstenite::http::Uri;
 
-use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use tokio_rustls::rustls::{Certificate, PrivateKey};
 
 /// The commandline flags for the server
 #[derive(Debug, StructOpt)]
 pub struct Flags {
-    /// Which config file to use
-    #[structopt(long = "cfg", short = "c")]
+    /// Which config file to use (`.toml`, `.json`, `.yaml` or `.yml`)
+    #[structopt(long = "cfg", short = "c", env = "PADINGTON_CONFIG")]
     pub cfg: Option<PathBuf>,
-    /// Which port to use (if cfg isn't present)
-    #[structopt(long = "port", short = "p")]
+    /// Which addresses to bind to, replaces the ones from the config file
+    #[structopt(
+        long = "addr",
+        short = "a",
+        env = "PADINGTON_ADDR",
+        use_delimiter = true
+    )]
+    pub addr: Vec<String>,
+    /// Which port to use for every address (all interfaces if there is no other address)
+    #[structopt(long = "port", short = "p", env = "PADINGTON_PORT")]
     pub port: Option<u16>,
-    /// Which base folder to use (if cfg isn't present)
-    #[structopt(long = "base-folder", short = "b")]
+    /// Which base folder to use, replaces the `save_dir` of the root folder
+    #[structopt(long = "base-folder", short = "b", env = "PADINGTON_SAVE_DIR")]
     pub base_folder: Option<PathBuf>,
+    /// Which directory to write log files to, in addition to stdout
+    #[structopt(long = "log-dir", env = "PADINGTON_LOG_DIR")]
+    pub log_dir: Option<PathBuf>,
+    /// How often to start a new log file (`minutely`, `hourly`, `daily` or `never`)
+    #[structopt(
+        long = "log-rotation",
+        env = "PADINGTON_LOG_ROTATION",
+        default_value = "daily"
+    )]
+    pub log_rotation: LogRotation,
+    /// How to write log lines (`pretty` to read them, `json` for a log aggregator)
+    #[structopt(
+        long = "log-format",
+        env = "PADINGTON_LOG_FORMAT",
+        default_value = "pretty"
+    )]
+    pub log_format: LogFormat,
+}
+
+/// How log lines are written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// One JSON object per event, with the fields of the event and its spans
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(eyre!("Unknown log format {:?}", s)),
+        }
+    }
+}
+
+/// How often a new log file is started
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogRotation {
+    /// Every minute
+    Minutely,
+    /// Every hour
+    Hourly,
+    /// Every day
+    Daily,
+    /// Never, everything goes to the same file
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minutely" => Ok(Self::Minutely),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            "never" => Ok(Self::Never),
+            _ => Err(eyre!("Unknown log rotation {:?}", s)),
+        }
+    }
 }
 
 /// The type of connection we want
@@ -46,61 +127,320 @@ pub enum ConnSetup {
         /// The loaded certificates
         certs: Vec<Certificate>,
     },
+    /// A Unix domain socket (behind a web-server on the same host, never with TLS)
+    #[cfg(unix)]
+    Unix {
+        /// The path of the socket file
+        path: PathBuf,
+    },
 }
 
 /// The setup that we are actually using
 pub struct Setup {
-    /// The address to bind to
-    pub addr: String,
+    /// The addresses to bind to
+    pub addrs: Vec<String>,
     /// The kind of connection we use
     pub conn: ConnSetup,
     /// The folder we use
     pub folder: Folder,
+    /// The options for client connections
+    pub client: ClientConfig,
+    /// The storage for documents
+    pub store: Arc<dyn DocStore>,
+    /// The authentication options, `None` if anyone may connect
+    pub auth: Option<Arc<AuthConfig>>,
+    /// The maximum number of simultaneous connections
+    pub max_connections: Option<usize>,
+    /// The maximum number of simultaneous connections from a single IP address
+    ///
+    /// Connections on a Unix socket have no address and only count toward `max_connections`.
+    pub max_connections_per_ip: Option<usize>,
+    /// How long an IP address is blocked after repeatedly exceeding its limit
+    pub ip_penalty: Option<Duration>,
+    /// Whether the metrics are served on `/metrics`
+    pub metrics: bool,
+    /// The file to write the audit log to, if any
+    pub audit_log: Option<PathBuf>,
+    /// The endpoint to notify when a document changes, if any
+    pub webhook: Option<Webhook>,
+    /// The capacities of the queues between the tasks
+    pub buffers: BufferConfig,
+}
+
+impl Default for Setup {
+    fn default() -> Self {
+        Self {
+            addrs: vec![String::from("127.0.0.1:9002")],
+            conn: ConnSetup::Basic,
+            folder: Folder::default(),
+            client: ClientConfig::default(),
+            store: Arc::new(FsDocStore),
+            auth: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            ip_penalty: None,
+            metrics: false,
+            audit_log: None,
+            webhook: None,
+            buffers: BufferConfig::default(),
+        }
+    }
+}
+
+impl Setup {
+    /// Set the address to bind to
+    pub fn with_addr<S: Into<String>>(mut self, addr: S) -> Self {
+        self.addrs = vec![addr.into()];
+        self
+    }
+
+    /// Set multiple addresses to bind to
+    pub fn with_addrs(mut self, addrs: Vec<String>) -> Self {
+        self.addrs = addrs;
+        self
+    }
+
+    /// Set the kind of connection
+    pub fn with_conn(mut self, conn: ConnSetup) -> Self {
+        self.conn = conn;
+        self
+    }
+
+    /// Use TLS with certificates and keys that are already loaded
+    pub fn with_tls(self, certs: Vec<Certificate>, keys: Vec<PrivateKey>) -> Self {
+        self.with_conn(ConnSetup::Tls { keys, certs })
+    }
+
+    /// Set the root folder
+    pub fn with_folder(mut self, folder: Folder) -> Self {
+        self.folder = folder;
+        self
+    }
+
+    /// Set the options for client connections
+    pub fn with_client(mut self, client: ClientConfig) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Set the storage for documents
+    pub fn with_store(mut self, store: Arc<dyn DocStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Require clients to authenticate
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Set the maximum number of simultaneous connections
+    pub fn with_max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Set the maximum number of simultaneous connections from a single IP address
+    pub fn with_max_connections_per_ip(
+        mut self,
+        max_connections: Option<usize>,
+        penalty: Option<Duration>,
+    ) -> Self {
+        self.max_connections_per_ip = max_connections;
+        self.ip_penalty = penalty;
+        self
+    }
+
+    /// Set whether the metrics are served on `/metrics`
+    pub fn with_metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Set the file to write the audit log to
+    pub fn with_audit_log(mut self, audit_log: Option<PathBuf>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Set the endpoint to notify when a document changes
+    pub fn with_webhook(mut self, webhook: Option<Webhook>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
+    /// Set the capacities of the queues between the tasks
+    pub fn with_buffers(mut self, buffers: BufferConfig) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Check for problems that would otherwise only show up once the server is running
+    pub fn validate(&self) -> Result<()> {
+        match &self.conn {
+            ConnSetup::Basic | ConnSetup::Tls { .. } => {
+                for addr in &self.addrs {
+                    let mut resolved = addr
+                        .to_socket_addrs()
+                        .wrap_err_with(|| format!("Invalid address {:?}", addr))?;
+                    if resolved.next().is_none() {
+                        return Err(eyre!("No address for {:?}", addr));
+                    }
+                }
+            }
+            #[cfg(unix)]
+            ConnSetup::Unix { path } => {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                if let Some(dir) = dir.filter(|dir| !dir.is_dir()) {
+                    return Err(eyre!(
+                        "The directory {:?} of the socket does not exist",
+                        dir
+                    ));
+                }
+            }
+        }
+        if let ConnSetup::Tls { keys, certs } = &self.conn {
+            if certs.is_empty() {
+                return Err(eyre!("The certificate file contains no certificates"));
+            }
+            if keys.is_empty() {
+                return Err(eyre!("The key file contains no keys"));
+            }
+        }
+        let client = &self.client;
+        if client.ping_interval == 0 || client.pong_timeout == 0 {
+            return Err(eyre!(
+                "The ping interval and the pong timeout must not be 0"
+            ));
+        }
+        if let Some(webhook) = &self.webhook {
+            if webhook.url().scheme_str() != Some("http") {
+                return Err(eyre!(
+                    "Only http:// webhook URLs are supported, not {}",
+                    webhook.url()
+                ));
+            }
+        }
+        let buffers = &self.buffers;
+        let capacities = [
+            buffers.lobby,
+            buffers.requests,
+            buffers.broadcasts,
+            client.signal_buffer,
+        ];
+        if capacities.contains(&0) {
+            return Err(eyre!("Buffer capacities must be at least 1"));
+        }
+        self.folder.validate()
+    }
+}
+
+/// Parse a config file in the format that matches its extension, TOML if there is none
+fn parse_config(path: &Path, contents: &str) -> Result<Config> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        None | Some("toml") => Ok(toml::from_str(contents)?),
+        Some("json") => Ok(serde_json::from_str(contents)?),
+        Some("yaml") | Some("yml") => Ok(serde_yaml::from_str(contents)?),
+        Some(ext) => Err(eyre!(
+            "Unsupported config format {:?} (use toml, json, yaml or yml)",
+            ext
+        )),
+    }
+}
+
+/// Load the setup from a config file
+async fn load_file(path: &Path) -> Result<Setup> {
+    let cfg_string: String = read_to_string(path)
+        .await
+        .wrap_err("Could not read config file")?;
+    let config = parse_config(path, &cfg_string).wrap_err("Could not parse config file")?;
+    let webhook = match &config.webhook_url {
+        Some(url) => {
+            let url = url.parse::<Uri>().wrap_err("Invalid webhook URL")?;
+            let interval = Duration::from_secs(config.webhook_interval);
+            Some(Webhook::new(url, interval))
+        }
+        None => None,
+    };
+    let mut setup = Setup::default()
+        .with_addrs(config.addr.iter().map(Uri::to_string).collect())
+        .with_folder(config.folder)
+        .with_client(config.client)
+        .with_max_connections(config.max_connections)
+        .with_max_connections_per_ip(
+            config.max_connections_per_ip,
+            config.ip_penalty.map(Duration::from_secs),
+        )
+        .with_metrics(config.metrics)
+        .with_audit_log(config.audit_log)
+        .with_webhook(webhook)
+        .with_buffers(config.buffers);
+    if let Some(auth) = config.auth {
+        setup = setup.with_auth(auth);
+    }
+    if let Some(path) = config.unix_socket {
+        if config.tls.as_ref().map_or(false, |tls| tls.enabled) {
+            return Err(eyre!("TLS and Unix sockets are mutually exclusive"));
+        }
+        #[cfg(unix)]
+        return Ok(setup.with_conn(ConnSetup::Unix { path }));
+        #[cfg(not(unix))]
+        return Err(eyre!(
+            "Unix sockets are not supported, can't listen on {:?}",
+            path
+        ));
+    }
+    if let Some(cfg_tls) = config.tls {
+        if cfg_tls.enabled {
+            let certs = cfg_tls
+                .load_certs()
+                .wrap_err("Could not load certificate file")?;
+            let keys = cfg_tls.load_keys().wrap_err("Could not load key file")?;
+            return Ok(setup.with_tls(certs, keys));
+        }
+    }
+    Ok(setup)
+}
+
+/// Replace the port of an address like `host:port`
+fn with_port(addr: &str, port: u16) -> String {
+    let host = match addr.rfind(':') {
+        Some(i) if !addr[i..].contains(']') => &addr[..i],
+        _ => addr,
+    };
+    format!("{}:{}", host, port)
 }
 
 impl Flags {
     #[instrument]
-    /// Load the configuration from a file
+    /// Load the configuration from a file, then apply the flags and environment variables
+    ///
+    /// Flags take precedence over environment variables, which take precedence over the
+    /// config file, which takes precedence over the defaults.
     pub async fn load_cfg(&self) -> Result<Setup, Report> {
-        if let Some(cfg) = &self.cfg {
-            let cfg_string: String = read_to_string(cfg)
-                .await
-                .wrap_err("Could not read config file")?;
-            let config: Config =
-                toml::from_str(&cfg_string).wrap_err("Could not parse config file")?;
-
-            let addr = config.addr;
-            if let Some(cfg_tls) = config.tls {
-                if cfg_tls.enabled {
-                    let certs = cfg_tls
-                        .load_certs()
-                        .wrap_err("Could not load certificate file")?;
-                    let keys = cfg_tls.load_keys().wrap_err("Could not load key file")?;
-                    return Ok(Setup {
-                        addr: addr.to_string(),
-                        conn: ConnSetup::Tls { certs, keys },
-                        folder: config.folder,
-                    });
-                }
-            }
-            Ok(Setup {
-                addr: addr.to_string(),
-                conn: ConnSetup::Basic,
-                folder: config.folder,
-            })
-        } else if let Some(port) = self.port {
-            Ok(Setup {
-                addr: format!("0.0.0.0:{}", port),
-                conn: ConnSetup::Basic,
-                folder: Folder::from(self.base_folder.clone()),
-            })
-        } else {
-            Ok(Setup {
-                addr: String::from("127.0.0.1:9002"),
-                conn: ConnSetup::Basic,
-                folder: Folder::from(self.base_folder.clone()),
-            })
+        let mut setup = match &self.cfg {
+            Some(path) => load_file(path).await?,
+            None => Setup::default(),
+        };
+        if !self.addr.is_empty() {
+            setup.addrs = self.addr.clone();
         }
+        if let Some(port) = self.port {
+            setup.addrs = if self.cfg.is_none() && self.addr.is_empty() {
+                vec![format!("0.0.0.0:{}", port)]
+            } else {
+                setup
+                    .addrs
+                    .iter()
+                    .map(|addr| with_port(addr, port))
+                    .collect()
+            };
+        }
+        if let Some(save_dir) = &self.base_folder {
+            setup.folder.set_save_dir(save_dir.clone());
+        }
+        Ok(setup)
     }
 }
 
@@ -125,35 +465,310 @@ impl Tls {
     }
 
     #[instrument]
-    /// Load the TLS keys
+    /// Load the TLS keys, in PKCS#8, RSA (PKCS#1) or EC (SEC1) format
     pub fn load_keys(&self) -> Result<Vec<PrivateKey>> {
-        let path = &self.key;
-        let file = File::open(path)?;
-        pkcs8_private_keys(&mut BufReader::new(file)).map_err(|()| eyre!("Invalid key"))
+        let pem = std::fs::read(&self.key)?;
+        let keys = pkcs8_private_keys(&mut pem.as_slice()).unwrap_or_default();
+        if !keys.is_empty() {
+            return Ok(keys);
+        }
+        let keys = rsa_private_keys(&mut pem.as_slice()).unwrap_or_default();
+        if !keys.is_empty() {
+            return Ok(keys);
+        }
+        // rustls only reads EC keys wrapped in PKCS#8, so they are wrapped here
+        let keys = sec1::ec_private_keys(&String::from_utf8_lossy(&pem))?;
+        if !keys.is_empty() {
+            return Ok(keys);
+        }
+        Err(eyre!("No PKCS#8, RSA or EC private key found"))
     }
 }
 
 /// A configuration for the system
 #[derive(Deserialize)]
 pub struct Config {
-    /// The address to bind the service to
-    #[serde(deserialize_with = "deserialize_from_str")]
-    pub addr: Uri,
+    /// The address (or a list of addresses) to bind the service to
+    #[serde(
+        deserialize_with = "deserialize_list_from_str",
+        default = "default_addr"
+    )]
+    pub addr: Vec<Uri>,
+    /// The Unix domain socket to listen on instead of `addr`, can't be used with TLS
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
     /// The TLS options
     pub tls: Option<Tls>,
     /// The folder options
     #[serde(default)]
     pub folder: Folder,
+    /// The client connection options
+    #[serde(default)]
+    pub client: ClientConfig,
+    /// The capacities of the queues between the tasks
+    #[serde(default)]
+    pub buffers: BufferConfig,
+    /// The authentication options
+    pub auth: Option<AuthConfig>,
+    /// The maximum number of simultaneous connections
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// The maximum number of simultaneous connections from a single IP address
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    /// How long an IP address is blocked after repeatedly exceeding its limit (in seconds)
+    #[serde(default)]
+    pub ip_penalty: Option<u64>,
+    /// Whether the metrics are served on `/metrics`
+    #[serde(default)]
+    pub metrics: bool,
+    /// The file to write the audit log to, if any
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// The `http://` URL to post to when a document changes, if any
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// The minimum time between two webhook calls for the same document (in seconds)
+    #[serde(default = "default_webhook_interval")]
+    pub webhook_interval: u64,
+}
+
+fn default_addr() -> Vec<Uri> {
+    vec![Uri::from_static("127.0.0.1:9002")]
+}
+
+fn default_webhook_interval() -> u64 {
+    5
+}
+
+/// The authentication options
+///
+/// Clients pass their token in the `token` query parameter or as an
+/// `Authorization: Bearer <token>` header.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    /// The accepted tokens and the names of the users they belong to
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+    /// The tokens of users that may not edit documents
+    #[serde(default)]
+    pub viewer_tokens: HashMap<String, String>,
+    /// The tokens of users that may moderate channels
+    #[serde(default)]
+    pub admin_tokens: HashMap<String, String>,
+}
+
+impl AuthConfig {
+    /// Check a token, returns the identity of the user it belongs to
+    pub fn authenticate(&self, token: &str) -> Option<Identity> {
+        let lookup = |tokens: &HashMap<String, String>, role| {
+            tokens.get(token).map(|name| Identity {
+                name: name.clone(),
+                role,
+            })
+        };
+        lookup(&self.admin_tokens, Role::Admin)
+            .or_else(|| lookup(&self.tokens, Role::Editor))
+            .or_else(|| lookup(&self.viewer_tokens, Role::Viewer))
+    }
+}
+
+/// The options for client connections
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// The time between two pings to the client (in milliseconds, must not be 0)
+    pub ping_interval: u64,
+    /// The time after the last pong until a client is considered dead (in milliseconds, must not be 0)
+    pub pong_timeout: u64,
+    /// The maximum length of a chat message or a whisper (in bytes)
+    pub max_chat_len: usize,
+    /// The maximum length of the JSON of a `steps`, `update` or `webrtc` command (in bytes)
+    pub max_payload_len: usize,
+    /// The maximum number of steps in a single `steps` command
+    pub max_steps: usize,
+    /// The number of commands for its channel a client may send per second
+    ///
+    /// Only leaving the channel is never limited.
+    pub rate_limit: f64,
+    /// The number of commands for its channel a client may send at once
+    pub rate_burst: u32,
+    /// The number of rate limited messages in a row until a client is disconnected
+    pub max_rate_violations: Option<u32>,
+    /// The time without messages from a client until it is disconnected (in milliseconds, 0 = never)
+    pub idle_timeout: u64,
+    /// The origins that may open a connection (any origin if empty)
+    pub allowed_origins: Vec<String>,
+    /// The number of signals from the channel that may wait to be sent to the client
+    pub signal_buffer: usize,
+    /// Whether clients are told the round-trip time of each ping as `latency|<micros>`
+    pub report_latency: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 1000,
+            pong_timeout: 10000,
+            max_chat_len: 4096,
+            max_payload_len: 1 << 20,
+            max_steps: 1000,
+            rate_limit: 20.0,
+            rate_burst: 50,
+            max_rate_violations: None,
+            idle_timeout: 0,
+            allowed_origins: Vec::new(),
+            signal_buffer: 20,
+            report_latency: false,
+        }
+    }
+}
+
+/// The capacities of the queues between the tasks
+///
+/// Larger queues use more memory, but tolerate longer bursts. When the broadcast queue of a
+/// channel overflows, slow clients miss messages and have to resync. When any other queue is
+/// full, the senders wait until there is room again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    /// The number of requests from clients that may wait for the lobby
+    pub lobby: usize,
+    /// The number of requests from clients that may wait for a channel
+    pub requests: usize,
+    /// The number of broadcasts that a channel keeps for clients that are behind
+    pub broadcasts: usize,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            lobby: 100,
+            requests: 100,
+            broadcasts: 100,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// The time between two pings to the client
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_millis(self.ping_interval)
+    }
+
+    /// The time after the last pong until a client is considered dead
+    pub fn pong_timeout(&self) -> Duration {
+        Duration::from_millis(self.pong_timeout)
+    }
+
+    /// The time without messages from a client until it is disconnected, if enabled
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+}
+
+/// A single value or a list of values
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
 }
 
 // You can use this deserializer for any type that implements FromStr
-// and the FromStr::Err implements Display
-fn deserialize_from_str<'de, S, D>(deserializer: D) -> Result<S, D::Error>
+// and the FromStr::Err implements Display. A single string is read as
+// a list with one element.
+fn deserialize_list_from_str<'de, S, D>(deserializer: D) -> Result<Vec<S>, D::Error>
 where
     S: FromStr,      // Required for S::from_str...
     S::Err: Display, // Required for .map_err(de::Error::custom)
     D: Deserializer<'de>,
 {
-    let s: String = Deserialize::deserialize(deserializer)?;
-    S::from_str(&s).map_err(de::Error::custom)
+    let list = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(list) => list,
+    };
+    if list.is_empty() {
+        return Err(de::Error::custom("expected at least one value"));
+    }
+    list.iter()
+        .map(|s| S::from_str(s).map_err(de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_rustls::rustls::sign::any_supported_type;
+
+    /// Load the sample key `name` and check that rustls can sign with it
+    fn load_sample_key(name: &str) {
+        let tls = Tls {
+            enabled: true,
+            cert: PathBuf::new(),
+            key: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/keys")
+                .join(name),
+        };
+        let keys = tls.load_keys().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(any_supported_type(&keys[0]).is_ok(), "{} is unusable", name);
+    }
+
+    /// Parse the flags like they were given after the name of the binary
+    fn flags(args: &[&str]) -> Flags {
+        let args = std::iter::once("padington").chain(args.iter().copied());
+        Flags::from_iter_safe(args).unwrap()
+    }
+
+    #[tokio::test]
+    async fn flags_win_over_the_environment_and_the_file() -> Result<()> {
+        let name = format!("padington-{}.toml", crate::util::random_token());
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, "addr = \"127.0.0.1:8080\"\n")?;
+        let cfg = path.to_str().unwrap();
+        std::env::remove_var("PADINGTON_ADDR");
+        std::env::remove_var("PADINGTON_PORT");
+
+        let setup = flags(&["--cfg", cfg]).load_cfg().await?;
+        assert_eq!(setup.addrs, vec!["127.0.0.1:8080"]);
+
+        std::env::set_var("PADINGTON_PORT", "9000");
+        let setup = flags(&["--cfg", cfg]).load_cfg().await?;
+        assert_eq!(setup.addrs, vec!["127.0.0.1:9000"]);
+        let setup = flags(&["--cfg", cfg, "--port", "9001"]).load_cfg().await?;
+        assert_eq!(setup.addrs, vec!["127.0.0.1:9001"]);
+        std::env::remove_var("PADINGTON_PORT");
+
+        std::env::set_var("PADINGTON_ADDR", "10.0.0.1:80");
+        let setup = flags(&["--cfg", cfg]).load_cfg().await?;
+        assert_eq!(setup.addrs, vec!["10.0.0.1:80"]);
+        let setup = flags(&["--cfg", cfg, "--addr", "10.0.0.2:80"])
+            .load_cfg()
+            .await?;
+        assert_eq!(setup.addrs, vec!["10.0.0.2:80"]);
+        std::env::remove_var("PADINGTON_ADDR");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn pkcs8_keys_are_loaded() {
+        load_sample_key("pkcs8.pem");
+    }
+
+    #[test]
+    fn rsa_keys_are_loaded() {
+        load_sample_key("rsa.pem");
+    }
+
+    #[test]
+    fn sec1_keys_are_loaded() {
+        load_sample_key("ec.pem");
+        load_sample_key("ec-p384.pem");
+    }
 }
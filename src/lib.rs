@@ -0,0 +1,29 @@
+//! # Server for the `padington` protocol
+#![warn(missing_docs)]
+
+pub mod audit;
+pub mod channel;
+pub mod client;
+pub mod command;
+pub mod config;
+pub mod lobby;
+pub mod metrics;
+pub mod search;
+pub mod server;
+pub mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transport;
+pub mod util;
+pub mod webhook;
+
+#[macro_use]
+extern crate derive_new;
+
+pub use server::{Server, ShutdownHandle};
+pub use transport::Transport;
+
+use util::http::Rewind;
+
+/// The stream of a client connection, starting with the request head that was already read
+pub type ClientStream = Rewind<Transport>;
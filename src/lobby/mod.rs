@@ -1,14 +1,29 @@
 //! # Client distribution
 //!
 //! This module contains the methods to distribute incoming clients into appropriate
-//! channels. The `LobbyServer` responds to `JoinRequest`s and spins up new channels when
+//! channels. The `LobbyServer` responds to `LobbyRequest`s and spins up new channels when
 //! necessary. It also keeps track of which channels are currently active.
+//!
+//! ## Document names
+//!
+//! A document is stored under the slug of its name, e.g. `my-doc.md` for "My Doc", and
+//! that slug is the only name it can be reached by. Joining or reading any other spelling
+//! that maps to the same slug, like `My Doc`, `my_doc` or `MY-DOC`, is rejected with
+//! `NotCanonical` and the slug to use instead, so that two names never silently share
+//! a file. Creating a document slugs the chosen name, fails with `Exists` if that slug is
+//! taken and keeps the name as the title of the document.
 mod server;
 
 pub use server::{ChannelID, LobbyServer, UserID};
 
-use crate::channel::{Broadcast, Request};
+use crate::channel::{Broadcast, DocMeta, Request};
+use crate::command::ErrorCode;
+use crate::search::{Query, SearchHit};
+use crate::util::Secret;
 use displaydoc::Display;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, oneshot};
 
@@ -17,17 +32,138 @@ use tokio::sync::{broadcast, mpsc, oneshot};
 pub struct JoinResponse {
     /// The ID that this client is assigned.
     pub id: UserID,
+    /// The path of the channel, after normalization
+    pub path: String,
+    /// The title of the document, if it differs from its file name
+    pub title: Option<String>,
     /// The sender to pass requests into the channel
     pub msg_tx: mpsc::Sender<Request>,
     /// The receiver to listen to events in the channel
     pub bct_rx: broadcast::Receiver<Broadcast>,
 }
 
+/// What a user is allowed to do in a channel
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// The user may edit the document
+    #[default]
+    Editor,
+    /// The user may only watch the document
+    Viewer,
+    /// The user may edit the document and kick other users
+    Admin,
+}
+
+/// An authenticated user
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    /// The name that belongs to the token of the user
+    pub name: String,
+    /// What the user is allowed to do
+    pub role: Role,
+}
+
+/// The contents of a folder that a client can navigate to
+#[derive(Debug, Serialize)]
+pub struct FolderListing {
+    /// The names of the subfolders
+    pub folders: Vec<String>,
+    /// The names of the documents
+    pub documents: Vec<String>,
+    /// The metadata of the documents that have any, by name
+    pub meta: BTreeMap<String, DocMeta>,
+}
+
+/// A request to the lobby server
+#[derive(Debug)]
+pub enum LobbyRequest {
+    /// Join a channel
+    Join(JoinRequest),
+    /// Create a new document
+    Create(CreateRequest),
+    /// Read the current content of a document
+    Read(ReadRequest),
+    /// Search the documents of a folder
+    Search(SearchRequest),
+    /// List the active channels
+    Channels(oneshot::Sender<Vec<ChannelInfo>>),
+    /// Send an announcement to active channels
+    Announce(AnnounceRequest),
+}
+
+/// Request to send an announcement to the users of a channel or all channels
+#[derive(Debug)]
+pub struct AnnounceRequest {
+    /// The text of the announcement.
+    pub text: String,
+    /// The channel to send the announcement to, all channels if not set.
+    pub channel: Option<ChannelID>,
+    /// The channel to send the number of reached channels over.
+    pub response: oneshot::Sender<usize>,
+}
+
+/// An active channel, as reported to operators
+#[derive(Debug, Serialize)]
+pub struct ChannelInfo {
+    /// The ID of the channel
+    pub id: ChannelID,
+    /// The file of the document
+    pub path: PathBuf,
+    /// The number of clients in the channel
+    pub members: u64,
+}
+
+/// Request to create a document in a folder
+#[derive(Debug)]
+pub struct CreateRequest {
+    /// The path of the folder to create the document in.
+    pub folder: String,
+    /// The name of the new document.
+    pub name: String,
+    /// The authenticated user that creates the document, if any
+    pub identity: Option<Identity>,
+    /// The channel to send the path of the new document over.
+    pub response: oneshot::Sender<Result<String, CreateError>>,
+}
+
+/// Request to read the markdown of a document
+#[derive(Debug)]
+pub struct ReadRequest {
+    /// The path of the document.
+    pub path: String,
+    /// The authenticated user, if authentication is enabled.
+    pub identity: Option<Identity>,
+    /// The password for the folders on the way to the document, if one was given.
+    pub password: Option<Secret>,
+    /// The channel to send the markdown over.
+    pub response: oneshot::Sender<Result<String, ReadError>>,
+}
+
+/// Request to search the documents of a folder
+#[derive(Debug)]
+pub struct SearchRequest {
+    /// The path of the folder.
+    pub folder: String,
+    /// What to look for.
+    pub query: Query,
+    /// The authenticated user, if authentication is enabled.
+    pub identity: Option<Identity>,
+    /// The password for the folders on the way to the folder, if one was given.
+    pub password: Option<Secret>,
+    /// The channel to send the results over.
+    pub response: oneshot::Sender<Result<Vec<SearchHit>, SearchError>>,
+}
+
 /// Request to join a channel
 #[derive(Debug)]
 pub struct JoinRequest {
     /// The path that identifies the channel to join.
     pub path: String,
+    /// The authenticated user, if authentication is enabled.
+    pub identity: Option<Identity>,
+    /// The password for the folders on the way to the channel, if one was given.
+    pub password: Option<Secret>,
     /// The channel to send the response over.
     pub response: oneshot::Sender<Result<JoinResponse, JoinError>>,
 }
@@ -38,19 +174,108 @@ pub enum JoinError {
     /// Recieving JoinResponse failed
     RecvFailed(#[from] oneshot::error::RecvError),
     /// Sending JoinRequest failed
-    SendFailed(#[from] mpsc::error::SendError<JoinRequest>),
+    SendFailed(#[from] mpsc::error::SendError<LobbyRequest>),
     /// Invalid path {0:?}
     InvalidPath(String),
     /// Is folder {0:?}
-    IsFolder(String),
+    IsFolder(FolderListing),
+    /// Channel is full ({0} users)
+    ChannelFull(u64),
+    /// Access to {0:?} is forbidden
+    Forbidden(String),
+    /// Wrong or missing password for {0:?}
+    BadPassword(String),
+    /// The document {0:?} is called {1:?}
+    NotCanonical(String, String),
+    /// Could not prepare the storage of the document: {0}
+    StorageFailed(String),
+    /// Could not load the document: {0}
+    LoadFailed(String),
+    /// All IDs are used up, the server needs a restart
+    Exhausted,
+}
+
+/// Error when creating a document
+#[derive(Debug, Error, Display)]
+pub enum CreateError {
+    /// Recieving the created path failed
+    RecvFailed(#[from] oneshot::error::RecvError),
+    /// Sending CreateRequest failed
+    SendFailed(#[from] mpsc::error::SendError<LobbyRequest>),
+    /// Not a folder {0:?}
+    NotFolder(String),
+    /// Folder {0:?} is read-only
+    ReadOnly(String),
+    /// Invalid name {0:?}
+    InvalidName(String),
+    /// Document {0:?} already exists
+    Exists(String),
+    /// Could not save document: {0}
+    SaveFailed(String),
+}
+
+impl CreateError {
+    /// The code that this error is reported to the client with
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotFolder(_) => ErrorCode::Unavailable,
+            Self::ReadOnly(_) => ErrorCode::Forbidden,
+            Self::InvalidName(_) => ErrorCode::InvalidArgument,
+            Self::Exists(_) => ErrorCode::Exists,
+            Self::RecvFailed(_) | Self::SendFailed(_) | Self::SaveFailed(_) => ErrorCode::Storage,
+        }
+    }
+}
+
+/// Error when reading a document
+#[derive(Debug, Error, Display)]
+pub enum ReadError {
+    /// Recieving the document failed
+    RecvFailed(#[from] oneshot::error::RecvError),
+    /// Sending ReadRequest failed
+    SendFailed(#[from] mpsc::error::SendError<LobbyRequest>),
+    /// Document {0:?} not found
+    NotFound(String),
+    /// The document {0:?} is called {1:?}
+    NotCanonical(String, String),
+    /// Access to {0:?} is forbidden
+    Forbidden(String),
+    /// Could not load document: {0}
+    LoadFailed(String),
+    /// All IDs are used up, the server needs a restart
+    Exhausted,
+}
+
+/// Error when searching a folder
+#[derive(Debug, Error, Display)]
+pub enum SearchError {
+    /// Recieving the results failed
+    RecvFailed(#[from] oneshot::error::RecvError),
+    /// Sending SearchRequest failed
+    SendFailed(#[from] mpsc::error::SendError<LobbyRequest>),
+    /// Not a folder of documents {0:?}
+    NotFolder(String),
+    /// Access to {0:?} is forbidden
+    Forbidden(String),
+    /// All IDs are used up, the server needs a restart
+    Exhausted,
+}
+
+/// Error when sending an admin request
+#[derive(Debug, Error, Display)]
+pub enum AdminError {
+    /// Recieving the response failed
+    RecvFailed(#[from] oneshot::error::RecvError),
+    /// Sending the request failed
+    SendFailed(#[from] mpsc::error::SendError<LobbyRequest>),
 }
 
 /// A handle to a lobby server that can be used to send join requests
 #[derive(Debug, Clone)]
-pub struct LobbyClient(mpsc::Sender<JoinRequest>);
+pub struct LobbyClient(mpsc::Sender<LobbyRequest>);
 
-impl From<mpsc::Sender<JoinRequest>> for LobbyClient {
-    fn from(inner: mpsc::Sender<JoinRequest>) -> Self {
+impl From<mpsc::Sender<LobbyRequest>> for LobbyClient {
+    fn from(inner: mpsc::Sender<LobbyRequest>) -> Self {
         Self(inner)
     }
 }
@@ -60,14 +285,18 @@ impl LobbyClient {
     pub async fn join_channel<S: Into<String>>(
         &mut self,
         path: S,
+        identity: Option<Identity>,
+        password: Option<Secret>,
     ) -> Result<JoinResponse, JoinError> {
         let (tx, rx) = oneshot::channel::<Result<JoinResponse, JoinError>>();
 
         self.0
-            .send(JoinRequest {
+            .send(LobbyRequest::Join(JoinRequest {
                 path: path.into(),
+                identity,
+                password,
                 response: tx,
-            })
+            }))
             .await
             .map_err(JoinError::SendFailed)?;
 
@@ -75,4 +304,103 @@ impl LobbyClient {
         let join_response = recv_result?;
         Ok(join_response)
     }
+
+    /// Request to create a document in the given folder, returns the path of the document
+    pub async fn create_document<S: Into<String>, N: Into<String>>(
+        &mut self,
+        folder: S,
+        name: N,
+        identity: Option<Identity>,
+    ) -> Result<String, CreateError> {
+        let (tx, rx) = oneshot::channel::<Result<String, CreateError>>();
+
+        self.0
+            .send(LobbyRequest::Create(CreateRequest {
+                folder: folder.into(),
+                name: name.into(),
+                identity,
+                response: tx,
+            }))
+            .await
+            .map_err(CreateError::SendFailed)?;
+
+        let recv_result = rx.await?;
+        let path = recv_result?;
+        Ok(path)
+    }
+
+    /// Request the current markdown of the document at the given path
+    pub async fn read_document<S: Into<String>>(
+        &mut self,
+        path: S,
+        identity: Option<Identity>,
+        password: Option<Secret>,
+    ) -> Result<String, ReadError> {
+        let (tx, rx) = oneshot::channel::<Result<String, ReadError>>();
+
+        self.0
+            .send(LobbyRequest::Read(ReadRequest {
+                path: path.into(),
+                identity,
+                password,
+                response: tx,
+            }))
+            .await
+            .map_err(ReadError::SendFailed)?;
+
+        let recv_result = rx.await?;
+        let markdown = recv_result?;
+        Ok(markdown)
+    }
+
+    /// Search the documents of a folder, returns at most [`MAX_RESULTS`] hits
+    ///
+    /// [`MAX_RESULTS`]: crate::search::MAX_RESULTS
+    pub async fn search<S: Into<String>>(
+        &mut self,
+        folder: S,
+        query: Query,
+        identity: Option<Identity>,
+        password: Option<Secret>,
+    ) -> Result<Vec<SearchHit>, SearchError> {
+        let (tx, rx) = oneshot::channel::<Result<Vec<SearchHit>, SearchError>>();
+
+        self.0
+            .send(LobbyRequest::Search(SearchRequest {
+                folder: folder.into(),
+                query,
+                identity,
+                password,
+                response: tx,
+            }))
+            .await
+            .map_err(SearchError::SendFailed)?;
+
+        let recv_result = rx.await?;
+        let hits = recv_result?;
+        Ok(hits)
+    }
+
+    /// Send an announcement to one or all channels, returns the number of reached channels
+    pub async fn announce(
+        &mut self,
+        text: String,
+        channel: Option<ChannelID>,
+    ) -> Result<usize, AdminError> {
+        let (tx, rx) = oneshot::channel::<usize>();
+        let request = AnnounceRequest {
+            text,
+            channel,
+            response: tx,
+        };
+        self.0.send(LobbyRequest::Announce(request)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Request the list of active channels
+    pub async fn list_channels(&mut self) -> Result<Vec<ChannelInfo>, AdminError> {
+        let (tx, rx) = oneshot::channel::<Vec<ChannelInfo>>();
+        self.0.send(LobbyRequest::Channels(tx)).await?;
+        Ok(rx.await?)
+    }
 }
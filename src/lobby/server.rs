@@ -1,20 +1,31 @@
-use super::{JoinError, JoinRequest, JoinResponse};
-use crate::channel::{Broadcast, Channel, ChannelComms, Request};
+use super::{
+    ChannelInfo, CreateError, CreateRequest, FolderListing, JoinError, JoinRequest, JoinResponse,
+    LobbyRequest, ReadError, ReadRequest, SearchError, SearchRequest,
+};
+use crate::channel::{
+    Broadcast, Channel, ChannelComms, Content, DocMeta, History, Persistence, Request, RequestKind,
+};
 use crate::{
-    config::{Folder, PathValidity},
-    util::{Counter, LoopState},
+    audit::AuditLog,
+    config::{normalize_path, BufferConfig, ContentKind, Folder, Format, PathValidity, Storage},
+    metrics::METRICS,
+    search::{SearchHit, MAX_RESULTS},
+    store::DocStore,
+    util::{unix_millis, Counter, LoopState, Secret},
+    webhook::Webhook,
 };
-use displaydoc::Display;
 use futures_util::future::{select, Either};
 use log::*;
+use prosemirror::markdown::to_markdown;
 use serde::Serialize;
 use slug::slugify;
 use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::{fmt, path::PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
 use tokio::stream::StreamExt;
 use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 macro_rules! make_id {
     (#[$doc:meta] $name:ident, $key:literal) => {
@@ -37,14 +48,14 @@ macro_rules! make_id {
         }
 
         impl From<$name> for u64 {
-            fn from(u_id: $name) -> u64 {
-                u_id.0
+            fn from(id: $name) -> u64 {
+                id.0
             }
         }
 
         impl From<u64> for $name {
             fn from(id: u64) -> $name {
-                UserID(id)
+                $name(id)
             }
         }
     };
@@ -56,18 +67,64 @@ make_id!(
     "user#{0}"
 );
 
-#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Hash)]
-/// channel#{0}
-pub struct ChannelID(u64);
-impl From<ChannelID> for u64 {
-    fn from(c_id: ChannelID) -> u64 {
-        c_id.0
+make_id!(
+    /// ID for a channel
+    ChannelID,
+    "channel#{0}"
+);
+
+/// Check that `name` is a valid document name, i.e. its own slug
+///
+/// Documents are only reachable by their slug, so that every file has exactly one name.
+/// Otherwise, e.g. "My Doc", "my_doc" and "my-doc" would silently share `my-doc.md`.
+/// Returns the slug to use instead, or `None` if nothing usable is left.
+fn check_slug(name: &str) -> Result<(), Option<String>> {
+    let slug = slugify(name);
+    if slug.is_empty() {
+        Err(None)
+    } else if slug != name {
+        Err(Some(slug))
+    } else {
+        Ok(())
     }
 }
 
-impl From<u64> for ChannelID {
-    fn from(id: u64) -> ChannelID {
-        ChannelID(id)
+/// Replace the last segment `file` of `path` with `slug`
+fn canonical_path(path: &str, file: &str, slug: &str) -> String {
+    let folder = path.strip_suffix(file).unwrap_or(path);
+    format!("{}{}", folder, slug)
+}
+
+/// A document that is about to be created
+struct NewDocument {
+    /// The path of the document, as clients join it
+    path: String,
+    /// The file of the document
+    file: PathBuf,
+    /// The initial content of the document
+    text: String,
+    /// The metadata of the document
+    meta: DocMeta,
+}
+
+impl NewDocument {
+    /// Save the document, unless there already is one, returns its path
+    async fn save(self, store: &dyn DocStore) -> Result<String, CreateError> {
+        match store.load(&self.file).await {
+            Ok(Some(_)) => Err(CreateError::Exists(self.path)),
+            Ok(None) => {
+                store
+                    .save(&self.file, &self.text)
+                    .await
+                    .map_err(|e| CreateError::SaveFailed(e.to_string()))?;
+                if let Err(e) = self.meta.save(store, &self.file).await {
+                    warn!("Could not save the metadata of {:?}: {}", self.file, e);
+                }
+                info!("Created document {:?}", self.file);
+                Ok(self.path)
+            }
+            Err(e) => Err(CreateError::SaveFailed(e.to_string())),
+        }
     }
 }
 
@@ -79,6 +136,21 @@ pub struct LobbyChannel {
     bct_tx: broadcast::Sender<Broadcast>,
     req_tx: mpsc::Sender<Request>,
     terminate: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+    /// How long the channel stays open after the last client left
+    linger: Option<Duration>,
+    /// How often the channel became empty, to ignore outdated timers
+    #[new(default)]
+    generation: u64,
+}
+
+/// An event from the channels for the lobby
+#[derive(Debug)]
+enum ChannelEvent {
+    /// A client left the channel
+    Left(ChannelID),
+    /// An empty channel waited long enough for someone to rejoin
+    Expired(ChannelID, u64),
 }
 
 #[derive(Debug, Default)]
@@ -89,7 +161,31 @@ pub struct LobbyState {
 }
 
 impl LobbyState {
-    async fn handle_end(&mut self, sig: ChannelID) -> LoopState<()> {
+    /// Terminate all channels and wait for them to save their documents
+    async fn shutdown(&mut self) {
+        self.channel_names.clear();
+        // Dropping the `terminate` sender signals a server shutdown to the channel
+        let handles: Vec<_> = self
+            .channels
+            .drain()
+            .map(|(id, channel)| (id, channel.handle))
+            .collect();
+        for _ in &handles {
+            METRICS.channels_destroyed.inc();
+            METRICS.live_channels.dec();
+        }
+        for (id, handle) in handles {
+            if let Err(e) = handle.await {
+                error!("Channel {} failed to shut down: {}", id, e);
+            }
+        }
+    }
+
+    async fn handle_end(
+        &mut self,
+        sig: ChannelID,
+        expire_tx: &mpsc::Sender<(ChannelID, u64)>,
+    ) -> LoopState<()> {
         match self.channels.entry(sig) {
             Entry::Vacant(_v) => {
                 error!("Channel entry vanished");
@@ -102,9 +198,26 @@ impl LobbyState {
                         error!("Channel {} not cleaned up correctly", sig);
                         LoopState::Break(())
                     }
+                    Ordering::Equal if channel.linger.is_some() => {
+                        // Keep the channel around, a new client cancels the timer
+                        channel.count = 0;
+                        channel.generation += 1;
+                        let generation = channel.generation;
+                        let linger = channel.linger.unwrap_or_default();
+                        let mut expire_tx = expire_tx.clone();
+                        tokio::spawn(async move {
+                            tokio::time::delay_for(linger).await;
+                            if let Err(e) = expire_tx.send((sig, generation)).await {
+                                debug!("Lobby is gone, channel {} expired: {}", sig, e);
+                            }
+                        });
+                        LoopState::Continue
+                    }
                     Ordering::Equal => {
                         let channel = o.remove();
                         self.channel_names.remove(&channel.path);
+                        METRICS.channels_destroyed.inc();
+                        METRICS.live_channels.dec();
                         if let Err(()) = channel.terminate.send(()) {
                             error!("Error terminating channel {}", sig);
                         }
@@ -119,62 +232,549 @@ impl LobbyState {
         }
     }
 
+    fn list_channels(&self) -> Vec<ChannelInfo> {
+        let mut channels: Vec<_> = self
+            .channels
+            .iter()
+            .map(|(id, channel)| ChannelInfo {
+                id: *id,
+                path: channel.path.clone(),
+                members: channel.count,
+            })
+            .collect();
+        channels.sort_by(|a, b| a.path.cmp(&b.path));
+        channels
+    }
+
+    fn announce(&self, text: String, target: Option<ChannelID>) -> usize {
+        let mut reached = 0;
+        for (id, channel) in &self.channels {
+            if target.map_or(true, |target| target == *id) {
+                let broadcast = Broadcast::System(text.clone());
+                // Fails if nobody is listening, which is fine for an empty channel
+                if channel.bct_tx.send(broadcast).is_ok() {
+                    reached += 1;
+                }
+            }
+        }
+        info!("Announced {:?} in {} channels", text, reached);
+        reached
+    }
+
+    fn handle_expired(&mut self, id: ChannelID, generation: u64) {
+        let expired = self.channels.get(&id).map_or(false, |channel| {
+            channel.count == 0 && channel.generation == generation
+        });
+        if !expired {
+            return;
+        }
+        if let Some(channel) = self.channels.remove(&id) {
+            info!("Channel {} was empty for too long", id);
+            self.channel_names.remove(&channel.path);
+            METRICS.channels_destroyed.inc();
+            METRICS.live_channels.dec();
+            if let Err(()) = channel.terminate.send(()) {
+                error!("Error terminating channel {}", id);
+            }
+        }
+    }
+
+    pub fn handle_create_request(
+        &mut self,
+        mut msg: CreateRequest,
+        folder: &mut Folder,
+        store: &Arc<dyn DocStore>,
+    ) {
+        msg.folder = normalize_path(&msg.folder);
+        let response = msg.response;
+        let res = match folder.check_name(&msg.folder) {
+            PathValidity::Folder(used_folder, dir) => {
+                if used_folder.read_only() {
+                    Err(CreateError::ReadOnly(msg.folder.clone()))
+                } else {
+                    let creator = msg.identity.map(|identity| identity.name);
+                    self.new_document(&msg.folder, &msg.name, creator, used_folder, dir)
+                        .map(|doc| (doc, used_folder.storage()))
+                }
+            }
+            PathValidity::File(..) | PathValidity::Invalid => {
+                Err(CreateError::NotFolder(msg.folder.clone()))
+            }
+        };
+
+        match res {
+            // A document in memory exists as soon as someone joins its channel
+            Ok((doc, Storage::Memory)) => {
+                if response.send(Ok(doc.path)).is_err() {
+                    error!("Client connection dropped while creating a document");
+                }
+            }
+            // Saving happens in a separate task, like reading, so that a slow store never
+            // blocks the lobby
+            Ok((doc, Storage::Disk)) => {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let res = doc.save(store.as_ref()).await;
+                    if response.send(res).is_err() {
+                        error!("Client connection dropped while creating a document");
+                    }
+                });
+            }
+            Err(e) => {
+                if response.send(Err(e)).is_err() {
+                    error!("Client connection dropped while creating a document");
+                }
+            }
+        }
+    }
+
+    /// Check the name of a new document and prepare its initial content
+    fn new_document(
+        &self,
+        folder_path: &str,
+        name: &str,
+        creator: Option<String>,
+        used_folder: &Folder,
+        dir: PathBuf,
+    ) -> Result<NewDocument, CreateError> {
+        let file_slug: String = slugify(name);
+        if file_slug.is_empty() {
+            return Err(CreateError::InvalidName(name.to_owned()));
+        }
+        let mut file = dir.join(&file_slug);
+        file.set_extension(used_folder.extension());
+
+        let path = format!("{}{}", folder_path, file_slug);
+        if self.channel_names.contains_key(&file) {
+            return Err(CreateError::Exists(path));
+        }
+        let text = Content::initial(used_folder.content(), &used_folder.initial_doc())
+            .write(used_folder.format())
+            .map_err(|e| CreateError::SaveFailed(e.to_string()))?;
+        // Keep the name the user chose, so that it can be shown instead of the slug
+        let title = Some(name.trim()).filter(|title| *title != file_slug);
+        let meta = DocMeta {
+            title: title.map(str::to_owned),
+            created: Some(unix_millis()),
+            creator,
+            ..DocMeta::default()
+        };
+        Ok(NewDocument {
+            path,
+            file,
+            text,
+            meta,
+        })
+    }
+
+    pub fn handle_read_request(
+        &mut self,
+        mut msg: ReadRequest,
+        folder: &mut Folder,
+        store: &Arc<dyn DocStore>,
+    ) {
+        msg.path = normalize_path(&msg.path);
+        let response = msg.response;
+        let password = msg.password.as_ref().map(Secret::expose);
+        if !folder.check_access(&msg.path, msg.identity.as_ref())
+            || !folder.check_password(&msg.path, password)
+        {
+            info!("Denied {:?} reading {:?}", msg.identity, msg.path);
+            if response.send(Err(ReadError::Forbidden(msg.path))).is_err() {
+                error!("Client connection dropped while reading a document");
+            }
+            return;
+        }
+
+        let (file, storage, format) = match folder.check_name(&msg.path) {
+            PathValidity::File(used_folder, ..)
+                if used_folder.content() != ContentKind::Document =>
+            {
+                // Only documents can be read as markdown
+                if response.send(Err(ReadError::NotFound(msg.path))).is_err() {
+                    error!("Client connection dropped while reading a document");
+                }
+                return;
+            }
+            PathValidity::File(used_folder, dir, file) => match check_slug(file) {
+                Ok(()) => {
+                    let mut file = dir.join(file);
+                    file.set_extension(used_folder.extension());
+                    (file, used_folder.storage(), used_folder.format())
+                }
+                Err(Some(slug)) => {
+                    let canonical = canonical_path(&msg.path, file, &slug);
+                    let err = ReadError::NotCanonical(msg.path.clone(), canonical);
+                    if response.send(Err(err)).is_err() {
+                        error!("Client connection dropped while reading a document");
+                    }
+                    return;
+                }
+                Err(None) => {
+                    if response.send(Err(ReadError::NotFound(msg.path))).is_err() {
+                        error!("Client connection dropped while reading a document");
+                    }
+                    return;
+                }
+            },
+            PathValidity::Folder(..) | PathValidity::Invalid => {
+                if response.send(Err(ReadError::NotFound(msg.path))).is_err() {
+                    error!("Client connection dropped while reading a document");
+                }
+                return;
+            }
+        };
+
+        // A live channel has a newer version than the store. Both are asked from a
+        // separate task, so that a busy channel or a slow store never blocks the lobby.
+        // The store may be outdated, so a live channel is never skipped for it
+        let live = match self.channel_names.get(&file) {
+            Some(channel_id) => {
+                let channel = self.channels.get_mut(channel_id).unwrap();
+                match channel.next_id.next() {
+                    Some(source) => Some((source, channel.req_tx.clone())),
+                    None => {
+                        error!("All user IDs of {:?} are used up", file);
+                        if response.send(Err(ReadError::Exhausted)).is_err() {
+                            error!("Client connection dropped while reading a document");
+                        }
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+        let store = store.clone();
+        let path = msg.path;
+        tokio::spawn(async move {
+            let res = match (live, storage) {
+                (Some((source, mut req_tx)), _) => {
+                    let (tx, rx) = oneshot::channel::<String>();
+                    let kind = RequestKind::Markdown { response: tx };
+                    match req_tx.send(Request { source, kind }).await {
+                        Ok(()) => rx.await.map_err(ReadError::RecvFailed),
+                        Err(_) => Err(ReadError::LoadFailed("Channel closed".to_string())),
+                    }
+                }
+                (None, Storage::Disk) => match store.load(&file).await {
+                    Ok(Some(md)) if format == Format::Markdown => Ok(md),
+                    Ok(Some(text)) => format
+                        .parse(&text)
+                        .and_then(|doc| Ok(to_markdown(&doc)?))
+                        .map_err(|e| ReadError::LoadFailed(e.to_string())),
+                    Ok(None) => Err(ReadError::NotFound(path)),
+                    Err(e) => Err(ReadError::LoadFailed(e.to_string())),
+                },
+                (None, Storage::Memory) => Err(ReadError::NotFound(path)),
+            };
+            if response.send(res).is_err() {
+                error!("Client connection dropped while reading a document");
+            }
+        });
+    }
+
+    pub async fn handle_search_request(
+        &mut self,
+        mut msg: SearchRequest,
+        folder: &mut Folder,
+        store: &Arc<dyn DocStore>,
+    ) {
+        msg.folder = normalize_path(&msg.folder);
+        if !msg.folder.ends_with('/') {
+            msg.folder.push('/');
+        }
+        let response = msg.response;
+        let password = msg.password.as_ref().map(Secret::expose);
+        if !folder.check_access(&msg.folder, msg.identity.as_ref())
+            || !folder.check_password(&msg.folder, password)
+        {
+            info!("Denied {:?} searching {:?}", msg.identity, msg.folder);
+            if response
+                .send(Err(SearchError::Forbidden(msg.folder)))
+                .is_err()
+            {
+                error!("Client connection dropped while searching");
+            }
+            return;
+        }
+
+        let (used_folder, dir) = match folder.check_name(&msg.folder) {
+            PathValidity::Folder(used_folder, dir)
+                if used_folder.content() == ContentKind::Document =>
+            {
+                (used_folder, dir)
+            }
+            _ => {
+                if response
+                    .send(Err(SearchError::NotFolder(msg.folder)))
+                    .is_err()
+                {
+                    error!("Client connection dropped while searching");
+                }
+                return;
+            }
+        };
+        let names = match store.list(&dir, used_folder.extension()).await {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Could not list documents in {:?}: {}", dir, e);
+                Vec::new()
+            }
+        };
+
+        // Live channels are asked for their current text, everything else is read from
+        // the store. Both happen in a separate task, like a read request.
+        let mut documents = Vec::with_capacity(names.len());
+        for name in names {
+            let mut file = dir.join(&name);
+            file.set_extension(used_folder.extension());
+            let live = match self.channel_names.get(&file) {
+                Some(channel_id) => {
+                    let channel = self.channels.get_mut(channel_id).unwrap();
+                    match channel.next_id.next() {
+                        Some(source) => Some((source, channel.req_tx.clone())),
+                        None => {
+                            error!("All user IDs of {:?} are used up", file);
+                            if response.send(Err(SearchError::Exhausted)).is_err() {
+                                error!("Client connection dropped while searching");
+                            }
+                            return;
+                        }
+                    }
+                }
+                None => None,
+            };
+            documents.push((format!("{}{}", msg.folder, name), file, live));
+        }
+        let format = used_folder.format();
+        let query = msg.query;
+        let store = store.clone();
+        tokio::spawn(async move {
+            let mut hits = Vec::new();
+            for (path, file, live) in documents {
+                let md = match live {
+                    Some((source, mut req_tx)) => {
+                        let (tx, rx) = oneshot::channel::<String>();
+                        let kind = RequestKind::Markdown { response: tx };
+                        match req_tx.send(Request { source, kind }).await {
+                            Ok(()) => rx.await.ok(),
+                            Err(_) => None,
+                        }
+                    }
+                    None => match store.load(&file).await {
+                        Ok(Some(md)) if format == Format::Markdown => Some(md),
+                        Ok(Some(text)) => {
+                            match format.parse(&text).and_then(|doc| Ok(to_markdown(&doc)?)) {
+                                Ok(md) => Some(md),
+                                Err(e) => {
+                                    warn!("Could not parse {:?} for a search: {}", file, e);
+                                    None
+                                }
+                            }
+                        }
+                        Ok(None) => None,
+                        Err(e) => {
+                            warn!("Could not load {:?} for a search: {}", file, e);
+                            None
+                        }
+                    },
+                };
+                if let Some(snippet) = md.and_then(|md| query.snippet(&md)) {
+                    hits.push(SearchHit { path, snippet });
+                    if hits.len() >= MAX_RESULTS {
+                        break;
+                    }
+                }
+            }
+            if response.send(Ok(hits)).is_err() {
+                error!("Client connection dropped while searching");
+            }
+        });
+    }
+
     pub async fn handle_join_request(
         &mut self,
-        msg: JoinRequest,
+        mut msg: JoinRequest,
         end_tx: &mpsc::Sender<ChannelID>,
         folder: &mut Folder,
+        store: &Arc<dyn DocStore>,
+        audit: &Option<AuditLog>,
+        webhook: &Option<Webhook>,
+        buffers: &BufferConfig,
     ) {
+        msg.path = normalize_path(&msg.path);
         let response = msg.response;
         let log_join_response = |res: Result<(), Result<JoinResponse, JoinError>>| match res {
             Ok(()) => {}
             Err(_) => error!("Client connection dropped while joining"),
         };
 
-        let mut base_dir = std::env::current_dir().unwrap();
-        base_dir.push("pads");
+        if !folder.check_access(&msg.path, msg.identity.as_ref()) {
+            info!("Denied {:?} access to {:?}", msg.identity, msg.path);
+            log_join_response(response.send(Err(JoinError::Forbidden(msg.path))));
+            return;
+        }
+
+        let password = msg.password.as_ref().map(Secret::expose);
+        if !folder.check_password(&msg.path, password) {
+            info!("Wrong or missing password for {:?}", msg.path);
+            log_join_response(response.send(Err(JoinError::BadPassword(msg.path))));
+            return;
+        }
 
-        let (_used_folder, dir, file) = match folder.check_name(&msg.path, base_dir) {
+        let (used_folder, dir, file) = match folder.check_name(&msg.path) {
             PathValidity::Invalid => {
                 log_join_response(response.send(Err(JoinError::InvalidPath(msg.path))));
                 return;
             }
-            PathValidity::Folder(used_folder, _dir) => {
-                log_join_response(
-                    response.send(Err(JoinError::IsFolder(format!("{:?}", used_folder)))),
-                );
+            PathValidity::Folder(used_folder, dir) => {
+                let documents = match store.list(&dir, used_folder.extension()).await {
+                    Ok(documents) => documents,
+                    Err(e) => {
+                        warn!("Could not list documents in {:?}: {}", dir, e);
+                        Vec::new()
+                    }
+                };
+                let mut meta = BTreeMap::new();
+                for name in &documents {
+                    let mut file = dir.join(name);
+                    file.set_extension(used_folder.extension());
+                    match DocMeta::load(store.as_ref(), &file).await {
+                        Ok(doc_meta) if doc_meta.is_empty() => {}
+                        Ok(doc_meta) => {
+                            meta.insert(name.clone(), doc_meta);
+                        }
+                        Err(e) => warn!("Could not load the metadata of {:?}: {}", file, e),
+                    }
+                }
+                let listing = FolderListing {
+                    folders: used_folder.sub_names(),
+                    documents,
+                    meta,
+                };
+                log_join_response(response.send(Err(JoinError::IsFolder(listing))));
                 return;
             }
-            PathValidity::File(used_folder, dir, file) => {
-                info!("loading file {:?} {:?} {:?}", used_folder, dir, file);
-                (used_folder, dir, file)
-            }
+            PathValidity::File(used_folder, dir, file) => match check_slug(file) {
+                Ok(()) => {
+                    info!("loading file {:?} {:?} {:?}", used_folder, dir, file);
+                    (used_folder, dir, file)
+                }
+                Err(Some(slug)) => {
+                    let canonical = canonical_path(&msg.path, file, &slug);
+                    let err = JoinError::NotCanonical(msg.path.clone(), canonical);
+                    log_join_response(response.send(Err(err)));
+                    return;
+                }
+                Err(None) => {
+                    log_join_response(response.send(Err(JoinError::InvalidPath(msg.path))));
+                    return;
+                }
+            },
         };
 
-        let file_slug: String = slugify(file);
-        let mut file = dir.as_path().join(file_slug);
-        file.set_extension("md");
+        let max_users = used_folder.max_users_per_channel();
+        // Without this, the first user would still open a new channel
+        if max_users == Some(0) {
+            info!("Rejected client, channels in {:?} take no users", dir);
+            log_join_response(response.send(Err(JoinError::ChannelFull(0))));
+            return;
+        }
+        let linger = used_folder.channel_linger();
+        let storage = used_folder.storage();
+        let format = used_folder.format();
+        let kind = used_folder.content();
+        let template = used_folder.initial_doc();
+        let max_name_len = used_folder.max_name_len();
+        let require_name = used_folder.require_name();
+        let max_doc_bytes = used_folder.max_doc_bytes();
+        let resume_grace = used_folder.resume_grace();
+        let chat_history = used_folder.chat_history();
+        let step_coalesce = used_folder.step_coalesce();
+        let revision_interval = used_folder.revision_interval();
+        let max_revisions = used_folder.max_revisions();
+        let mut file = dir.as_path().join(file);
+        file.set_extension(used_folder.extension());
+
+        let title = match storage {
+            Storage::Disk => match DocMeta::load(store.as_ref(), &file).await {
+                Ok(doc_meta) => doc_meta.title,
+                Err(e) => {
+                    warn!("Could not load the metadata of {:?}: {}", file, e);
+                    None
+                }
+            },
+            Storage::Memory => None,
+        };
+
+        // A new channel saves its document right away, so report problems with the
+        // directory to the client instead of failing in the channel task
+        if storage == Storage::Disk && !self.channel_names.contains_key(&file) {
+            if let Err(e) = store.create_dir(&dir).await {
+                error!("{:?}", e);
+                let err = JoinError::StorageFailed(e.root_cause().to_string());
+                log_join_response(response.send(Err(err)));
+                return;
+            }
+        }
 
         match self.channel_names.entry(file.clone()) {
             Entry::Vacant(v) => {
-                let (req_tx, req_rx) = mpsc::channel(100);
-                let (bct_tx, bct_rx) = broadcast::channel(100);
+                let channel_id = match self.next_id.next() {
+                    Some(channel_id) => channel_id,
+                    None => {
+                        error!("All channel IDs are used up");
+                        log_join_response(response.send(Err(JoinError::Exhausted)));
+                        return;
+                    }
+                };
+                let (req_tx, req_rx) = mpsc::channel(buffers.requests);
+                let (bct_tx, bct_rx) = broadcast::channel(buffers.broadcasts);
                 let (ter_tx, ter_rx) = oneshot::channel::<()>();
-                let channel_id = self.next_id.next();
+                let (ready_tx, ready_rx) = oneshot::channel::<Result<(), String>>();
 
-                tokio::spawn({
+                let handle = tokio::spawn({
                     let end_tx = end_tx.clone();
                     let bct_tx = bct_tx.clone();
                     let path = file.clone();
+                    let doc_path = msg.path.clone();
+                    let store = store.clone();
+                    let audit = audit.clone();
+                    let webhook = webhook.as_ref().map(Webhook::notifier);
+                    let history = match (storage, revision_interval) {
+                        (Storage::Disk, Some(interval)) => {
+                            Some(History::new(store.clone(), &file, interval, max_revisions))
+                        }
+                        _ => None,
+                    };
+                    let persistence = match storage {
+                        Storage::Disk => Persistence::Disk(file.clone(), format),
+                        Storage::Memory => Persistence::Memory,
+                    };
                     async move {
                         let res = Channel {
                             msg_rx: req_rx,
                             ter_rx,
+                            ready_tx,
+                            store,
+                            persistence,
+                            kind,
+                            template,
+                            max_name_len,
+                            require_name,
+                            max_doc_bytes,
+                            resume_grace,
+                            chat_history,
+                            step_coalesce,
                             comms: ChannelComms {
                                 id: channel_id,
                                 path,
+                                doc_path,
                                 bct_tx,
                                 end_tx,
+                                audit,
+                                webhook,
+                                history,
                             },
                         }
                         .handle_messages()
@@ -185,34 +785,69 @@ impl LobbyState {
                     }
                 });
 
+                // Only acknowledge the join once the document is loaded, so that a document
+                // that can not be parsed never leaves a channel without a task behind
+                let ready = ready_rx
+                    .await
+                    .unwrap_or_else(|_| Err(String::from("The channel stopped")));
+                if let Err(e) = ready {
+                    warn!("Could not open channel for {:?}: {}", file, e);
+                    log_join_response(response.send(Err(JoinError::LoadFailed(e))));
+                    return;
+                }
+                METRICS.channels_created.inc();
+                METRICS.live_channels.inc();
+
+                // The first ID of a new counter is always there
                 let mut next_id = Counter::default();
+                let id = next_id.next().unwrap();
 
                 log_join_response(response.send(Ok(JoinResponse {
-                    id: next_id.next(),
+                    id,
+                    path: msg.path.clone(),
+                    title,
                     msg_tx: req_tx.clone(),
                     bct_rx,
                 })));
 
                 self.channels.insert(
                     channel_id,
-                    LobbyChannel::new(next_id, 1, file, bct_tx, req_tx, ter_tx),
+                    LobbyChannel::new(next_id, 1, file, bct_tx, req_tx, ter_tx, handle, linger),
                 );
                 v.insert(channel_id);
             }
             Entry::Occupied(o_id) => {
                 let channel_id = o_id.get();
                 let channel = self.channels.get_mut(channel_id).unwrap();
+                if let Some(limit) = max_users {
+                    if channel.count >= limit {
+                        info!("Rejected client, channel {} is full", channel_id);
+                        log_join_response(response.send(Err(JoinError::ChannelFull(limit))));
+                        return;
+                    }
+                }
+                let id = match channel.next_id.next() {
+                    Some(id) => id,
+                    None => {
+                        error!("All user IDs in channel {} are used up", channel_id);
+                        log_join_response(response.send(Err(JoinError::Exhausted)));
+                        return;
+                    }
+                };
                 channel.count += 1;
-
-                let id = channel.next_id.next();
                 let res = response.send(Ok(JoinResponse {
                     id,
+                    path: msg.path.clone(),
+                    title,
                     msg_tx: channel.req_tx.clone(),
                     bct_rx: channel.bct_tx.subscribe(),
                 }));
                 match res {
                     Ok(()) => {
-                        info!("Accepted client {} into channel {}", id, channel_id);
+                        info!(
+                            "Accepted client {} ({:?}) into channel {}",
+                            id, msg.identity, channel_id
+                        );
                     }
                     Err(_) => {
                         error!("Client connection {} dropped while joining", id);
@@ -226,38 +861,97 @@ impl LobbyState {
 /// The task for the lobby
 #[derive(Debug, new)]
 pub struct LobbyServer {
-    inner: mpsc::Receiver<JoinRequest>,
+    inner: mpsc::Receiver<LobbyRequest>,
+    shutdown: oneshot::Receiver<()>,
     #[new(default)]
     state: LobbyState,
     folder: Folder,
+    store: Arc<dyn DocStore>,
+    audit: Option<AuditLog>,
+    webhook: Option<Webhook>,
+    buffers: BufferConfig,
 }
 
 impl LobbyServer {
     /// The main loop of the server
     pub async fn run(mut self) {
-        let (end_tx, mut end_rx) = mpsc::channel::<ChannelID>(5);
+        let (end_tx, end_rx) = mpsc::channel::<ChannelID>(5);
+        let (expire_tx, expire_rx) = mpsc::channel::<(ChannelID, u64)>(5);
+        let mut events = end_rx
+            .map(ChannelEvent::Left)
+            .merge(expire_rx.map(|(id, generation)| ChannelEvent::Expired(id, generation)));
 
-        let mut sig_fut = end_rx.next();
+        let mut shutdown = self.shutdown;
+        let mut sig_fut = events.next();
         let mut jrq_fut = self.inner.next();
         loop {
             let fut = select(sig_fut, jrq_fut);
-            match fut.await {
+            let fut = match select(&mut shutdown, fut).await {
+                Either::Left((_shutdown, _fut_continue)) => {
+                    info!("Shutting down, saving all channels");
+                    self.state.shutdown().await;
+                    break;
+                }
+                Either::Right((res, _shutdown_continue)) => res,
+            };
+            match fut {
                 Either::Left((sig, jrq_fut_continue)) => {
-                    if let Some(sig) = sig {
-                        if let LoopState::Break(()) = self.state.handle_end(sig).await {
-                            break;
+                    match sig {
+                        Some(ChannelEvent::Left(id)) => {
+                            if let LoopState::Break(()) =
+                                self.state.handle_end(id, &expire_tx).await
+                            {
+                                break;
+                            }
                         }
+                        Some(ChannelEvent::Expired(id, generation)) => {
+                            self.state.handle_expired(id, generation);
+                        }
+                        None => {}
                     }
                     jrq_fut = jrq_fut_continue;
-                    sig_fut = end_rx.next();
+                    sig_fut = events.next();
                 }
                 Either::Right((msg, sig_fut_continue)) => {
-                    if let Some(msg) = msg {
-                        self.state
-                            .handle_join_request(msg, &end_tx, &mut self.folder)
-                            .await;
-                    } else {
-                        trace!("JoinRequest stream broke!");
+                    match msg {
+                        Some(LobbyRequest::Join(msg)) => {
+                            self.state
+                                .handle_join_request(
+                                    msg,
+                                    &end_tx,
+                                    &mut self.folder,
+                                    &self.store,
+                                    &self.audit,
+                                    &self.webhook,
+                                    &self.buffers,
+                                )
+                                .await;
+                        }
+                        Some(LobbyRequest::Create(msg)) => {
+                            self.state
+                                .handle_create_request(msg, &mut self.folder, &self.store);
+                        }
+                        Some(LobbyRequest::Read(msg)) => {
+                            self.state
+                                .handle_read_request(msg, &mut self.folder, &self.store);
+                        }
+                        Some(LobbyRequest::Search(msg)) => {
+                            self.state
+                                .handle_search_request(msg, &mut self.folder, &self.store)
+                                .await;
+                        }
+                        Some(LobbyRequest::Announce(msg)) => {
+                            let reached = self.state.announce(msg.text, msg.channel);
+                            if msg.response.send(reached).is_err() {
+                                error!("Admin connection dropped while announcing");
+                            }
+                        }
+                        Some(LobbyRequest::Channels(response)) => {
+                            if response.send(self.state.list_channels()).is_err() {
+                                error!("Admin connection dropped while listing channels");
+                            }
+                        }
+                        None => trace!("LobbyRequest stream broke!"),
                     }
                     sig_fut = sig_fut_continue;
                     jrq_fut = self.inner.next();
@@ -266,3 +960,193 @@ impl LobbyServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobby::{Identity, Role};
+    use crate::store::FsDocStore;
+    use crate::testing::TestServer;
+    use crate::util::random_token;
+    use color_eyre::Report;
+
+    #[test]
+    fn ids_round_trip_through_u64() {
+        assert_eq!(u64::from(UserID::from(42)), 42);
+        assert_eq!(UserID::from(42).to_string(), "user#42");
+        assert_eq!(u64::from(ChannelID::from(7)), 7);
+        assert_eq!(ChannelID::from(7).int_val(), 7);
+        assert_eq!(ChannelID::from(7).to_string(), "channel#7");
+        assert_eq!(serde_json::to_string(&ChannelID::from(7)).unwrap(), "7");
+    }
+
+    #[tokio::test]
+    async fn documents_use_the_extension_of_their_folder() -> Result<(), Report> {
+        let dir = std::env::temp_dir().join(format!("padington-lobby-{}", random_token()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("notes.txt"), "# Kept as text\n")?;
+        let mut folder = Folder::default()
+            .with_storage(Storage::Disk)
+            .with_extension("txt");
+        folder.set_save_dir(dir.clone());
+        let server = TestServer::with_folder(folder);
+
+        // An existing document is opened from its file
+        let client = server.connect("/notes", None).await?;
+        assert!(client.init.doc.contains("Kept as text"));
+        client.leave().await?;
+        // A new one is created with the same extension
+        assert_eq!(
+            server.lobby().create_document("/", "Other", None).await?,
+            "/other"
+        );
+        server.shutdown().await?;
+
+        assert_eq!(FsDocStore.list(&dir, "txt").await?, vec!["notes", "other"]);
+        assert!(FsDocStore.list(&dir, "md").await?.is_empty());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_directory_of_a_new_document_is_created() -> Result<(), Report> {
+        let base = std::env::temp_dir().join(format!("padington-lobby-{}", random_token()));
+        let dir = base.join("docs");
+        let notes = Folder::default().with_storage(Storage::Disk);
+        let mut folder = Folder::default()
+            .with_storage(Storage::Disk)
+            .with_sub("notes", notes);
+        folder.set_save_dir(dir.clone());
+        let server = TestServer::with_folder(folder);
+
+        let client = server.connect("/notes/todo", None).await?;
+        assert!(dir.join("notes").is_dir());
+        client.leave().await?;
+        server.shutdown().await?;
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_directory_that_can_not_be_created_is_reported() -> Result<(), Report> {
+        let file = std::env::temp_dir().join(format!("padington-lobby-{}", random_token()));
+        std::fs::write(&file, "not a directory")?;
+        let mut folder = Folder::default().with_storage(Storage::Disk);
+        folder.set_save_dir(file.join("docs"));
+        let server = TestServer::with_folder(folder);
+
+        let res = server.lobby().join_channel("/todo", None, None).await;
+        assert!(matches!(res, Err(JoinError::StorageFailed(_))));
+        server.shutdown().await?;
+        std::fs::remove_file(&file)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_documents_can_not_be_joined() -> Result<(), Report> {
+        let dir = std::env::temp_dir().join(format!("padington-lobby-{}", random_token()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("broken.json"),
+            "{\"type\": \"doc\", \"content\": [",
+        )?;
+        let mut folder = Folder::default()
+            .with_storage(Storage::Disk)
+            .with_format(Format::Json);
+        folder.set_save_dir(dir.clone());
+        let server = TestServer::with_folder(folder);
+
+        for _ in 0..2 {
+            let res = server.lobby().join_channel("/broken", None, None).await;
+            assert!(matches!(res, Err(JoinError::LoadFailed(_))));
+        }
+
+        // Nothing of the failed channel is left, so the next join starts over
+        std::fs::remove_file(dir.join("broken.json"))?;
+        let client = server.connect("/broken", None).await?;
+        client.leave().await?;
+        server.shutdown().await?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn denied_identities_can_not_join() -> Result<(), Report> {
+        let team = Folder::default()
+            .with_storage(Storage::Memory)
+            .with_allow(vec![String::from("alice")]);
+        let folder = Folder::default()
+            .with_storage(Storage::Memory)
+            .with_sub("team", team);
+        let server = TestServer::with_folder(folder);
+        let user = |name: &str| {
+            Some(Identity {
+                name: name.to_owned(),
+                role: Role::Editor,
+            })
+        };
+
+        let join = server
+            .lobby()
+            .join_channel("/team/doc", user("alice"), None)
+            .await?;
+        let mut msg_tx = join.msg_tx;
+        let res = server
+            .lobby()
+            .join_channel("/team/doc", user("carol"), None)
+            .await;
+        assert!(matches!(res, Err(JoinError::Forbidden(path)) if path == "/team/doc"));
+        let res = server.lobby().join_channel("/team/doc", None, None).await;
+        assert!(matches!(res, Err(JoinError::Forbidden(_))));
+
+        let leave = Request {
+            source: join.id,
+            kind: RequestKind::Leave,
+        };
+        assert!(msg_tx.send(leave).await.is_ok());
+        server.shutdown().await
+    }
+
+    #[test]
+    fn only_the_slug_is_a_valid_name() {
+        assert_eq!(check_slug("my-doc"), Ok(()));
+        for name in &["My Doc", "my_doc", "MY-DOC", "my--doc"] {
+            assert_eq!(check_slug(name), Err(Some(String::from("my-doc"))));
+        }
+        assert_eq!(check_slug("cafe"), Ok(()));
+        assert_eq!(check_slug("café"), Err(Some(String::from("cafe"))));
+        assert_eq!(check_slug("!!!"), Err(None));
+    }
+
+    #[tokio::test]
+    async fn names_with_the_same_slug_do_not_share_a_document() -> Result<(), Report> {
+        let dir = std::env::temp_dir().join(format!("padington-lobby-{}", random_token()));
+        let mut folder = Folder::default().with_storage(Storage::Disk);
+        folder.set_save_dir(dir.clone());
+        let server = TestServer::with_folder(folder);
+        let mut lc = server.lobby();
+
+        assert_eq!(lc.create_document("/", "My Doc", None).await?, "/my-doc");
+        let res = lc.create_document("/", "my_doc", None).await;
+        assert!(matches!(res, Err(CreateError::Exists(path)) if path == "/my-doc"));
+        assert_eq!(lc.create_document("/", "café", None).await?, "/cafe");
+        let res = lc.create_document("/", "Cafe", None).await;
+        assert!(matches!(res, Err(CreateError::Exists(path)) if path == "/cafe"));
+
+        for (path, canonical) in &[
+            ("/My Doc", "/my-doc"),
+            ("/my_doc", "/my-doc"),
+            ("/café", "/cafe"),
+        ] {
+            match lc.join_channel(*path, None, None).await {
+                Err(JoinError::NotCanonical(_, slug)) => assert_eq!(slug, *canonical),
+                other => panic!("Joined {:?} as {:?}", path, other.map(|res| res.path)),
+            }
+        }
+        assert_eq!(FsDocStore.list(&dir, "md").await?, vec!["cafe", "my-doc"]);
+
+        server.shutdown().await?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,69 +1,65 @@
 //! # Server for the `padington` protocol
 #![warn(missing_docs)]
 
-pub mod channel;
-pub mod client;
-pub mod command;
-pub mod config;
-pub mod lobby;
-pub mod util;
-
-#[macro_use]
-extern crate derive_new;
-
-use crate::client::handle_connection;
-use crate::config::{ConnSetup, Flags, Setup};
-use crate::lobby::{JoinRequest, LobbyClient, LobbyServer};
 use color_eyre::Report;
-use eyre::{eyre, WrapErr};
-use futures_util::future::ready;
-//use log::*;
-use std::future::Future;
-use std::io;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
+use eyre::WrapErr;
+use padington_server::config::{Flags, Setup};
+use padington_server::Server;
 use structopt::StructOpt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
-use tokio_rustls::{server::TlsStream, TlsAcceptor};
-use tokio_tungstenite::stream::Stream;
+use tokio::signal;
 use tracing::{error, info, instrument};
+#[cfg(feature = "capture-spantrace")]
+use tracing_appender::non_blocking::WorkerGuard;
 
-async fn accept_connection(lc: LobbyClient, peer: SocketAddr, stream: ClientStream) {
-    if let Err(e) = handle_connection(lc, peer, stream).await {
-        error!("Error processing connection: {}", e)
-    }
-}
-
-type ClientStream = Stream<TcpStream, TlsStream<TcpStream>>;
-
-async fn wait_for_connections<F, R>(
-    mut listener: TcpListener,
-    lobby_sender: mpsc::Sender<JoinRequest>,
-    map: F,
-) where
-    F: Fn(TcpStream) -> R,
-    R: Future<Output = Result<ClientStream, io::Error>>,
-{
-    while let Ok((stream, peer)) = listener.accept().await {
-        let lc = LobbyClient::from(lobby_sender.clone());
-        match map(stream).await {
-            Ok(stream) => {
-                tokio::spawn(accept_connection(lc, peer, stream));
-            }
-            Err(e) => error!("Invalid connection request: {:?}", e),
-        }
-    }
-}
+/// The name of the log files, before the date
+const LOG_FILE_PREFIX: &str = "padington.log";
 
+/// Install the subscriber for traces, logging to stdout and to files in `log_dir`
+///
+/// The returned guard writes the remaining lines to the file when it is dropped.
 #[cfg(feature = "capture-spantrace")]
-fn install_tracing() {
+fn install_tracing(flags: &Flags) -> Option<WorkerGuard> {
+    use padington_server::config::{LogFormat, LogRotation};
+    use tracing_appender::{non_blocking, rolling};
     use tracing_error::ErrorLayer;
     use tracing_subscriber::prelude::*;
     use tracing_subscriber::{fmt, EnvFilter};
 
-    let fmt_layer = fmt::layer(); //.with_target(false);
+    let json = flags.log_format == LogFormat::Json;
+    // The JSON records keep the fields of the current span and its parents, e.g. the
+    // channel and user of a client task
+    let (fmt_layer, json_layer) = if json {
+        let layer = fmt::layer()
+            .json()
+            .with_span_list(true)
+            .with_current_span(true);
+        (None, Some(layer))
+    } else {
+        (Some(fmt::layer()), None) //.with_target(false);
+    };
+    let (file_layer, file_json_layer, guard) = match &flags.log_dir {
+        Some(dir) => {
+            let appender = match flags.log_rotation {
+                LogRotation::Minutely => rolling::minutely(dir, LOG_FILE_PREFIX),
+                LogRotation::Hourly => rolling::hourly(dir, LOG_FILE_PREFIX),
+                LogRotation::Daily => rolling::daily(dir, LOG_FILE_PREFIX),
+                LogRotation::Never => rolling::never(dir, LOG_FILE_PREFIX),
+            };
+            let (writer, guard) = non_blocking(appender);
+            if json {
+                let layer = fmt::layer()
+                    .json()
+                    .with_span_list(true)
+                    .with_current_span(true)
+                    .with_writer(writer);
+                (None, Some(layer), Some(guard))
+            } else {
+                let layer = fmt::layer().with_writer(writer).with_ansi(false);
+                (Some(layer), None, Some(guard))
+            }
+        }
+        None => (None, None, None),
+    };
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| {
             EnvFilter::try_new(
@@ -78,54 +74,60 @@ fn install_tracing() {
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(fmt_layer)
+        .with(json_layer)
+        .with(file_layer)
+        .with(file_json_layer)
         .with(ErrorLayer::default())
         .init();
+    guard
+}
+
+/// Wait for ctrl-c
+async fn ctrl_c() {
+    match signal::ctrl_c().await {
+        Ok(()) => info!("Received ctrl-c, shutting down"),
+        Err(e) => error!("Failed to listen for ctrl-c: {}", e),
+    }
+}
+
+/// Wait until the process should stop, i.e. ctrl-c or `SIGTERM` on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = ctrl_c() => {}
+                    _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+                }
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    ctrl_c().await
 }
 
 #[instrument]
 #[tokio::main]
 async fn main() -> Result<(), Report> {
-    #[cfg(feature = "capture-spantrace")]
-    install_tracing();
-
     let flags: Flags = Flags::from_args();
-    let cfg: Setup = flags.load_cfg().await.wrap_err("loading config")?;
 
-    let addr = cfg.addr.as_str().to_socket_addrs().unwrap().next().unwrap();
-
-    let (lobby_sender, lobby_receiver) = mpsc::channel(100);
-
-    tokio::spawn(LobbyServer::new(lobby_receiver, cfg.folder).run());
-
-    let listener = TcpListener::bind(&addr).await.wrap_err("Can't listen")?;
-    info!("Listening on: {}", addr);
+    // Keep the guard until the end, so that the last lines reach the log file
+    #[cfg(feature = "capture-spantrace")]
+    let _guard = install_tracing(&flags);
 
-    match cfg.conn {
-        ConnSetup::Basic => {
-            wait_for_connections(listener, lobby_sender, |stream| {
-                ready(Ok(Stream::Plain(stream)))
-            })
-            .await;
-        }
-        ConnSetup::Tls { certs, mut keys } => {
-            info!("Setting up TLS ...");
-            let mut config = ServerConfig::new(NoClientAuth::new());
-            let key = keys
-                .drain(..1)
-                .next()
-                .ok_or_else(|| eyre!("Key-File contains no keys"))?;
-            config
-                .set_single_cert(certs, key)
-                .wrap_err("setting certificate")?;
-            let acceptor = TlsAcceptor::from(Arc::new(config));
-            wait_for_connections(listener, lobby_sender, |stream: TcpStream| async {
-                let acceptor = acceptor.clone();
-                let stream = acceptor.accept(stream).await?;
-                Ok(Stream::Tls(stream))
-            })
-            .await;
-        }
+    let cfg: Setup = flags.load_cfg().await.wrap_err("loading config")?;
+    cfg.validate().wrap_err("invalid config")?;
+
+    let mut server = Server::new(cfg);
+    if let Some(handle) = server.shutdown_handle() {
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            handle.shutdown();
+        });
     }
 
-    Ok(())
+    server.run().await
 }
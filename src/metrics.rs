@@ -0,0 +1,193 @@
+//! # Metrics
+//!
+//! This module contains the counters for what happens in the server. They are always
+//! collected and can be exposed in the Prometheus text format on `/metrics`.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A value that only goes up
+#[derive(Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Add one to the counter
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The current value
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down
+#[derive(Debug)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    /// Add one to the gauge
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Subtract one from the gauge
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The current value
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The sum and number of observed durations
+#[derive(Debug)]
+pub struct Summary {
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Summary {
+    const fn new() -> Self {
+        Self {
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a duration to the summary
+    pub fn observe(&self, duration: Duration) {
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The sum of all durations (in seconds)
+    pub fn sum(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    }
+
+    /// The number of durations
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// All metrics of the server
+#[derive(Debug)]
+pub struct Metrics {
+    /// The connections that were accepted
+    pub connections_accepted: Counter,
+    /// The connections that were closed
+    pub connections_closed: Counter,
+    /// The channels that were created
+    pub channels_created: Counter,
+    /// The channels that were destroyed
+    pub channels_destroyed: Counter,
+    /// The steps that were applied to a document
+    pub steps_applied: Counter,
+    /// The steps that were rejected
+    pub steps_rejected: Counter,
+    /// The chat messages that were sent
+    pub chat_messages: Counter,
+    /// The signals that were dropped because a client did not take them
+    pub signals_dropped: Counter,
+    /// The channels that are currently open
+    pub live_channels: Gauge,
+    /// The users that are currently in a channel
+    pub live_users: Gauge,
+    /// The round-trip times of the pings to clients
+    pub round_trip: Summary,
+}
+
+/// The metrics of this process
+pub static METRICS: Metrics = Metrics {
+    connections_accepted: Counter::new(),
+    connections_closed: Counter::new(),
+    channels_created: Counter::new(),
+    channels_destroyed: Counter::new(),
+    steps_applied: Counter::new(),
+    steps_rejected: Counter::new(),
+    chat_messages: Counter::new(),
+    signals_dropped: Counter::new(),
+    live_channels: Gauge::new(),
+    live_users: Gauge::new(),
+    round_trip: Summary::new(),
+};
+
+impl Metrics {
+    /// Render all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "connections_accepted_total",
+                "Accepted connections",
+                &self.connections_accepted,
+            ),
+            (
+                "connections_closed_total",
+                "Closed connections",
+                &self.connections_closed,
+            ),
+            (
+                "channels_created_total",
+                "Created channels",
+                &self.channels_created,
+            ),
+            (
+                "channels_destroyed_total",
+                "Destroyed channels",
+                &self.channels_destroyed,
+            ),
+            ("steps_applied_total", "Applied steps", &self.steps_applied),
+            (
+                "steps_rejected_total",
+                "Rejected steps",
+                &self.steps_rejected,
+            ),
+            (
+                "chat_messages_total",
+                "Sent chat messages",
+                &self.chat_messages,
+            ),
+            (
+                "signals_dropped_total",
+                "Signals dropped for slow clients",
+                &self.signals_dropped,
+            ),
+        ];
+        let gauges = [
+            ("live_channels", "Open channels", &self.live_channels),
+            ("live_users", "Users in a channel", &self.live_users),
+        ];
+
+        let mut out = String::new();
+        for (name, help, counter) in counters.iter() {
+            writeln!(out, "# HELP padington_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE padington_{} counter", name).unwrap();
+            writeln!(out, "padington_{} {}", name, counter.get()).unwrap();
+        }
+        for (name, help, gauge) in gauges.iter() {
+            writeln!(out, "# HELP padington_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE padington_{} gauge", name).unwrap();
+            writeln!(out, "padington_{} {}", name, gauge.get()).unwrap();
+        }
+        let name = "round_trip_seconds";
+        writeln!(out, "# HELP padington_{} Round-trip times of pings", name).unwrap();
+        writeln!(out, "# TYPE padington_{} summary", name).unwrap();
+        writeln!(out, "padington_{}_sum {}", name, self.round_trip.sum()).unwrap();
+        writeln!(out, "padington_{}_count {}", name, self.round_trip.count()).unwrap();
+        out
+    }
+}
@@ -0,0 +1,75 @@
+//! # Full-text search
+//!
+//! For now, a search reads the markdown of every document in a folder and looks for the
+//! query in it. Finding a match is kept apart from where the text comes from, so that an
+//! index can take the place of the scan without changing the results.
+use serde::Serialize;
+
+/// The maximum number of documents in the results of a search
+pub const MAX_RESULTS: usize = 50;
+
+/// The maximum length of a query (in bytes)
+pub const MAX_QUERY_LEN: usize = 100;
+
+/// The number of bytes before and after a match that are included in the snippet
+const SNIPPET_CONTEXT: usize = 40;
+
+/// A document that contains the query
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    /// The path of the document
+    pub path: String,
+    /// The text around the first match
+    pub snippet: String,
+}
+
+/// A phrase to search for, ignoring case
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// The lowercase phrase
+    needle: String,
+}
+
+impl Query {
+    /// Create a query, returns `None` if it is empty or too long
+    pub fn new(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.is_empty() || text.len() > MAX_QUERY_LEN {
+            return None;
+        }
+        Some(Self {
+            needle: text.to_lowercase(),
+        })
+    }
+
+    /// Find the query in `text`, returns the text around the first match
+    pub fn snippet(&self, text: &str) -> Option<String> {
+        // Lowercasing may change the length of a character, so remember where each byte of
+        // the lowercase text came from
+        let mut lower = String::with_capacity(text.len());
+        let mut origin = Vec::with_capacity(text.len());
+        for (index, c) in text.char_indices() {
+            for l in c.to_lowercase() {
+                lower.push(l);
+                origin.resize(lower.len(), index);
+            }
+        }
+        let pos = lower.find(&self.needle)?;
+        let start = origin[pos];
+        let end = origin
+            .get(pos + self.needle.len())
+            .copied()
+            .unwrap_or_else(|| text.len());
+
+        let mut from = start.saturating_sub(SNIPPET_CONTEXT);
+        while !text.is_char_boundary(from) {
+            from -= 1;
+        }
+        let mut to = (end + SNIPPET_CONTEXT).min(text.len());
+        while !text.is_char_boundary(to) {
+            to += 1;
+        }
+        let snippet = text[from..to].split_whitespace().collect::<Vec<_>>();
+        Some(snippet.join(" "))
+    }
+}
@@ -0,0 +1,638 @@
+//! # Server setup
+//!
+//! This module wires the listener, the lobby and the client connections together. The
+//! `Server` can be started from any tokio runtime and stopped with a `ShutdownHandle`.
+use crate::audit::AuditLog;
+use crate::client::{find_password, find_token, handle_connection, reject_connection};
+use crate::config::{AuthConfig, ClientConfig, ConnSetup, Setup};
+use crate::lobby::{
+    ChannelID, Identity, LobbyClient, LobbyRequest, LobbyServer, ReadError, Role, SearchError,
+};
+use crate::metrics::METRICS;
+use crate::search::{Query, MAX_QUERY_LEN};
+use crate::util::http::{query_param, read_request_head, write_response, Rewind};
+use crate::util::{ConnectionCounter, ConnectionGuard, Refusal};
+use crate::Transport;
+use color_eyre::Report;
+use eyre::{eyre, WrapErr};
+use futures_util::future::{join_all, pending, ready, select, Either, FutureExt};
+use futures_util::stream::{self, Stream, StreamExt};
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+use tungstenite::http::{header::UPGRADE, Method, Request, StatusCode};
+
+/// How long to wait for all channels to be saved on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the health check reports the shutdown before no more connections are accepted
+const DRAIN_PERIOD: Duration = Duration::from_secs(2);
+
+/// How long a client may take to send the head of its request
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The path of the health check
+const HEALTH_PATH: &str = "/healthz";
+
+/// The path of the metrics
+const METRICS_PATH: &str = "/metrics";
+
+/// The path of the list of active channels, for admins
+const CHANNELS_PATH: &str = "/admin/channels";
+
+/// The path to send announcements to channels, for admins
+const ANNOUNCE_PATH: &str = "/admin/announce";
+
+/// The path to search the documents of a folder
+const SEARCH_PATH: &str = "/search";
+
+/// Everything a connection task needs from the server
+#[derive(Clone)]
+struct ConnContext {
+    /// The sender for requests to the lobby
+    lobby_sender: mpsc::Sender<LobbyRequest>,
+    /// The options for client connections
+    client_cfg: ClientConfig,
+    /// The authentication options
+    auth: Option<Arc<AuthConfig>>,
+    /// Whether the lobby is running and the server accepts connections
+    ready: Arc<AtomicBool>,
+    /// Whether the metrics are served
+    metrics: bool,
+}
+
+/// The user that sent a plain request, `Err(())` if authentication is enabled and failed
+fn identify(ctx: &ConnContext, request: &Request<()>) -> Result<Option<Identity>, ()> {
+    match &ctx.auth {
+        Some(auth) => match find_token(request).and_then(|t| auth.authenticate(&t)) {
+            Some(identity) => Ok(Some(identity)),
+            None => Err(()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Answer a plain `GET` request with the current markdown of a document
+async fn serve_document(ctx: &ConnContext, request: &Request<()>, stream: &mut Transport) {
+    let identity = match identify(ctx, request) {
+        Ok(identity) => identity,
+        Err(()) => {
+            let status = StatusCode::UNAUTHORIZED;
+            let body = "Invalid or missing token";
+            if let Err(e) = write_response(stream, status, "text/plain", body).await {
+                warn!("Could not answer document request: {}", e);
+            }
+            return;
+        }
+    };
+
+    let path = request.uri().path();
+    let res = match urlencoding::decode(path) {
+        Ok(path) => {
+            let mut lc = LobbyClient::from(ctx.lobby_sender.clone());
+            let password = find_password(request);
+            lc.read_document(path, identity, password).await
+        }
+        Err(_) => Err(ReadError::NotFound(path.to_owned())),
+    };
+    let (status, content_type, body) = match res {
+        Ok(md) => (StatusCode::OK, "text/markdown; charset=utf-8", md),
+        Err(e @ ReadError::NotFound(_)) | Err(e @ ReadError::NotCanonical(..)) => {
+            (StatusCode::NOT_FOUND, "text/plain", e.to_string())
+        }
+        Err(e @ ReadError::Forbidden(_)) => (StatusCode::FORBIDDEN, "text/plain", e.to_string()),
+        Err(e @ ReadError::Exhausted) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "text/plain", e.to_string())
+        }
+        Err(e) => {
+            error!("Could not read {:?}: {}", path, e);
+            let body = "Could not read document".to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, "text/plain", body)
+        }
+    };
+    if let Err(e) = write_response(stream, status, content_type, &body).await {
+        warn!("Could not answer document request: {}", e);
+    }
+}
+
+/// Answer a search in the documents of a folder with a list of paths and snippets as JSON
+///
+/// The folder is given as `?folder=..` (default: `/`) and the phrase as `?q=..`.
+async fn serve_search(ctx: &ConnContext, request: &Request<()>, stream: &mut Transport) {
+    let uri = request.uri();
+    let folder = query_param(uri, "folder").unwrap_or_else(|| "/".to_string());
+    let query = query_param(uri, "q").and_then(|q| Query::new(&q));
+    let (status, content_type, body) = match (identify(ctx, request), query) {
+        (Err(()), _) => {
+            let body = "Invalid or missing token".to_string();
+            (StatusCode::UNAUTHORIZED, "text/plain", body)
+        }
+        (Ok(_), None) => {
+            let body = format!("Expected ?q=.. with at most {} bytes", MAX_QUERY_LEN);
+            (StatusCode::BAD_REQUEST, "text/plain", body)
+        }
+        (Ok(identity), Some(query)) => {
+            let mut lc = LobbyClient::from(ctx.lobby_sender.clone());
+            let password = find_password(request);
+            match lc.search(folder, query, identity, password).await {
+                Ok(hits) => {
+                    let body = serde_json::to_string(&hits).unwrap();
+                    (StatusCode::OK, "application/json", body)
+                }
+                Err(e @ SearchError::NotFolder(_)) => {
+                    (StatusCode::NOT_FOUND, "text/plain", e.to_string())
+                }
+                Err(e @ SearchError::Forbidden(_)) => {
+                    (StatusCode::FORBIDDEN, "text/plain", e.to_string())
+                }
+                Err(e @ SearchError::Exhausted) => {
+                    (StatusCode::SERVICE_UNAVAILABLE, "text/plain", e.to_string())
+                }
+                Err(e) => {
+                    error!("Could not search: {}", e);
+                    let body = "Could not search".to_string();
+                    (StatusCode::INTERNAL_SERVER_ERROR, "text/plain", body)
+                }
+            }
+        }
+    };
+    if let Err(e) = write_response(stream, status, content_type, &body).await {
+        warn!("Could not answer search request: {}", e);
+    }
+}
+
+/// Whether the request carries the token of an admin
+///
+/// Without authentication, there are no admins and the admin API is never served.
+fn is_admin(ctx: &ConnContext, request: &Request<()>) -> bool {
+    let identity = ctx
+        .auth
+        .as_ref()
+        .and_then(|auth| find_token(request).and_then(|t| auth.authenticate(&t)));
+    identity.map_or(false, |identity| identity.role == Role::Admin)
+}
+
+/// Answer a request to the admin API
+async fn serve_admin(ctx: &ConnContext, request: &Request<()>, stream: &mut Transport) {
+    let mut lc = LobbyClient::from(ctx.lobby_sender.clone());
+    let (status, content_type, body) = if !is_admin(ctx, request) {
+        let body = "Admin token required".to_string();
+        (StatusCode::FORBIDDEN, "text/plain", body)
+    } else if request.uri().path() == CHANNELS_PATH {
+        match lc.list_channels().await {
+            Ok(channels) => {
+                let body = serde_json::to_string(&channels).unwrap();
+                (StatusCode::OK, "application/json", body)
+            }
+            Err(e) => {
+                error!("Could not list channels: {}", e);
+                let body = "Could not list channels".to_string();
+                (StatusCode::INTERNAL_SERVER_ERROR, "text/plain", body)
+            }
+        }
+    } else if request.method() != Method::POST {
+        let body = "Announcements must be posted".to_string();
+        (StatusCode::METHOD_NOT_ALLOWED, "text/plain", body)
+    } else {
+        let text = query_param(request.uri(), "text").filter(|text| !text.is_empty());
+        let channel = query_param(request.uri(), "channel").map(|id| id.parse::<u64>());
+        match (text, channel) {
+            (None, _) | (_, Some(Err(_))) => {
+                let body = "Expected ?text=..[&channel=..]".to_string();
+                (StatusCode::BAD_REQUEST, "text/plain", body)
+            }
+            (Some(text), channel) => {
+                let channel = channel.and_then(Result::ok).map(ChannelID::from);
+                match lc.announce(text, channel).await {
+                    Ok(reached) => (StatusCode::OK, "text/plain", reached.to_string()),
+                    Err(e) => {
+                        error!("Could not announce: {}", e);
+                        let body = "Could not announce".to_string();
+                        (StatusCode::INTERNAL_SERVER_ERROR, "text/plain", body)
+                    }
+                }
+            }
+        }
+    };
+    if let Err(e) = write_response(stream, status, content_type, &body).await {
+        warn!("Could not answer admin request: {}", e);
+    }
+}
+
+async fn accept_connection(
+    ctx: ConnContext,
+    peer: SocketAddr,
+    mut stream: Transport,
+    _guard: ConnectionGuard,
+) {
+    let (head, request) = match timeout(HEAD_TIMEOUT, read_request_head(&mut stream)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => {
+            error!("Could not read request from {}: {}", peer, e);
+            return;
+        }
+        Err(_) => {
+            debug!("No request from {} in time", peer);
+            return;
+        }
+    };
+    let path = request.as_ref().map(|req| req.uri().path());
+    if path == Some(HEALTH_PATH) {
+        let (status, body) = if ctx.ready.load(Ordering::SeqCst) {
+            (StatusCode::OK, "ok")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+        };
+        if let Err(e) = write_response(&mut stream, status, "text/plain", body).await {
+            warn!("Could not answer health check: {}", e);
+        }
+        return;
+    }
+    if ctx.metrics && path == Some(METRICS_PATH) {
+        let body = METRICS.render();
+        let content_type = "text/plain; version=0.0.4";
+        if let Err(e) = write_response(&mut stream, StatusCode::OK, content_type, &body).await {
+            warn!("Could not serve metrics: {}", e);
+        }
+        return;
+    }
+    if let Some(request) = request.as_ref().filter(|req| {
+        let path = req.uri().path();
+        path == CHANNELS_PATH || path == ANNOUNCE_PATH
+    }) {
+        serve_admin(&ctx, request, &mut stream).await;
+        return;
+    }
+    if let Some(request) = request
+        .as_ref()
+        .filter(|req| req.uri().path() == SEARCH_PATH)
+    {
+        serve_search(&ctx, request, &mut stream).await;
+        return;
+    }
+    if let Some(request) = &request {
+        // Everything but a WebSocket upgrade is a plain read of a document
+        if request.method() == Method::GET && !request.headers().contains_key(UPGRADE) {
+            serve_document(&ctx, request, &mut stream).await;
+            return;
+        }
+    }
+
+    METRICS.connections_accepted.inc();
+    let lc = LobbyClient::from(ctx.lobby_sender);
+    let stream = Rewind::new(head, stream);
+    if let Err(e) = handle_connection(lc, peer, stream, ctx.client_cfg, ctx.auth).await {
+        error!("Error processing connection: {}", e)
+    }
+    METRICS.connections_closed.inc();
+}
+
+/// The HTTP response for a refused connection
+fn refusal_response(refusal: Refusal) -> (StatusCode, &'static str) {
+    match refusal {
+        Refusal::Full => (StatusCode::SERVICE_UNAVAILABLE, "Server is full"),
+        Refusal::TooManyFromAddr | Refusal::Penalty => {
+            (StatusCode::TOO_MANY_REQUESTS, "Too many connections")
+        }
+    }
+}
+
+/// The accepted connections of a listener and the addresses of their peers
+type Incoming<T> = Pin<Box<dyn Stream<Item = io::Result<(T, SocketAddr)>> + Send>>;
+
+fn tcp_incoming(listener: TcpListener) -> Incoming<TcpStream> {
+    Box::pin(stream::unfold(listener, |mut listener| async move {
+        let res = listener.accept().await;
+        Some((res, listener))
+    }))
+}
+
+#[cfg(unix)]
+fn unix_incoming(listener: UnixListener) -> Incoming<UnixStream> {
+    // Peers on a Unix socket have no IP address, they all show up as local connections
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    Box::pin(stream::unfold(listener, move |mut listener| async move {
+        let res = listener
+            .accept()
+            .await
+            .map(|(stream, _addr)| (stream, local));
+        Some((res, listener))
+    }))
+}
+
+/// Bind a listener to a Unix domain socket, replacing a socket file left over from an earlier run
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<UnixListener, Report> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path).wrap_err("Could not remove stale socket")?;
+        }
+    }
+    UnixListener::bind(path).wrap_err("Can't listen")
+}
+
+/// Accept connections from `incoming` until `shutdown` completes
+///
+/// Unless `per_addr` is set, the peers have no address of their own (e.g. on a Unix socket),
+/// so they only count toward the total number of connections.
+async fn wait_for_connections<T, F, R, S>(
+    mut incoming: Incoming<T>,
+    per_addr: bool,
+    ctx: ConnContext,
+    connections: ConnectionCounter,
+    shutdown: S,
+    map: F,
+) where
+    F: Fn(T) -> R,
+    R: Future<Output = Result<Transport, io::Error>>,
+    S: Future<Output = ()> + Send,
+{
+    // The health check fails for a while before the listener closes, so that a load
+    // balancer can stop sending new clients while the checks are still answered
+    let ready = ctx.ready.clone();
+    let mut shutdown = async move {
+        shutdown.await;
+        info!("Shutting down in {:?}", DRAIN_PERIOD);
+        ready.store(false, Ordering::SeqCst);
+        delay_for(DRAIN_PERIOD).await;
+    }
+    .boxed();
+    loop {
+        match select(incoming.next(), shutdown).await {
+            Either::Left((Some(Ok((stream, peer))), shutdown_continue)) => {
+                let guard = if per_addr {
+                    connections.try_acquire(peer.ip())
+                } else {
+                    connections.try_acquire_local()
+                };
+                if let Err(Refusal::Penalty) = guard {
+                    // Don't spend a handshake on an address that keeps trying
+                    debug!("Dropped connection from blocked {}", peer);
+                } else {
+                    match map(stream).await {
+                        Ok(stream) => match guard {
+                            Ok(guard) => {
+                                tokio::spawn(accept_connection(ctx.clone(), peer, stream, guard));
+                            }
+                            Err(refusal) => {
+                                warn!("Rejected {} ({:?})", peer, refusal);
+                                let (status, reason) = refusal_response(refusal);
+                                let stream = Rewind::new(Vec::new(), stream);
+                                tokio::spawn(reject_connection(stream, status, reason));
+                            }
+                        },
+                        Err(e) => error!("Invalid connection request: {:?}", e),
+                    }
+                }
+                shutdown = shutdown_continue;
+            }
+            Either::Left((Some(Err(e)), _)) => {
+                error!("Failed to accept connection: {}", e);
+                break;
+            }
+            Either::Left((None, _)) => break,
+            Either::Right(((), _)) => {
+                info!("Stopped accepting connections");
+                break;
+            }
+        }
+    }
+}
+
+/// Bind a listener to a single address
+async fn bind(addr: &str) -> Result<TcpListener, Report> {
+    let addr = addr
+        .to_socket_addrs()
+        .wrap_err("Invalid address")?
+        .next()
+        .ok_or_else(|| eyre!("No address for {:?}", addr))?;
+    TcpListener::bind(&addr).await.wrap_err("Can't listen")
+}
+
+/// Bind a listener to every address, fails only if none of them could be bound
+async fn bind_all(addrs: &[String]) -> Result<Vec<TcpListener>, Report> {
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        match bind(addr).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => error!("Could not listen on {:?}: {:?}", addr, e),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(eyre!("Could not listen on any of {:?}", addrs));
+    }
+    Ok(listeners)
+}
+
+/// A handle to stop a running `Server`
+#[derive(Debug)]
+pub struct ShutdownHandle(oneshot::Sender<()>);
+
+impl ShutdownHandle {
+    /// Stop accepting connections and save all channels
+    pub fn shutdown(self) {
+        if let Err(()) = self.0.send(()) {
+            error!("Server stopped before shutdown");
+        }
+    }
+}
+
+/// A padington server
+pub struct Server {
+    setup: Setup,
+    listeners: Vec<TcpListener>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    shutdown_rx: oneshot::Receiver<()>,
+    connections: ConnectionCounter,
+}
+
+impl Server {
+    /// Create a new server from a setup
+    pub fn new(setup: Setup) -> Self {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let connections = ConnectionCounter::new(setup.max_connections)
+            .with_max_per_addr(setup.max_connections_per_ip, setup.ip_penalty);
+        Self {
+            setup,
+            connections,
+            listeners: Vec::new(),
+            shutdown_tx: Some(shutdown_tx),
+            shutdown_rx,
+        }
+    }
+
+    /// Use an already bound listener instead of binding to `Setup::addrs`
+    ///
+    /// This can be called multiple times to accept connections on multiple listeners.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Get the handle to stop this server, returns `None` if it was already taken
+    pub fn shutdown_handle(&mut self) -> Option<ShutdownHandle> {
+        self.shutdown_tx.take().map(ShutdownHandle)
+    }
+
+    /// Get the counter of active connections
+    pub fn connections(&self) -> ConnectionCounter {
+        self.connections.clone()
+    }
+
+    /// Run the server until it is shut down
+    pub async fn run(self) -> Result<(), Report> {
+        let Server {
+            mut setup,
+            listeners,
+            shutdown_rx,
+            connections,
+            ..
+        } = self;
+
+        setup
+            .folder
+            .load_templates()
+            .wrap_err("loading templates")?;
+
+        let uses_tcp = matches!(setup.conn, ConnSetup::Basic | ConnSetup::Tls { .. });
+        let listeners = if listeners.is_empty() && uses_tcp {
+            bind_all(&setup.addrs).await?
+        } else {
+            listeners
+        };
+        for listener in &listeners {
+            info!("Listening on: {}", listener.local_addr()?);
+        }
+
+        // A dropped handle never shuts the server down. The future is shared by the
+        // accept loops of all listeners.
+        let shutdown = async move {
+            if shutdown_rx.await.is_err() {
+                pending::<()>().await;
+            }
+        }
+        .boxed()
+        .shared();
+
+        let (lobby_sender, lobby_receiver) = mpsc::channel(setup.buffers.lobby);
+        let (lobby_shutdown_tx, lobby_shutdown_rx) = oneshot::channel::<()>();
+
+        let audit = match &setup.audit_log {
+            Some(path) => Some(AuditLog::open(path).await?),
+            None => None,
+        };
+        let lobby = LobbyServer::new(
+            lobby_receiver,
+            lobby_shutdown_rx,
+            setup.folder,
+            setup.store,
+            audit,
+            setup.webhook,
+            setup.buffers,
+        );
+        // `ready` is taken by the future constructor below
+        let is_ready = Arc::new(AtomicBool::new(true));
+        let lobby = tokio::spawn({
+            let is_ready = is_ready.clone();
+            async move {
+                lobby.run().await;
+                is_ready.store(false, Ordering::SeqCst);
+            }
+        });
+
+        let ctx = ConnContext {
+            lobby_sender,
+            client_cfg: setup.client,
+            auth: setup.auth,
+            ready: is_ready,
+            metrics: setup.metrics,
+        };
+
+        match setup.conn {
+            ConnSetup::Basic => {
+                let loops = listeners.into_iter().map(|listener| {
+                    wait_for_connections(
+                        tcp_incoming(listener),
+                        true,
+                        ctx.clone(),
+                        connections.clone(),
+                        shutdown.clone(),
+                        |stream| ready(Ok(Transport::Plain(stream))),
+                    )
+                });
+                join_all(loops).await;
+            }
+            ConnSetup::Tls { certs, mut keys } => {
+                info!("Setting up TLS ...");
+                let mut config = ServerConfig::new(NoClientAuth::new());
+                let key = keys
+                    .drain(..1)
+                    .next()
+                    .ok_or_else(|| eyre!("Key-File contains no keys"))?;
+                config
+                    .set_single_cert(certs, key)
+                    .wrap_err("setting certificate")?;
+                let acceptor = TlsAcceptor::from(Arc::new(config));
+                let loops = listeners.into_iter().map(|listener| {
+                    wait_for_connections(
+                        tcp_incoming(listener),
+                        true,
+                        ctx.clone(),
+                        connections.clone(),
+                        shutdown.clone(),
+                        |stream: TcpStream| async {
+                            let acceptor = acceptor.clone();
+                            let stream = acceptor.accept(stream).await?;
+                            Ok(Transport::Tls(Box::new(stream)))
+                        },
+                    )
+                });
+                join_all(loops).await;
+            }
+            #[cfg(unix)]
+            ConnSetup::Unix { path } => {
+                let listener = bind_unix(&path)?;
+                info!("Listening on: {:?}", path);
+                wait_for_connections(
+                    unix_incoming(listener),
+                    false,
+                    ctx,
+                    connections,
+                    shutdown,
+                    |stream| ready(Ok(Transport::Unix(stream))),
+                )
+                .await;
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!("Could not remove socket {:?}: {}", path, e);
+                }
+            }
+        }
+
+        if let Err(()) = lobby_shutdown_tx.send(()) {
+            error!("Lobby server stopped before shutdown");
+        }
+        match timeout(SHUTDOWN_TIMEOUT, lobby).await {
+            Ok(Ok(())) => info!("All channels saved"),
+            Ok(Err(e)) => error!("Lobby server failed: {}", e),
+            Err(_) => error!("Timed out waiting for channels to be saved"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! # Document storage
+//!
+//! This module contains the backends that channels use to load and save their documents.
+use crate::util::random_token;
+use async_trait::async_trait;
+use color_eyre::Report;
+use eyre::{eyre, WrapErr};
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+/// A place where documents are stored
+#[async_trait]
+pub trait DocStore: Debug + Send + Sync {
+    /// Load the document at `path`, returns `None` if there is none yet
+    async fn load(&self, path: &Path) -> Result<Option<String>, Report>;
+    /// Save the document at `path`
+    async fn save(&self, path: &Path, contents: &str) -> Result<(), Report>;
+    /// List the names of the documents with the given extension in `dir`
+    async fn list(&self, dir: &Path, extension: &str) -> Result<Vec<String>, Report>;
+    /// Remove the document at `path`, if there is one
+    async fn remove(&self, path: &Path) -> Result<(), Report>;
+    /// Make sure that documents can be saved in `dir`
+    async fn create_dir(&self, dir: &Path) -> Result<(), Report>;
+}
+
+/// Write `contents` to a new file at `path` and wait until they are on disk
+async fn write_synced(path: &Path, contents: &str) -> io::Result<()> {
+    let mut file = File::create(path).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await
+}
+
+/// Wait until the entries of `dir` are on disk
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir).await?.sync_all().await
+}
+
+/// Directories can't be opened like files on other platforms
+#[cfg(not(unix))]
+async fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Stores documents as files on disk
+#[derive(Debug, Default)]
+pub struct FsDocStore;
+
+#[async_trait]
+impl DocStore for FsDocStore {
+    async fn load(&self, path: &Path) -> Result<Option<String>, Report> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Report::from(e)),
+        }
+    }
+
+    async fn save(&self, path: &Path, contents: &str) -> Result<(), Report> {
+        // Write to a temporary file next to the document and rename it over the original,
+        // so that the document is never left half-written. The name is unique, so that
+        // two saves of the same document never write to the same file.
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| eyre!("Invalid document path {:?}", path))?;
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(file_name);
+        tmp_name.push(format!(".{}.tmp", random_token()));
+        let tmp_path = path.with_file_name(tmp_name);
+
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .wrap_err_with(|| format!("Could not create {:?}", dir))?;
+        }
+        let res = async {
+            write_synced(&tmp_path, contents)
+                .await
+                .wrap_err_with(|| format!("Could not write {:?}", tmp_path))?;
+            tokio::fs::rename(&tmp_path, path)
+                .await
+                .wrap_err_with(|| format!("Could not replace {:?}", path))
+        }
+        .await;
+        if res.is_err() {
+            // The temporary file is useless now, but the error is more interesting
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+        res?;
+        // The rename is only durable once the directory is on disk as well
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        sync_dir(dir)
+            .await
+            .wrap_err_with(|| format!("Could not sync {:?}", dir))
+    }
+
+    async fn list(&self, dir: &Path, extension: &str) -> Result<Vec<String>, Report> {
+        let mut names = Vec::new();
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(Report::from(e)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == extension) {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(stem.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    async fn remove(&self, path: &Path) -> Result<(), Report> {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).wrap_err_with(|| format!("Could not remove {:?}", path)),
+        }
+    }
+
+    async fn create_dir(&self, dir: &Path) -> Result<(), Report> {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .wrap_err_with(|| format!("Could not create {:?}", dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A fresh directory for a single test
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("padington-store-{}", random_token()))
+    }
+
+    /// The names of all files in `dir`
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn save_replaces_the_document() -> Result<(), Report> {
+        let dir = test_dir();
+        let path = dir.join("doc.md");
+        let store = FsDocStore;
+        assert_eq!(store.load(&path).await?, None);
+
+        store.save(&path, "# First").await?;
+        store.save(&path, "# Second").await?;
+        assert_eq!(store.load(&path).await?.as_deref(), Some("# Second"));
+        assert_eq!(file_names(&dir), vec!["doc.md"]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_save_keeps_the_document() -> Result<(), Report> {
+        let dir = test_dir();
+        let path = dir.join("doc.md");
+        let store = FsDocStore;
+        store.save(&path, "# Complete").await?;
+
+        // A save that died before the rename leaves only its temporary file behind
+        std::fs::write(dir.join(".doc.md.interrupted.tmp"), "# Compl")?;
+        assert_eq!(store.load(&path).await?.as_deref(), Some("# Complete"));
+        assert_eq!(store.list(&dir, "md").await?, vec!["doc"]);
+
+        // The next save is not disturbed by it
+        store.save(&path, "# Updated").await?;
+        assert_eq!(store.load(&path).await?.as_deref(), Some("# Updated"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_failed_save_is_reported() -> Result<(), Report> {
+        let dir = test_dir();
+        // A directory that is in the way of the document can't be replaced
+        let path = dir.join("doc.md");
+        std::fs::create_dir_all(path.join("blocker"))?;
+
+        let store = FsDocStore;
+        assert!(store.save(&path, "# Lost").await.is_err());
+        assert_eq!(file_names(&dir), vec!["doc.md"]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_saves_do_not_collide() -> Result<(), Report> {
+        let dir = test_dir();
+        let path = dir.join("doc.md");
+        let store = FsDocStore;
+        let (first, second) =
+            futures_util::future::join(store.save(&path, "# One"), store.save(&path, "# Two"))
+                .await;
+        first?;
+        second?;
+        let md = store.load(&path).await?.unwrap();
+        assert!(md == "# One" || md == "# Two");
+        assert_eq!(file_names(&dir), vec!["doc.md"]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! # In-process testing
+//!
+//! Runs a lobby without any sockets and connects clients to its channels directly. The
+//! clients send the same [`Request`]s and receive the same [`Broadcast`]s as the tasks of
+//! WebSocket connections, so tests see what a real client would see, without the framing.
+//!
+//! Only available with the `testing` feature, and in the tests of this crate.
+use crate::channel::{Broadcast, InitReply, Request, RequestKind, SessionReply, Signal};
+use crate::config::{BufferConfig, Folder, Storage};
+use crate::lobby::{LobbyClient, LobbyRequest, LobbyServer, UserID};
+use crate::store::FsDocStore;
+use color_eyre::Report;
+use eyre::{eyre, WrapErr};
+use prosemirror::markdown::MD;
+use prosemirror::transform::Steps;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// How long a test client waits for a reply, a broadcast or a signal
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of signals that may wait for a test client
+const SIGNAL_BUFFER: usize = 20;
+
+/// The steps that insert `text` at `pos` in the document
+pub fn insert_text(pos: usize, text: &str) -> Steps<MD> {
+    let steps = serde_json::json!([{
+        "stepType": "replace",
+        "from": pos,
+        "to": pos,
+        "slice": { "content": [{ "type": "text", "text": text }] },
+    }]);
+    serde_json::from_value(steps).expect("A valid replace step")
+}
+
+/// A lobby that runs in the current process
+pub struct TestServer {
+    lobby_tx: mpsc::Sender<LobbyRequest>,
+    shutdown_tx: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Start a lobby whose documents are kept in memory only
+    pub fn start() -> Self {
+        Self::with_folder(Folder::default().with_storage(Storage::Memory))
+    }
+
+    /// Start a lobby for the given root folder
+    pub fn with_folder(folder: Folder) -> Self {
+        let buffers = BufferConfig::default();
+        let (lobby_tx, lobby_rx) = mpsc::channel(buffers.lobby);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let store = Arc::new(FsDocStore::default());
+        let lobby = LobbyServer::new(lobby_rx, shutdown_rx, folder, store, None, None, buffers);
+        let handle = tokio::spawn(lobby.run());
+        Self {
+            lobby_tx,
+            shutdown_tx,
+            handle,
+        }
+    }
+
+    /// A handle to send requests to the lobby
+    pub fn lobby(&self) -> LobbyClient {
+        LobbyClient::from(self.lobby_tx.clone())
+    }
+
+    /// Join the channel at `path` and initialize like a client that picked `name`
+    pub async fn connect(&self, path: &str, name: Option<&str>) -> Result<TestClient, Report> {
+        let join = self
+            .lobby()
+            .join_channel(path, None, None)
+            .await
+            .wrap_err_with(|| format!("Could not join {:?}", path))?;
+        let mut msg_tx = join.msg_tx;
+        let (sig_tx, sig_rx) = mpsc::channel(SIGNAL_BUFFER);
+        let (tx, rx) = oneshot::channel::<SessionReply>();
+        let req = Request {
+            source: join.id,
+            kind: RequestKind::Init {
+                response: tx,
+                name: name.map(str::to_owned),
+                token: None,
+                identity: None,
+                sig_tx,
+            },
+        };
+        msg_tx
+            .send(req)
+            .await
+            .map_err(|_| eyre!("The channel closed"))?;
+        let reply = timeout(TIMEOUT, rx)
+            .await
+            .wrap_err("No reply to init")?
+            .wrap_err("The channel closed")?;
+        Ok(TestClient {
+            id: reply.id,
+            init: reply.state,
+            msg_tx,
+            bct_rx: join.bct_rx,
+            sig_rx,
+        })
+    }
+
+    /// Shut the lobby down and wait until all channels are closed
+    pub async fn shutdown(self) -> Result<(), Report> {
+        // The lobby may have stopped already, then there is nothing to wait for
+        let _ = self.shutdown_tx.send(());
+        self.handle.await?;
+        Ok(())
+    }
+}
+
+/// A client that is connected to a channel of a [`TestServer`]
+pub struct TestClient {
+    /// The ID of this client in the channel
+    pub id: UserID,
+    /// The state of the channel when this client joined
+    pub init: InitReply,
+    msg_tx: mpsc::Sender<Request>,
+    bct_rx: broadcast::Receiver<Broadcast>,
+    sig_rx: mpsc::Receiver<Signal>,
+}
+
+impl TestClient {
+    /// Send a request to the channel
+    pub async fn send(&mut self, kind: RequestKind) -> Result<(), Report> {
+        let req = Request {
+            source: self.id,
+            kind,
+        };
+        self.msg_tx
+            .send(req)
+            .await
+            .map_err(|_| eyre!("The channel closed"))
+    }
+
+    /// Send a chat message
+    pub async fn chat(&mut self, text: &str) -> Result<(), Report> {
+        self.send(RequestKind::Chat(text.to_owned())).await
+    }
+
+    /// Send steps for the given version of the document
+    pub async fn steps(&mut self, version: usize, steps: Steps<MD>) -> Result<(), Report> {
+        self.send(RequestKind::Steps(version, steps)).await
+    }
+
+    /// Wait for the next broadcast in the channel
+    pub async fn next_broadcast(&mut self) -> Result<Broadcast, Report> {
+        let res = timeout(TIMEOUT, self.bct_rx.recv())
+            .await
+            .wrap_err("No broadcast")?;
+        Ok(res?)
+    }
+
+    /// Wait for the next broadcast that matches `pred`, skipping all others
+    pub async fn expect_broadcast<F>(&mut self, pred: F) -> Result<Broadcast, Report>
+    where
+        F: Fn(&Broadcast) -> bool,
+    {
+        loop {
+            let broadcast = self.next_broadcast().await?;
+            if pred(&broadcast) {
+                return Ok(broadcast);
+            }
+        }
+    }
+
+    /// Wait for the next signal to this client, e.g. an error
+    pub async fn next_signal(&mut self) -> Result<Signal, Report> {
+        timeout(TIMEOUT, self.sig_rx.recv())
+            .await
+            .wrap_err("No signal")?
+            .ok_or_else(|| eyre!("The channel closed"))
+    }
+
+    /// Leave the channel
+    pub async fn leave(mut self) -> Result<(), Report> {
+        self.send(RequestKind::Leave).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn steps_are_broadcast_to_the_other_client() -> Result<(), Report> {
+        let server = TestServer::start();
+        let mut alice = server.connect("/test", Some("Alice")).await?;
+        let mut bob = server.connect("/test", Some("Bob")).await?;
+        assert_ne!(alice.id, bob.id);
+
+        alice.steps(0, insert_text(1, "Hello")).await?;
+        let bct = bob
+            .expect_broadcast(|b| matches!(b, Broadcast::Steps(_)))
+            .await?;
+        match bct {
+            Broadcast::Steps(json) => {
+                assert!(json.contains("Hello"));
+                assert!(json.contains(&format!("\"src\":{}", alice.id.int_val())));
+            }
+            _ => unreachable!(),
+        }
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn the_second_client_sees_the_first() -> Result<(), Report> {
+        let server = TestServer::start();
+        let alice = server.connect("/test", Some("Alice")).await?;
+        let bob = server.connect("/test", Some("Bob")).await?;
+        assert!(!alice.init.j_peers.contains("Bob"));
+        assert!(bob.init.j_peers.contains("Alice"));
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn chat_is_broadcast() -> Result<(), Report> {
+        let server = TestServer::start();
+        let mut alice = server.connect("/test", None).await?;
+        let mut bob = server.connect("/test", None).await?;
+
+        alice.chat("hi").await?;
+        let bct = bob
+            .expect_broadcast(|b| matches!(b, Broadcast::ChatMessage(..)))
+            .await?;
+        match bct {
+            Broadcast::ChatMessage(src, _, text) => {
+                assert_eq!(src, alice.id);
+                assert_eq!(text, "hi");
+            }
+            _ => unreachable!(),
+        }
+
+        alice.leave().await?;
+        bob.leave().await?;
+        server.shutdown().await
+    }
+}
@@ -1,38 +1,362 @@
 //! # Misc utitlities
 //!
 //! This module contains some utilities that are used but not specific to `padington`.
+pub mod http;
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
 use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// A counter that produces IDs of type T
 #[derive(Debug)]
-pub struct Counter<T>(u64, PhantomData<fn() -> T>);
+pub struct Counter<T>(Option<u64>, PhantomData<fn() -> T>);
 
 impl<T> Default for Counter<T> {
     fn default() -> Self {
-        Self(0, PhantomData)
+        Self(Some(0), PhantomData)
     }
 }
 
-/*impl <T: From<u64>> Iterator for Counter<T> {
+/// Produces the IDs in order and ends instead of wrapping around, so that no ID is
+/// ever handed out twice. Freed IDs are not reused, to keep them unambiguous in logs.
+impl<T: From<u64>> Iterator for Counter<T> {
     type Item = T;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next) = self.0.checked_add(1) {
-            let id = self.0;
-            self.0 = next;
-            Some(T::from(id))
+        let id = self.0?;
+        self.0 = id.checked_add(1);
+        Some(T::from(id))
+    }
+}
+
+/// The current time in milliseconds since the UNIX epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// A random token that is hard to guess, e.g. to identify a session
+pub fn random_token() -> String {
+    // Every `RandomState` is seeded with fresh random keys
+    let part = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u64(unix_millis());
+        hasher.finish()
+    };
+    format!("{:016x}{:016x}", part(0), part(1))
+}
+
+/// A string that must not end up in the logs, e.g. a password
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Wrap a secret string
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// The secret itself
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Compare two byte strings in time that only depends on their length
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The 32-bit FNV-1a hash of everything written to it
+///
+/// Simple enough that clients can compute the same hash in a few lines.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv1a(u32);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0x811c_9dc5)
+    }
+}
+
+impl Fnv1a {
+    /// The hash of the bytes so far
+    pub fn finish(self) -> u32 {
+        self.0
+    }
+}
+
+impl io::Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.0 ^= u32::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0193);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A token bucket that limits how often something may happen
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// The number of tokens added per second
+    rate: f64,
+    /// The maximum number of tokens
+    burst: f64,
+    /// The number of tokens currently available
+    tokens: f64,
+    /// The last time tokens were added
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new full bucket
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket, returns `false` if there is none left
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
         } else {
-            None
+            false
         }
     }
-}*/
+}
+
+/// The number of refused connections in a row after which an address is penalized
+const PENALTY_THRESHOLD: u32 = 3;
+
+/// How long a refused connection counts towards the penalty of an address
+const REJECTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often the addresses without connections are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why a connection was refused
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Refusal {
+    /// There are too many connections in total
+    Full,
+    /// There are too many connections from the same address
+    TooManyFromAddr,
+    /// The address was refused too often and has to wait
+    Penalty,
+}
+
+/// The connections from a single address
+#[derive(Debug, Default)]
+struct AddrState {
+    /// The number of active connections
+    active: usize,
+    /// The number of refused connections in a row
+    rejections: u32,
+    /// The time of the last refused connection
+    last_rejection: Option<Instant>,
+    /// The time until which all connections are refused
+    blocked_until: Option<Instant>,
+}
+
+impl AddrState {
+    /// Whether the refused connections are too old to count
+    fn rejections_expired(&self, now: Instant) -> bool {
+        self.last_rejection
+            .map_or(true, |last| now.duration_since(last) >= REJECTION_WINDOW)
+    }
 
-#[allow(clippy::should_implement_trait)]
-impl<T: From<u64>> Counter<T> {
-    /// Get the next value from this counter
-    pub fn next(&mut self) -> T {
-        let id = self.0;
-        self.0 = id + 1;
-        T::from(id)
+    /// Whether this entry can be forgotten
+    fn is_idle(&self, now: Instant) -> bool {
+        self.active == 0
+            && self.rejections_expired(now)
+            && self.blocked_until.map_or(true, |until| until <= now)
+    }
+}
+
+/// The connections of all addresses
+#[derive(Debug)]
+struct AddrTable {
+    /// The state of each address that was seen recently
+    states: HashMap<IpAddr, AddrState>,
+    /// The next time that idle entries are removed
+    next_sweep: Instant,
+}
+
+impl Default for AddrTable {
+    fn default() -> Self {
+        Self {
+            states: HashMap::new(),
+            next_sweep: Instant::now() + SWEEP_INTERVAL,
+        }
+    }
+}
+
+/// Counts the active connections and enforces upper bounds
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionCounter {
+    /// The number of active connections
+    active: Arc<AtomicUsize>,
+    /// The maximum number of active connections
+    max: Option<usize>,
+    /// The connections for each address
+    per_addr: Arc<Mutex<AddrTable>>,
+    /// The maximum number of active connections from a single address
+    max_per_addr: Option<usize>,
+    /// How long an address is blocked after being refused repeatedly
+    penalty: Option<Duration>,
+}
+
+impl ConnectionCounter {
+    /// Create a new counter with an optional limit
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Limit the connections from a single address
+    pub fn with_max_per_addr(mut self, max: Option<usize>, penalty: Option<Duration>) -> Self {
+        self.max_per_addr = max;
+        self.penalty = penalty;
+        self
+    }
+
+    /// The number of active connections
+    pub fn count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Register a new connection from `addr`
+    pub fn try_acquire(&self, addr: IpAddr) -> Result<ConnectionGuard, Refusal> {
+        self.try_acquire_at(addr, Instant::now())
+    }
+
+    /// Register a new connection without an address of its own, e.g. on a Unix socket
+    ///
+    /// Such connections all come from the same place (like a reverse proxy), so they only
+    /// count toward the total and are never limited or penalized per address.
+    pub fn try_acquire_local(&self) -> Result<ConnectionGuard, Refusal> {
+        // The map is not needed, but it orders the change with all other counts
+        let _table = self.per_addr.lock().unwrap();
+        if self.max.map_or(false, |max| self.count() >= max) {
+            return Err(Refusal::Full);
+        }
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Ok(ConnectionGuard {
+            counter: self.clone(),
+            addr: None,
+        })
+    }
+
+    fn try_acquire_at(&self, addr: IpAddr, now: Instant) -> Result<ConnectionGuard, Refusal> {
+        let mut table = self.per_addr.lock().unwrap();
+        // Addresses that never come back would stay in the table forever otherwise
+        if now >= table.next_sweep {
+            table.states.retain(|_, state| !state.is_idle(now));
+            table.next_sweep = now + SWEEP_INTERVAL;
+        }
+        let per_addr = &mut table.states;
+        let state = per_addr.entry(addr).or_default();
+
+        if let Some(until) = state.blocked_until {
+            if now < until {
+                return Err(Refusal::Penalty);
+            }
+            state.blocked_until = None;
+        }
+        if self.max_per_addr.map_or(false, |max| state.active >= max) {
+            if state.rejections_expired(now) {
+                state.rejections = 0;
+            }
+            state.rejections += 1;
+            state.last_rejection = Some(now);
+            if let Some(penalty) = self.penalty {
+                if state.rejections >= PENALTY_THRESHOLD {
+                    state.blocked_until = Some(now + penalty);
+                    state.rejections = 0;
+                    state.last_rejection = None;
+                }
+            }
+            return Err(Refusal::TooManyFromAddr);
+        }
+        // All changes to the counts happen while the map is locked
+        if self.max.map_or(false, |max| self.count() >= max) {
+            if state.is_idle(now) {
+                per_addr.remove(&addr);
+            }
+            return Err(Refusal::Full);
+        }
+
+        state.active += 1;
+        state.rejections = 0;
+        state.last_rejection = None;
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Ok(ConnectionGuard {
+            counter: self.clone(),
+            addr: Some(addr),
+        })
+    }
+
+    /// Unregister a connection from `addr`, if it has one
+    fn release(&self, addr: Option<IpAddr>) {
+        let mut table = self.per_addr.lock().unwrap();
+        let per_addr = &mut table.states;
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        if let Some(state) = per_addr.get_mut(&addr) {
+            state.active = state.active.saturating_sub(1);
+            if state.is_idle(Instant::now()) {
+                per_addr.remove(&addr);
+            }
+        }
+    }
+}
+
+/// An active connection, which is unregistered when this is dropped
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counter: ConnectionCounter,
+    addr: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counter.release(self.addr);
     }
 }
 
@@ -40,3 +364,104 @@ pub(crate) enum LoopState<T> {
     Break(T),
     Continue,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_starts_at_zero() {
+        let ids: Vec<u64> = Counter::default().take(3).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn counter_hands_out_the_last_id_once() {
+        let mut counter = Counter::<u64>(Some(u64::MAX - 1), PhantomData);
+        assert_eq!(counter.next(), Some(u64::MAX - 1));
+        assert_eq!(counter.next(), Some(u64::MAX));
+        assert_eq!(counter.next(), None);
+        assert_eq!(counter.next(), None);
+    }
+
+    fn addr(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn connections_from_one_address_are_limited() {
+        let counter = ConnectionCounter::new(Some(10)).with_max_per_addr(Some(2), None);
+        let first = counter.try_acquire(addr(1)).unwrap();
+        let _second = counter.try_acquire(addr(1)).unwrap();
+        assert_eq!(
+            counter.try_acquire(addr(1)).unwrap_err(),
+            Refusal::TooManyFromAddr
+        );
+        // Other addresses are not affected
+        let _other = counter.try_acquire(addr(2)).unwrap();
+        assert_eq!(counter.count(), 3);
+
+        drop(first);
+        let _third = counter.try_acquire(addr(1)).unwrap();
+        assert_eq!(counter.count(), 3);
+    }
+
+    #[test]
+    fn repeated_refusals_are_penalized() {
+        let penalty = Duration::from_secs(30);
+        let counter = ConnectionCounter::new(None).with_max_per_addr(Some(1), Some(penalty));
+        let guard = counter.try_acquire(addr(1)).unwrap();
+        for _ in 0..PENALTY_THRESHOLD {
+            assert_eq!(
+                counter.try_acquire(addr(1)).unwrap_err(),
+                Refusal::TooManyFromAddr
+            );
+        }
+        drop(guard);
+        assert_eq!(counter.try_acquire(addr(1)).unwrap_err(), Refusal::Penalty);
+
+        let later = Instant::now() + penalty;
+        assert!(counter.try_acquire_at(addr(1), later).is_ok());
+    }
+
+    #[test]
+    fn local_connections_only_count_toward_the_total() {
+        let penalty = Duration::from_secs(30);
+        let max = PENALTY_THRESHOLD as usize + 3;
+        let counter = ConnectionCounter::new(Some(max)).with_max_per_addr(Some(1), Some(penalty));
+        // Way more than the limit per address, without a penalty
+        let mut guards: Vec<_> = (1..max)
+            .map(|_| counter.try_acquire_local().unwrap())
+            .collect();
+        let _remote = counter.try_acquire(addr(1)).unwrap();
+        assert_eq!(counter.count(), max);
+        assert_eq!(counter.try_acquire_local().unwrap_err(), Refusal::Full);
+        // Only the remote address is tracked
+        assert_eq!(counter.per_addr.lock().unwrap().states.len(), 1);
+
+        guards.pop();
+        guards.push(counter.try_acquire_local().unwrap());
+        assert_eq!(counter.count(), max);
+    }
+
+    #[test]
+    fn addresses_that_do_not_return_are_forgotten() {
+        let counter = ConnectionCounter::new(None).with_max_per_addr(Some(1), None);
+        let guard = counter.try_acquire(addr(1)).unwrap();
+        assert!(counter.try_acquire(addr(1)).is_err());
+        drop(guard);
+        // The refusal still counts for a while
+        assert!(counter
+            .per_addr
+            .lock()
+            .unwrap()
+            .states
+            .contains_key(&addr(1)));
+
+        let later = Instant::now() + SWEEP_INTERVAL + REJECTION_WINDOW;
+        let _other = counter.try_acquire_at(addr(2), later).unwrap();
+        let table = counter.per_addr.lock().unwrap();
+        assert!(!table.states.contains_key(&addr(1)));
+        assert!(table.states.contains_key(&addr(2)));
+    }
+}
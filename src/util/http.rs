@@ -0,0 +1,163 @@
+//! # Plain HTTP requests
+//!
+//! Some requests (e.g. health checks) are answered without a WebSocket handshake. To
+//! find them, the head of every request is read first and then replayed to the handshake.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tungstenite::http::{Request, StatusCode, Uri};
+
+/// The maximum size of a request head
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Find a parameter in the query of `uri` and decode it
+pub fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .find_map(|pair| {
+            let (key, value) = pair.split_at(pair.find('=')?);
+            Some(&value[1..]).filter(|_| key == name)
+        })
+        .and_then(|value| urlencoding::decode(value).ok())
+}
+
+/// Read the head of an HTTP request, returns the bytes read and the parsed request
+pub async fn read_request_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<(Vec<u8>, Option<Request<()>>)> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD_LEN {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let request = parse_request_head(&head);
+    Ok((head, request))
+}
+
+/// Parse the request line and the headers, returns `None` if they are malformed
+fn parse_request_head(head: &[u8]) -> Option<Request<()>> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut lines = text.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?;
+    let target = request_line.next()?;
+
+    let mut builder = Request::builder().method(method).uri(target);
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_at(line.find(':')?);
+        builder = builder.header(name.trim(), value[1..].trim());
+    }
+    builder.body(()).ok()
+}
+
+/// Write a complete HTTP response
+pub async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: StatusCode,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or(""),
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Post a JSON body to an `http://` URL, returns the status of the response
+pub async fn post_json(url: &Uri, body: &str) -> io::Result<StatusCode> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    if url.scheme_str() != Some("http") {
+        return Err(invalid("only http:// URLs are supported"));
+    }
+    let host = url.host().ok_or_else(|| invalid("missing host"))?;
+    let port = url.port_u16().unwrap_or(80);
+    let target = url.path_and_query().map_or("/", |pq| pq.as_str());
+
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let host_header = match port {
+        80 => host.to_owned(),
+        port => format!("{}:{}", host, port),
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        target,
+        host_header,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(2).any(|w| w == b"\r\n") && head.len() < MAX_HEAD_LEN {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    head.split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| StatusCode::from_bytes(code).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid response"))
+}
+
+/// A stream that returns some bytes that were already read before reading from the inner stream
+#[derive(Debug)]
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    /// Create a new stream that starts with `prefix`
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.prefix.is_empty() {
+            let n = buf.len().min(self.prefix.len());
+            buf[..n].copy_from_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
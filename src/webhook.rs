@@ -0,0 +1,100 @@
+//! # Webhook
+//!
+//! This module notifies an external endpoint when a document changes. Every channel has a
+//! task that posts the latest change and then waits for the configured interval, so the
+//! endpoint is called at most once per interval and channel. Failed calls are logged and
+//! not retried, a later change triggers a new call anyway.
+use crate::lobby::UserID;
+use crate::util::{http::post_json, unix_millis};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, error, warn};
+use tungstenite::http::Uri;
+
+/// How long to wait for the endpoint to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The payload that is posted to the endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent {
+    /// The path of the document
+    pub path: String,
+    /// The version of the document after the change
+    pub version: usize,
+    /// The user that made the change
+    pub user: UserID,
+    /// The time of the change (in milliseconds since the UNIX epoch)
+    pub time: u64,
+}
+
+/// The endpoint to notify about changes
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    /// The URL to post to
+    url: Arc<Uri>,
+    /// The minimum time between two calls for the same channel
+    interval: Duration,
+}
+
+impl Webhook {
+    /// Create a new webhook, only `http://` URLs are supported
+    pub fn new(url: Uri, interval: Duration) -> Self {
+        Self {
+            url: Arc::new(url),
+            interval,
+        }
+    }
+
+    /// The URL that is posted to
+    pub fn url(&self) -> &Uri {
+        &self.url
+    }
+
+    /// Start the task that notifies about changes in a single channel
+    pub fn notifier(&self) -> WebhookNotifier {
+        let (tx, rx) = watch::channel(None);
+        tokio::spawn(notify_changes(self.clone(), rx));
+        WebhookNotifier(tx)
+    }
+}
+
+/// A handle to the webhook task of a channel, the task stops when this is dropped
+#[derive(Debug)]
+pub struct WebhookNotifier(watch::Sender<Option<ChangeEvent>>);
+
+impl WebhookNotifier {
+    /// Report a change, replacing any change that was not posted yet
+    pub fn notify(&self, path: &str, version: usize, user: UserID) {
+        let event = ChangeEvent {
+            path: path.to_owned(),
+            version,
+            user,
+            time: unix_millis(),
+        };
+        if self.0.broadcast(Some(event)).is_err() {
+            error!("Webhook task stopped");
+        }
+    }
+}
+
+async fn notify_changes(webhook: Webhook, mut rx: watch::Receiver<Option<ChangeEvent>>) {
+    while let Some(event) = rx.recv().await {
+        let event = match event {
+            Some(event) => event,
+            None => continue,
+        };
+        let body = serde_json::to_string(&event).unwrap();
+        match timeout(WEBHOOK_TIMEOUT, post_json(&webhook.url, &body)).await {
+            Ok(Ok(status)) if status.is_success() => {
+                debug!("Notified webhook about version {}", event.version)
+            }
+            Ok(Ok(status)) => warn!("Webhook answered {} for {:?}", status, event.path),
+            Ok(Err(e)) => warn!("Could not call webhook for {:?}: {}", event.path, e),
+            Err(_) => warn!("Webhook timed out for {:?}", event.path),
+        }
+        delay_for(webhook.interval).await;
+    }
+}